@@ -0,0 +1,68 @@
+//! Selection throughput over large backend sets, run with `cargo bench`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use yapf::load_balancer::{
+    strategy::{RoundRobin, Strategy, WeightedRoundRobin},
+    Backend, LoadBalancer,
+};
+
+const SELECTIONS: u32 = 1_000_000;
+
+fn backends(count: usize) -> Vec<Backend> {
+    (0..count)
+        .map(|i| {
+            Backend::new(format!(
+                "http://10.{}.{}.{}:8080",
+                i >> 16,
+                (i >> 8) & 255,
+                i & 255
+            ))
+        })
+        .collect()
+}
+
+fn bench<T: Strategy>(name: &str, lb: &LoadBalancer<T>) {
+    // Warm up
+    for _ in 0..SELECTIONS / 10 {
+        black_box(lb.next());
+    }
+
+    let start = Instant::now();
+    for _ in 0..SELECTIONS {
+        black_box(lb.next());
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{name:<48} {:>8.1} ns/selection {:>12.0} selections/s",
+        elapsed.as_nanos() as f64 / f64::from(SELECTIONS),
+        f64::from(SELECTIONS)
+            / elapsed
+                .as_secs_f64()
+                .max(Duration::from_nanos(1).as_secs_f64()),
+    );
+}
+
+fn main() {
+    for count in [10, 1_000, 10_000] {
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::new(backends(count));
+        bench(&format!("round robin, {count} healthy backends"), &lb);
+
+        // Every other backend is skipped
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::new(backends(count));
+        for backend in lb.healthy_backends().iter().step_by(2) {
+            lb.mark_unhealthy(backend);
+        }
+        bench(
+            &format!("round robin, {count} half unhealthy backends"),
+            &lb,
+        );
+
+        let lb: LoadBalancer<WeightedRoundRobin> = LoadBalancer::new(backends(count));
+        bench(
+            &format!("weighted round robin, {count} healthy backends"),
+            &lb,
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Gzip compression of the responses, see [crate::proxy::ProxyService::set_response_compression]
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use http_body_util::{BodyExt, Either};
+use hyper::{
+    body::{Bytes, Frame},
+    header::{
+        HeaderMap, HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH,
+        CONTENT_TYPE, VARY,
+    },
+    Method, Response, StatusCode,
+};
+use miniz_oxide::deflate::core::{
+    compress_to_output, create_comp_flags_from_zip_params, CompressorOxide, TDEFLFlush, TDEFLStatus,
+};
+
+use crate::proxy_trait::{Body, BoxError, RequestHeaders, StreamedBody};
+
+/// Which responses are worth compressing
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    /// The smallest `content-length` to compress, the responses without one are compressed
+    pub min_size: usize,
+    /// The media types to compress, e.g. `application/json`, or `text/*` for a whole type
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 1024,
+            content_types: [
+                "text/*",
+                "application/javascript",
+                "application/json",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    fn allows(&self, content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        self.content_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix('*') {
+                Some(prefix) => essence
+                    .get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+                None => essence.eq_ignore_ascii_case(allowed),
+            })
+    }
+
+    /// Whether to compress `response` to `request`
+    fn applies(&self, request: &RequestHeaders, response: &Response<Body>) -> bool {
+        let headers = response.headers();
+        let status = response.status();
+        let no_body = request.method == Method::HEAD
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || status == StatusCode::PARTIAL_CONTENT;
+        if no_body || !accepts_gzip(&request.headers) || headers.contains_key(CONTENT_ENCODING) {
+            return false;
+        }
+        let no_transform = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+        let allowed = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|content_type| self.allows(content_type));
+        let small = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse::<usize>().ok())
+            .is_some_and(|length| length < self.min_size);
+        !no_transform && allowed && !small
+    }
+}
+
+/// Whether the `accept-encoding` headers of a request accept gzip, e.g.
+/// `accept-encoding: gzip;q=0.8, br`
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let refused = params.any(|param| {
+                let param = param.trim();
+                let q = param
+                    .strip_prefix("q=")
+                    .or_else(|| param.strip_prefix("Q="));
+                q.and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+/// Gzip `response` to `request` if `config` allows it
+pub(crate) fn compress(
+    config: &CompressionConfig,
+    request: &RequestHeaders,
+    response: Response<Body>,
+) -> Response<Body> {
+    if !config.applies(request, &response) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    let body = GzipBody {
+        inner: body,
+        encoder: Some(GzipEncoder::new()),
+        trailers: None,
+    };
+    Response::from_parts(parts, Either::Right(StreamedBody::Wrapped(body.boxed())))
+}
+
+/// A gzip stream (RFC 1952) of the data given to it, deflated by miniz_oxide
+struct GzipEncoder {
+    deflate: Box<CompressorOxide>,
+    crc: crc32fast::Hasher,
+    /// The size of the uncompressed data, modulo 2^32
+    size: u32,
+    started: bool,
+}
+
+impl GzipEncoder {
+    fn new() -> Self {
+        // Raw deflate with a 32 KiB window, gzip adds its own framing
+        let flags = create_comp_flags_from_zip_params(6, -15, 0);
+        Self {
+            deflate: Box::new(CompressorOxide::new(flags)),
+            crc: crc32fast::Hasher::new(),
+            size: 0,
+            started: false,
+        }
+    }
+
+    /// Compress `data`, with everything given so far when flushed with [TDEFLFlush::Sync] and
+    /// the gzip trailer with [TDEFLFlush::Finish]
+    fn compress(&mut self, data: &[u8], flush: TDEFLFlush) -> Result<Vec<u8>, BoxError> {
+        let mut compressed = Vec::new();
+        if !self.started {
+            // No mtime, flags nor extra fields, from an unknown OS
+            compressed.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+            self.started = true;
+        }
+        let (status, consumed) = compress_to_output(&mut self.deflate, data, flush, |output| {
+            compressed.extend_from_slice(output);
+            true
+        });
+        let expected = match flush {
+            TDEFLFlush::Finish => TDEFLStatus::Done,
+            _ => TDEFLStatus::Okay,
+        };
+        if status != expected || consumed != data.len() {
+            return Err(format!("failed to gzip the response: {status:?}").into());
+        }
+        self.crc.update(data);
+        self.size = self.size.wrapping_add(data.len() as u32);
+        if let TDEFLFlush::Finish = flush {
+            compressed.extend_from_slice(&self.crc.clone().finalize().to_le_bytes());
+            compressed.extend_from_slice(&self.size.to_le_bytes());
+        }
+        Ok(compressed)
+    }
+}
+
+/// Decompress a whole gzip stream of [GzipEncoder], checking its trailer
+#[cfg(test)]
+pub(crate) fn gunzip(data: &[u8]) -> Vec<u8> {
+    assert_eq!(data[..10], [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+    let (deflated, trailer) = data[10..].split_at(data.len() - 18);
+    let decompressed = miniz_oxide::inflate::decompress_to_vec(deflated).unwrap();
+    assert_eq!(trailer[..4], crc32fast::hash(&decompressed).to_le_bytes());
+    assert_eq!(trailer[4..], (decompressed.len() as u32).to_le_bytes());
+    decompressed
+}
+
+/// A body gzipped as it is streamed, each of its frames flushed as soon as it is received
+struct GzipBody<B> {
+    inner: B,
+    /// Taken once the body is over
+    encoder: Option<GzipEncoder>,
+    trailers: Option<HeaderMap>,
+}
+
+impl<B> hyper::body::Body for GzipBody<B>
+where
+    B: hyper::body::Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = self.get_mut();
+        loop {
+            let Some(encoder) = &mut this.encoder else {
+                return Poll::Ready(
+                    this.trailers
+                        .take()
+                        .map(|trailers| Ok(Frame::trailers(trailers))),
+                );
+            };
+            let compressed = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => encoder.compress(&data, TDEFLFlush::Sync),
+                    Err(frame) => {
+                        // The trailers go after the end of the gzip stream
+                        this.trailers = frame.into_trailers().ok();
+                        continue;
+                    }
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => this
+                    .encoder
+                    .take()
+                    .unwrap()
+                    .compress(&[], TDEFLFlush::Finish),
+            };
+            match compressed {
+                Ok(compressed) if compressed.is_empty() => continue,
+                Ok(compressed) => return Poll::Ready(Some(Ok(Frame::data(compressed.into())))),
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.encoder.is_none() && self.trailers.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts_gzip() {
+        let accepts = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+            accepts_gzip(&headers)
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("br, GZIP;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("br"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("gzip; q=0.0, br"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_gzip_encoder() {
+        let mut encoder = GzipEncoder::new();
+        let mut compressed = Vec::new();
+        for chunk in ["hello ", "", "gzip ", "world"] {
+            compressed.extend(
+                encoder
+                    .compress(chunk.as_bytes(), TDEFLFlush::Sync)
+                    .unwrap(),
+            );
+        }
+        compressed.extend(encoder.compress(&[], TDEFLFlush::Finish).unwrap());
+        assert_eq!(gunzip(&compressed), b"hello gzip world");
+    }
+
+    #[test]
+    fn test_allows() {
+        let config = CompressionConfig::default();
+        assert!(config.allows("text/html; charset=utf-8"));
+        assert!(config.allows("Application/JSON"));
+        assert!(!config.allows("image/png"));
+        assert!(!config.allows("application/json-seq"));
+    }
+}
@@ -0,0 +1,365 @@
+//! The RFC 7239 `Forwarded` header, e.g. to append the downstream to it in
+//! [crate::Proxy::upstream_request_filter].
+//!
+//! ```
+//! use yapf::forwarded::{append_forwarded, ForwardedElement};
+//! use yapf::http::{header::FORWARDED, HeaderMap, HeaderValue};
+//!
+//! let mut headers = HeaderMap::new();
+//! headers.insert(FORWARDED, HeaderValue::from_static("for=192.0.2.43"));
+//! let element = ForwardedElement::new()
+//!     .with_for("[2001:db8:cafe::17]:4711")
+//!     .with_proto("https");
+//! append_forwarded(&mut headers, element).unwrap();
+//! assert_eq!(
+//!     headers[FORWARDED],
+//!     r#"for=192.0.2.43, for="[2001:db8:cafe::17]:4711";proto=https"#
+//! );
+//! ```
+
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+use http::{header::FORWARDED, HeaderMap, HeaderValue};
+
+/// The `name=value` pairs added by one proxy, in the order they were received.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForwardedElement {
+    pairs: Vec<(String, String)>,
+}
+
+impl ForwardedElement {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value of the `name` parameter, unquoted. Names are case-insensitive.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The pairs of the element, with their values unquoted.
+    pub fn pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// Set the `name` parameter to `value`, which is quoted as needed once serialized.
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self
+            .pairs
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.pairs.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn with(mut self, name: &str, value: &str) -> Self {
+        self.set(name, value);
+        self
+    }
+
+    /// The node the request came from, e.g. `192.0.2.60` or `[2001:db8::1]:4711`
+    pub fn with_for(self, node: &str) -> Self {
+        self.with("for", node)
+    }
+
+    /// The node the request came in at, i.e. this proxy
+    pub fn with_by(self, node: &str) -> Self {
+        self.with("by", node)
+    }
+
+    /// The `Host` header the request came with
+    pub fn with_host(self, host: &str) -> Self {
+        self.with("host", host)
+    }
+
+    /// The protocol the request came with, e.g. `https`
+    pub fn with_proto(self, proto: &str) -> Self {
+        self.with("proto", proto)
+    }
+}
+
+impl fmt::Display for ForwardedElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.pairs.iter().enumerate() {
+            if i > 0 {
+                f.write_str(";")?;
+            }
+            write!(f, "{name}=")?;
+            if !value.is_empty() && value.bytes().all(is_tchar) {
+                f.write_str(value)?;
+            } else {
+                f.write_str("\"")?;
+                for c in value.chars() {
+                    if c == '"' || c == '\\' {
+                        f.write_str("\\")?;
+                    }
+                    write!(f, "{c}")?;
+                }
+                f.write_str("\"")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The chain of proxies a request went through, the closest to the client first.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Forwarded {
+    elements: Vec<ForwardedElement>,
+}
+
+impl Forwarded {
+    /// Parse a `Forwarded` header value.
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut parser = Parser {
+            input: value.as_bytes(),
+            position: 0,
+        };
+        let mut forwarded = Self::default();
+        parser.list(&mut forwarded.elements)?;
+        Ok(forwarded)
+    }
+
+    /// Parse every `Forwarded` header of `headers`, as one list.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self> {
+        let mut forwarded = Self::default();
+        for value in headers.get_all(FORWARDED) {
+            let value = value
+                .to_str()
+                .map_err(|_| anyhow!("Forwarded header is not visible ASCII"))?;
+            forwarded.elements.extend(Self::parse(value)?.elements);
+        }
+        Ok(forwarded)
+    }
+
+    pub fn elements(&self) -> &[ForwardedElement] {
+        &self.elements
+    }
+
+    pub fn push(&mut self, element: ForwardedElement) {
+        self.elements.push(element);
+    }
+
+    pub fn to_header_value(&self) -> Result<HeaderValue> {
+        HeaderValue::from_str(&self.to_string())
+            .map_err(|_| anyhow!("invalid Forwarded header: {self}"))
+    }
+}
+
+impl fmt::Display for Forwarded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, element) in self.elements.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{element}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Append `element` to the `Forwarded` headers, merging them into a single one.
+///
+/// The headers are left untouched if the existing ones are malformed.
+pub fn append_forwarded(headers: &mut HeaderMap, element: ForwardedElement) -> Result<()> {
+    let mut forwarded = Forwarded::from_headers(headers)?;
+    forwarded.push(element);
+    headers.insert(FORWARDED, forwarded.to_header_value()?);
+    Ok(())
+}
+
+/// The `tchar` of RFC 9110, what a token is made of
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.position += 1;
+        }
+    }
+
+    /// `1#forwarded-element`, where empty list elements are allowed
+    fn list(&mut self, elements: &mut Vec<ForwardedElement>) -> Result<()> {
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None => return Ok(()),
+                Some(b',') => self.position += 1,
+                Some(_) => {
+                    elements.push(self.element()?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        None => return Ok(()),
+                        Some(b',') => self.position += 1,
+                        Some(c) => bail!(
+                            "unexpected {:?} at {} in Forwarded header",
+                            c as char,
+                            self.position
+                        ),
+                    }
+                }
+            }
+        }
+    }
+
+    /// `[ forwarded-pair ] *( ";" [ forwarded-pair ] )`
+    fn element(&mut self) -> Result<ForwardedElement> {
+        let mut element = ForwardedElement::new();
+        loop {
+            self.skip_whitespace();
+            if matches!(self.peek(), Some(c) if is_tchar(c)) {
+                let name = self.token()?;
+                self.skip_whitespace();
+                if self.peek() != Some(b'=') {
+                    bail!("missing value of {name:?} in Forwarded header");
+                }
+                self.position += 1;
+                self.skip_whitespace();
+                let value = match self.peek() {
+                    Some(b'"') => self.quoted_string()?,
+                    _ => self.token()?,
+                };
+                if element.get(&name).is_some() {
+                    bail!("duplicate {name:?} in Forwarded header");
+                }
+                element.pairs.push((name, value));
+                self.skip_whitespace();
+            }
+            if self.peek() != Some(b';') {
+                return Ok(element);
+            }
+            self.position += 1;
+        }
+    }
+
+    fn token(&mut self) -> Result<String> {
+        let start = self.position;
+        while matches!(self.peek(), Some(c) if is_tchar(c)) {
+            self.position += 1;
+        }
+        if start == self.position {
+            bail!("expected a token at {start} in Forwarded header");
+        }
+        Ok(String::from_utf8_lossy(&self.input[start..self.position]).into_owned())
+    }
+
+    /// `DQUOTE *( qdtext / quoted-pair ) DQUOTE`, unescaped
+    fn quoted_string(&mut self) -> Result<String> {
+        let start = self.position;
+        self.position += 1;
+        let mut value = Vec::new();
+        loop {
+            match self.peek() {
+                None => bail!("unterminated quoted string at {start} in Forwarded header"),
+                Some(b'"') => {
+                    self.position += 1;
+                    return Ok(String::from_utf8_lossy(&value).into_owned());
+                }
+                Some(b'\\') => {
+                    self.position += 1;
+                    let Some(c) = self.peek() else {
+                        bail!("unterminated quoted string at {start} in Forwarded header");
+                    };
+                    value.push(c);
+                    self.position += 1;
+                }
+                Some(c) => {
+                    value.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let forwarded = Forwarded::parse(
+            r#"for=192.0.2.43;Proto=http ;by="203.0.113.60",, for="[2001:db8:cafe::17]:4711""#,
+        )
+        .unwrap();
+        let elements = forwarded.elements();
+        assert_eq!(elements.len(), 2);
+        assert_eq!(elements[0].get("for"), Some("192.0.2.43"));
+        assert_eq!(elements[0].get("proto"), Some("http"));
+        assert_eq!(elements[0].get("by"), Some("203.0.113.60"));
+        assert_eq!(elements[1].get("for"), Some("[2001:db8:cafe::17]:4711"));
+
+        // Escaped characters in quoted strings, and commas which don't split the element
+        let forwarded = Forwarded::parse(r#"host="a\"b,c\\d";for=_hidden"#).unwrap();
+        assert_eq!(forwarded.elements().len(), 1);
+        assert_eq!(forwarded.elements()[0].get("host"), Some(r#"a"b,c\d"#));
+        assert_eq!(forwarded.to_string(), r#"host="a\"b,c\\d";for=_hidden"#);
+
+        for invalid in [
+            "for",
+            "for=",
+            "for=[2001:db8::1]",
+            r#"for="192.0.2.43"#,
+            "for=a;for=b",
+            "for=a b",
+        ] {
+            assert!(Forwarded::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_append_forwarded() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            FORWARDED,
+            HeaderValue::from_static(r#"for="[2001:db8:cafe::17]:4711";proto=https, for=unknown"#),
+        );
+        headers.append(
+            FORWARDED,
+            HeaderValue::from_static(r#"for="[2001:db8::1]";by=_proxy1;host="example.com:8080""#),
+        );
+
+        let element = ForwardedElement::new()
+            .with_for("[2001:db8::2]:5000")
+            .with_by("_proxy2")
+            .with_host("example.com")
+            .with_proto("https");
+        append_forwarded(&mut headers, element).unwrap();
+
+        assert_eq!(headers.get_all(FORWARDED).iter().count(), 1);
+        assert_eq!(
+            headers[FORWARDED],
+            concat!(
+                r#"for="[2001:db8:cafe::17]:4711";proto=https, for=unknown, "#,
+                r#"for="[2001:db8::1]";by=_proxy1;host="example.com:8080", "#,
+                r#"for="[2001:db8::2]:5000";by=_proxy2;host=example.com;proto=https"#
+            )
+        );
+        let forwarded = Forwarded::from_headers(&headers).unwrap();
+        assert_eq!(forwarded.elements().len(), 4);
+        assert_eq!(forwarded.elements()[2].get("for"), Some("[2001:db8::1]"));
+
+        // A malformed chain is left as is
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED, HeaderValue::from_static("for=[2001:db8::1]"));
+        assert!(append_forwarded(&mut headers, ForwardedElement::new().with_for("_a")).is_err());
+        assert_eq!(headers[FORWARDED], "for=[2001:db8::1]");
+    }
+}
@@ -1,11 +1,20 @@
+pub mod compression;
+pub mod forwarded;
+pub mod listeners;
 pub mod load_balancer;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
 pub mod proxy;
 pub mod proxy_trait;
 pub mod services;
 
 pub use http;
 pub use proxy::http_proxy_service;
-pub use proxy_trait::{empty_body, full_body, Body, Proxy, RequestHeaders, ResponseHeaders};
+pub use proxy_trait::{
+    boxed_body, empty_body, full_body, streamed_body, Body, BoxBody, BoxError, ClientAddr,
+    FailedRequestInfo, Proxy, RequestHeaders, ResponseHeaders, StreamedBody, UpstreamConnection,
+    UpstreamLatency,
+};
 
 #[cfg(feature = "pingora-core")]
 pub use pingora_core::{
@@ -1,59 +1,398 @@
 use anyhow::{Context, Result};
-use std::os::unix::net::SocketAddr;
-use tokio::net::TcpSocket;
+use pingora_server::server::ListenFds;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, UnixListener};
 
-// TODO: configurable backlog
+/// The default of [ListenerConfig::backlog]
 const LISTENER_BACKLOG: u32 = 65535;
 
-fn from_raw_fd(address: &SocketAddr, fd: i32) -> Result<TcpSocket> {
-    let std_listener_socket = unsafe { std::net::TcpStream::from_raw_fd(fd) };
-    let listener_socket = TcpSocket::from_std_stream(std_listener_socket);
+/// How many times to bind an address in use, e.g. by the previous process during an upgrade,
+/// backing off by one more step every time
+const TCP_LISTENER_MAX_TRY: usize = 5;
+const TCP_LISTENER_TRY_STEP: Duration = Duration::from_millis(100);
+
+/// How to listen on a TCP address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListenerConfig {
+    /// How many connections the kernel queues until they are accepted, 65535 by default.
+    ///
+    /// Capped by the kernel, e.g. by `net.core.somaxconn` on Linux.
+    pub backlog: u32,
+    /// Only accept IPv6 on an unspecified IPv6 address, instead of both IPv4 and IPv6 on `[::]`
+    pub ipv6_only: bool,
+    /// Let other sockets bind the same address with `SO_REUSEPORT`, e.g. to spread the
+    /// connections over several processes.
+    ///
+    /// Linux balances the new connections over the sockets of the same user, the BSDs only
+    /// give them to the last one bound, and macOS doesn't balance them either.
+    pub reuse_port: bool,
+    pub socket_options: TcpSocketOptions,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            backlog: LISTENER_BACKLOG,
+            ipv6_only: false,
+            reuse_port: false,
+            socket_options: TcpSocketOptions::default(),
+        }
+    }
+}
+
+/// The options of a listening socket, which its accepted connections inherit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpSocketOptions {
+    /// Disable Nagle's algorithm so that small responses aren't delayed, `true` by default
+    pub nodelay: bool,
+    /// Probe the idle connections to detect the dead peers, with the system's intervals
+    pub keepalive: bool,
+    /// The size of the kernel buffers, the system's default if unset
+    pub recv_buffer_size: Option<u32>,
+    pub send_buffer_size: Option<u32>,
+}
+
+impl Default for TcpSocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+impl TcpSocketOptions {
+    fn apply_to_listener(&self, socket: &TcpSocket) -> std::io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        socket.set_keepalive(self.keepalive)?;
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Listen on a socket inherited from the previous process of a graceful upgrade
+fn from_raw_fd(address: &str, fd: RawFd, config: &ListenerConfig) -> Result<TcpListener> {
+    let std_listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    std_listener
+        .set_nonblocking(true)
+        .with_context(|| format!("failed to set up the socket of {address}"))?;
+    let listener_socket = unsafe { TcpSocket::from_raw_fd(std_listener.into_raw_fd()) };
+    config
+        .socket_options
+        .apply_to_listener(&listener_socket)
+        .with_context(|| format!("failed to set the socket options of {address}"))?;
     // Note that we call listen on an already listening socket
     // POSIX undefined but on Linux it will update the backlog size
-    Ok(listener_socket.listen(LISTENER_BACKLOG)?)
-    // .or_err_with(BindError, || format!("Listen() failed on {address:?}"))?)
+    listener_socket
+        .listen(config.backlog)
+        .with_context(|| format!("listen() failed on {address}"))
 }
 
-async fn bind_tcp(addr: &str) -> Result<TcpSocket> {
-    let sock_addr = addr
-        .to_socket_addrs() // NOTE: this could invoke a blocking network lookup
-        .or_err_with(BindError, || format!("Invalid listen address {addr}"))?
+/// Listen on `addr`, retrying for a while if it is in use.
+///
+/// A name is bound to its first address only, bind `[::]` to listen on both IPv4 and IPv6.
+pub async fn bind_tcp(addr: &str, config: &ListenerConfig) -> Result<TcpListener> {
+    let sock_addr = tokio::net::lookup_host(addr)
+        .await
+        .with_context(|| format!("invalid listen address {addr}"))?
         .next() // take the first one for now
-        .unwrap(); // assume there is always at least one
+        .with_context(|| format!("{addr} resolves to no address"))?;
+
+    let mut try_count = 0;
+    loop {
+        let listener_socket = match sock_addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4(),
+            SocketAddr::V6(_) => TcpSocket::new_v6(),
+        }
+        .with_context(|| format!("failed to create a socket for {sock_addr}"))?;
+        // Not up to the system's default, e.g. `net.ipv6.bindv6only` on Linux
+        if sock_addr.is_ipv6() {
+            socket2::SockRef::from(&listener_socket)
+                .set_only_v6(config.ipv6_only)
+                .context("failed to set IPV6_V6ONLY")?;
+        }
+
+        // Rebind while the connections of the previous listener are in TIME_WAIT
+        listener_socket
+            .set_reuseaddr(true)
+            .context("failed to set_reuseaddr(true)")?;
+        if config.reuse_port {
+            listener_socket
+                .set_reuseport(true)
+                .context("failed to set_reuseport(true)")?;
+        }
+        config
+            .socket_options
+            .apply_to_listener(&listener_socket)
+            .with_context(|| format!("failed to set the socket options of {addr}"))?;
 
-    let listener_socket = match sock_addr {
-        SocketAddr::V4(_) => TcpSocket::new_v4(),
-        SocketAddr::V6(_) => TcpSocket::new_v6(),
+        match listener_socket.bind(sock_addr) {
+            Ok(()) => {
+                return listener_socket
+                    .listen(config.backlog)
+                    .with_context(|| format!("listen() failed on {addr}"))
+            }
+            Err(e) if e.kind() != ErrorKind::AddrInUse => {
+                return Err(e).with_context(|| format!("bind() failed on {addr}"));
+            }
+            Err(e) => {
+                try_count += 1;
+                if try_count >= TCP_LISTENER_MAX_TRY {
+                    return Err(e).with_context(|| {
+                        format!("bind() failed, after retries, {addr} still in use")
+                    });
+                }
+                tracing::warn!(addr, try_count, "address in use, retrying bind()");
+                tokio::time::sleep(TCP_LISTENER_TRY_STEP * try_count as u32).await;
+            }
+        }
     }
-    .or_err_with(BindError, || format!("fail to create address {sock_addr}"))?;
+}
 
-    // NOTE: this is to preserve the current TcpListener::bind() behavior.
-    // We have a few tests relying on this behavior to allow multiple identical
-    // test servers to coexist.
-    listener_socket
-        .set_reuseaddr(true)
-        .or_err(BindError, "fail to set_reuseaddr(true)")?;
-
-    // apply_tcp_socket_options(&listener_socket, opt.as_ref())?;
-    // listener_socket.bind(sock_addr).
-
-    match listener_socket.bind(sock_addr) {
-        Ok(()) => {
-            return Ok(listener_socket
-                .listen(LISTENER_BACKLOG)
-                .or_err(BindError, "bind() failed")?
-                .into())
-        }
-        Err(e) => {
-            if e.kind() != ErrorKind::AddrInUse {
-                return Err(e).or_err_with(BindError, || format!("bind() failed on {addr}"));
+/// Who may connect to the sockets of [bind_uds], the owner and its group
+const UDS_PERMISSIONS: u32 = 0o660;
+
+/// Listen on a Unix domain socket at `path`, e.g. for a front proxy on the same host.
+///
+/// The file of a socket nobody listens on anymore, e.g. left by a crashed process, is removed
+/// first.
+pub fn bind_uds(path: &Path) -> Result<UnixListener> {
+    let display = path.display();
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{display} exists and isn't a socket");
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            anyhow::bail!("{display} is in use");
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove the stale socket {display}"))?;
+    }
+    let listener = match UnixListener::bind(path) {
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            return Err(e).with_context(|| format!("{display} was bound by another process"));
+        }
+        listener => listener.with_context(|| format!("bind() failed on {display}"))?,
+    };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(UDS_PERMISSIONS))
+        .with_context(|| format!("failed to set the permissions of {display}"))?;
+    Ok(listener)
+}
+
+/// Listen on `addr`, on the socket inherited for it in `fds` if any, rather than binding it
+/// again, e.g. during a graceful upgrade.
+///
+/// A newly bound socket is added to `fds` to be passed on to the next process.
+pub(crate) async fn listen(
+    addr: &str,
+    config: &ListenerConfig,
+    fds: Option<&ListenFds>,
+) -> Result<TcpListener> {
+    let Some(fds) = fds else {
+        return bind_tcp(addr, config).await;
+    };
+    let mut fds = fds.lock().await;
+    if let Some(fd) = fds.get(addr) {
+        return from_raw_fd(addr, *fd, config);
+    }
+    let listener = bind_tcp(addr, config).await?;
+    fds.add(addr.to_string(), listener.as_raw_fd());
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_bind_tcp() {
+        let listener = bind_tcp("127.0.0.1:0", &ListenerConfig::default())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_ne!(addr.port(), 0);
+        TcpStream::connect(addr).await.unwrap();
+
+        let error = bind_tcp("not an address", &ListenerConfig::default())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "invalid listen address not an address");
+    }
+
+    #[tokio::test]
+    async fn test_bind_tcp_reuse_addr() {
+        // Leave a connection of the closed listener in TIME_WAIT
+        let listener = bind_tcp("127.0.0.1:0", &ListenerConfig::default())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.shutdown().await.unwrap();
+        drop(server);
+        drop(listener);
+        client.shutdown().await.unwrap();
+
+        bind_tcp(&addr.to_string(), &ListenerConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reuse_port() {
+        let config = ListenerConfig {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let first = bind_tcp("127.0.0.1:0", &config).await.unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+        let second = bind_tcp(&addr, &config).await.unwrap();
+
+        // The connections are spread over both, by the hash of their source port
+        let mut clients = Vec::new();
+        let (mut first_accepted, mut second_accepted) = (0, 0);
+        while first_accepted == 0 || second_accepted == 0 {
+            assert!(clients.len() < 64, "{first_accepted} {second_accepted}");
+            clients.push(TcpStream::connect(&addr).await.unwrap());
+            tokio::select! {
+                accepted = first.accept() => {
+                    accepted.unwrap();
+                    first_accepted += 1;
+                }
+                accepted = second.accept() => {
+                    accepted.unwrap();
+                    second_accepted += 1;
+                }
             }
-            try_count += 1;
-            if try_count >= TCP_LISTENER_MAX_TRY {
-                return Err(e).or_err_with(BindError, || {
-                    format!("bind() failed, after retries, {addr} still in use")
-                });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        let listener = bind_tcp("[::]:0", &ListenerConfig::default())
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        for client in ["127.0.0.1", "::1"] {
+            let client: std::net::IpAddr = client.parse().unwrap();
+            TcpStream::connect((client, port)).await.unwrap();
+            listener.accept().await.unwrap();
+        }
+
+        let config = ListenerConfig {
+            ipv6_only: true,
+            ..Default::default()
+        };
+        let listener = bind_tcp("[::]:0", &config).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        TcpStream::connect(("::1", port)).await.unwrap();
+        TcpStream::connect(("127.0.0.1", port)).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        for nodelay in [true, false] {
+            let config = ListenerConfig {
+                socket_options: TcpSocketOptions {
+                    nodelay,
+                    keepalive: true,
+                    recv_buffer_size: Some(64 * 1024),
+                    send_buffer_size: Some(64 * 1024),
+                },
+                ..Default::default()
+            };
+            let listener = bind_tcp("127.0.0.1:0", &config).await.unwrap();
+            let _client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            // Inherited from the listener
+            let (stream, _) = listener.accept().await.unwrap();
+            assert_eq!(stream.nodelay().unwrap(), nodelay);
+            assert!(socket2::SockRef::from(&stream).keepalive().unwrap());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_backlog() {
+        // How many connections are established without being accepted
+        async fn queued(config: &ListenerConfig) -> usize {
+            let listener = bind_tcp("127.0.0.1:0", config).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut clients = Vec::new();
+            while clients.len() < 8 {
+                let connect = TcpStream::connect(addr);
+                match tokio::time::timeout(Duration::from_millis(200), connect).await {
+                    Ok(client) => clients.push(client.unwrap()),
+                    Err(_) => break,
+                }
             }
+            clients.len()
         }
+
+        // Linux queues one more than the backlog
+        let config = ListenerConfig {
+            backlog: 2,
+            ..Default::default()
+        };
+        assert_eq!(queued(&config).await, 3);
+        assert_eq!(queued(&ListenerConfig::default()).await, 8);
+    }
+
+    #[tokio::test]
+    async fn test_bind_uds() {
+        let path = std::env::temp_dir().join(format!("yapf-bind-uds-{}.sock", std::process::id()));
+        let listener = bind_uds(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, UDS_PERMISSIONS);
+        let error = bind_uds(&path).unwrap_err();
+        assert!(error.to_string().contains("is in use"), "{error}");
+
+        // The file is left behind once the listener is closed
+        drop(listener);
+        assert!(path.exists());
+        let _listener = bind_uds(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let file = std::env::temp_dir().join(format!("yapf-bind-uds-{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let error = bind_uds(&file).unwrap_err();
+        std::fs::remove_file(&file).unwrap();
+        assert!(error.to_string().contains("isn't a socket"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_bind_tcp_in_use() {
+        let listener = bind_tcp("127.0.0.1:0", &ListenerConfig::default())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let error = bind_tcp(&addr, &ListenerConfig::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("still in use"), "{error}");
+
+        // Bound once the address is released
+        tokio::spawn(async move {
+            tokio::time::sleep(TCP_LISTENER_TRY_STEP / 2).await;
+            drop(listener);
+        });
+        bind_tcp(&addr, &ListenerConfig::default()).await.unwrap();
     }
 }
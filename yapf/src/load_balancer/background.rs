@@ -3,33 +3,52 @@ use async_trait::async_trait;
 use pingora_core::{server::ShutdownWatch, services::background::BackgroundService};
 #[cfg(not(feature = "pingora-core"))]
 use pingora_server::{server::ShutdownWatch, services::background::BackgroundService};
+use rand::Rng;
 use tokio::time::{self, Duration, Instant};
 
 use super::{LoadBalancer, Strategy};
 
+impl<T> LoadBalancer<T> {
+    /// The interval until the next health check, with a new jitter every time
+    fn health_check_delay(&self) -> Option<Duration> {
+        let interval = self.health_check_interval?;
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.health_check_jitter);
+        Some(interval + jitter)
+    }
+}
+
 #[async_trait]
 impl<T: Strategy + Send + Sync + 'static> BackgroundService for LoadBalancer<T> {
     async fn start(&self, shutdown: ShutdownWatch) {
         const NEVER: Duration = Duration::from_secs(u32::MAX as u64);
         let mut now = Instant::now();
 
-        // Run health check once immediately
+        // Update the backends and run health check once immediately
+        let mut next_update = now;
         let mut next_health_check = now;
         loop {
             if *shutdown.borrow() {
                 break;
             }
 
+            if next_update <= now {
+                // Keep balancing over the last known backends if the provider fails
+                if let Err(e) = self.update().await {
+                    tracing::warn!(error = %e, "failed to update backends");
+                }
+                next_update = now + self.update_interval.unwrap_or(NEVER);
+            }
+
             if next_health_check <= now {
                 self.run_health_check().await;
-                next_health_check = now + self.health_check_interval.unwrap_or(NEVER);
+                next_health_check = now + self.health_check_delay().unwrap_or(NEVER);
             }
 
-            if self.health_check_interval.is_none() {
+            if self.update_interval.is_none() && self.health_check_interval.is_none() {
                 break;
             }
 
-            time::sleep_until(next_health_check).await;
+            time::sleep_until(next_update.min(next_health_check)).await;
             now = Instant::now();
         }
     }
@@ -39,8 +58,11 @@ impl<T: Strategy + Send + Sync + 'static> BackgroundService for LoadBalancer<T>
 mod tests {
     use super::*;
     use crate::load_balancer::{
-        helthcheck::HttpHealthCheck, strategy::RoundRobin, Backend, LoadBalancer,
+        helthcheck::{HealthCheck, HttpHealthCheck},
+        strategy::RoundRobin,
+        Backend, LoadBalancer,
     };
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
 
     #[cfg(feature = "pingora-core")]
@@ -103,17 +125,84 @@ mod tests {
         // Wait for health check to run first time
         tokio::time::sleep(Duration::from_millis(100)).await;
         // All backends should be healthy
-        assert_eq!(lb.next().unwrap(), &backend1);
-        assert_eq!(lb.next().unwrap(), &backend2);
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend2);
 
         // By now health check should have run and backend2 should be unhealthy
         tokio::time::sleep(Duration::from_secs(2)).await;
-        assert_eq!(lb.next().unwrap(), &backend1);
-        assert_eq!(lb.next().unwrap(), &backend1);
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend1);
 
         // Shutdown background service, backend1 should remain healthy
         shutdown_sender.send(true).unwrap();
         tokio::time::sleep(Duration::from_millis(10)).await;
-        assert_eq!(lb.next().unwrap(), &backend1);
+        assert_eq!(*lb.next().unwrap(), backend1);
+    }
+
+    /// Counts the checks, which always pass
+    #[derive(Debug, Default)]
+    struct CountingHealthCheck(AtomicUsize);
+
+    #[async_trait]
+    impl HealthCheck for CountingHealthCheck {
+        async fn check(&self, _target: &Backend) -> anyhow::Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn health_threshold(&self, _success: bool) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check_interval() {
+        let health_check = Arc::new(CountingHealthCheck::default());
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![Backend::new("1.0.0.1".to_string())]);
+        lb.set_health_check(health_check.clone());
+        lb.set_health_check_interval(Duration::from_millis(100));
+
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let lb = Arc::new(lb);
+        let background = tokio::spawn({
+            let lb = lb.clone();
+            async move { lb.start(shutdown_receiver).await }
+        });
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        shutdown_sender.send(true).unwrap();
+        background.await.unwrap();
+        let checks = health_check.0.load(Ordering::Relaxed);
+        assert!((3..=5).contains(&checks), "{checks}");
+
+        // Without an interval the check only runs once
+        let health_check = Arc::new(CountingHealthCheck::default());
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![Backend::new("1.0.0.1".to_string())]);
+        lb.set_health_check(health_check.clone());
+        lb.set_health_check_interval(Duration::from_millis(100));
+        lb.clear_health_check_interval();
+        let (_shutdown_sender, shutdown_receiver) = watch::channel(false);
+        lb.start(shutdown_receiver).await;
+        assert_eq!(health_check.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_health_check_jitter() {
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![Backend::new("1.0.0.1".to_string())]);
+        assert_eq!(lb.health_check_delay(), None);
+
+        let interval = Duration::from_secs(10);
+        lb.set_health_check_interval(interval);
+        assert_eq!(lb.health_check_delay(), Some(interval));
+
+        let jitter = Duration::from_secs(2);
+        lb.set_health_check_jitter(jitter);
+        let delays: Vec<_> = (0..20).map(|_| lb.health_check_delay().unwrap()).collect();
+        assert!(delays
+            .iter()
+            .all(|delay| (interval..=interval + jitter).contains(delay)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]), "{delays:?}");
     }
 }
@@ -0,0 +1,113 @@
+use std::fmt::Debug;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use hyper::Uri;
+
+use super::Backend;
+
+/// [BackendProvider] is the interface to discover the backends of a load balancer
+#[async_trait]
+pub trait BackendProvider: Debug {
+    /// Return the current backends.
+    ///
+    /// On `Err` the load balancer keeps the backends it already has.
+    async fn fetch(&self) -> Result<Vec<Backend>>;
+}
+
+/// A fixed list of backends
+#[derive(Debug, Default)]
+pub struct StaticBackends {
+    backends: Vec<Backend>,
+}
+
+impl StaticBackends {
+    pub fn new(backends: Vec<Backend>) -> Self {
+        Self { backends }
+    }
+}
+
+#[async_trait]
+impl BackendProvider for StaticBackends {
+    async fn fetch(&self) -> Result<Vec<Backend>> {
+        Ok(self.backends.clone())
+    }
+}
+
+/// Backends resolved from DNS names, one per address of their A/AAAA records.
+///
+/// The names are URIs like `http://example.com:8080`, whose host is replaced by each of the
+/// addresses it resolves to.
+#[derive(Debug)]
+pub struct DnsBackends {
+    names: Vec<Uri>,
+}
+
+impl DnsBackends {
+    pub fn new(names: &[&str]) -> Result<Self> {
+        let names = names
+            .iter()
+            .map(|name| {
+                let uri = name.parse::<Uri>()?;
+                if uri.host().is_none() {
+                    return Err(anyhow!("{name} has no host to resolve"));
+                }
+                Ok(uri)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { names })
+    }
+
+    /// Resolve the names, blocking the current thread
+    pub fn resolve_blocking(&self) -> Result<Vec<Backend>> {
+        let mut backends = Vec::new();
+        for name in &self.names {
+            let addrs = (host(name), port(name)).to_socket_addrs()?;
+            backends.extend(Self::backends(name, addrs)?);
+        }
+        Ok(backends)
+    }
+
+    fn backends(name: &Uri, addrs: impl Iterator<Item = SocketAddr>) -> Result<Vec<Backend>> {
+        let scheme = name.scheme_str().unwrap_or("http");
+        let path = name.path_and_query().map_or("/", |path| path.as_str());
+        let backends: Vec<Backend> = addrs
+            .map(|addr| Backend::new(format!("{scheme}://{addr}{path}")))
+            .collect();
+        // Keep the last known backends rather than dropping all of the name's
+        if backends.is_empty() {
+            return Err(anyhow!("{name} resolved to no addresses"));
+        }
+        Ok(backends)
+    }
+}
+
+/// The host of `name`, without the brackets of an IPv6 address
+pub(super) fn host(name: &Uri) -> &str {
+    let host = name.host().unwrap_or_default();
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+pub(super) fn port(name: &Uri) -> u16 {
+    name.port_u16()
+        .unwrap_or(if name.scheme_str() == Some("https") {
+            443
+        } else {
+            80
+        })
+}
+
+#[async_trait]
+impl BackendProvider for DnsBackends {
+    async fn fetch(&self) -> Result<Vec<Backend>> {
+        let mut backends = Vec::new();
+        for name in &self.names {
+            let addrs = tokio::net::lookup_host((host(name), port(name))).await?;
+            backends.extend(Self::backends(name, addrs)?);
+        }
+        Ok(backends)
+    }
+}
@@ -1,15 +1,23 @@
 use std::fmt::Debug;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+pub use reqwest::Certificate;
 use reqwest::{
     header::{HeaderMap, HeaderName, HeaderValue},
     ClientBuilder, Method, Url,
 };
 
+use super::discovery::{host, port};
 use super::Backend;
 
 /// [HealthCheck] is the interface to implement health check for backends
@@ -26,59 +34,281 @@ pub trait HealthCheck: Debug {
     fn health_threshold(&self, success: bool) -> usize;
 }
 
+/// The default of [HttpHealthCheck::set_max_body_size]
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// The defaults of [HttpHealthCheck::set_timeout] and [HttpHealthCheck::set_pool_idle_timeout]
+const TIMEOUT: Duration = Duration::from_secs(30);
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// How the body of a health check response should look like to pass
+enum BodyMatcher {
+    Substring(String),
+    Fn(Box<dyn Fn(&Bytes) -> bool + Send + Sync>),
+}
+
+impl BodyMatcher {
+    fn matches(&self, body: &Bytes) -> bool {
+        match self {
+            Self::Substring(substring) => {
+                substring.is_empty()
+                    || body
+                        .windows(substring.len())
+                        .any(|window| window == substring.as_bytes())
+            }
+            Self::Fn(matcher) => matcher(body),
+        }
+    }
+}
+
+impl Debug for BodyMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Substring(substring) => f.debug_tuple("Substring").field(substring).finish(),
+            Self::Fn(_) => f.write_str("Fn"),
+        }
+    }
+}
+
+/// Decides from the response whether a check passes, see [HttpHealthCheck::set_success_fn]
+struct SuccessFn(Box<dyn Fn(&reqwest::Response) -> bool + Send + Sync>);
+
+impl Debug for SuccessFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SuccessFn")
+    }
+}
+
 #[derive(Debug)]
-pub struct HttpHealthCheck<'a> {
+pub struct HttpHealthCheck {
     client: reqwest::Client,
+    /// Whether the client was given, see [HttpHealthCheck::with_client]
+    custom_client: bool,
+    timeout: Duration,
+    pool_idle_timeout: Duration,
+    /// The roots trusted besides the system ones
+    tls_roots: Vec<Certificate>,
+    accept_invalid_certs: bool,
+    /// Whether every check opens a new connection
+    fresh_connections: bool,
     method: Method,
-    path: Option<&'a str>,
+    path: Option<String>,
     headers: HeaderMap,
     body: Option<String>,
+    /// The headers a response should have to pass the check
+    expected_headers: HeaderMap,
+    body_matcher: Option<BodyMatcher>,
+    max_body_size: usize,
+    /// Replaces the check of a 2xx status
+    success_fn: Option<SuccessFn>,
+    /// The consecutive passing checks to readmit an unhealthy backend
+    healthy_threshold: usize,
+    /// The consecutive failing checks to eject a healthy backend
+    unhealthy_threshold: usize,
+}
+
+impl Default for HttpHealthCheck {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl HttpHealthCheck<'_> {
+impl HttpHealthCheck {
     pub fn new() -> Self {
-        // TODO: make this configurable
-        let client = ClientBuilder::new()
-            .timeout(Duration::from_secs(30))
-            .pool_idle_timeout(Duration::from_secs(90))
-            .build()
-            .unwrap();
+        let client = Self::client(TIMEOUT, POOL_IDLE_TIMEOUT, &[], false, false);
+        Self::from_client(client, false)
+    }
+
+    /// Check with `client`, e.g. to share its connection pool, proxy or DNS settings with the
+    /// rest of the application.
+    ///
+    /// The client is used as is, the timeouts and TLS settings of the check are ignored.
+    pub fn with_client(client: reqwest::Client) -> Self {
+        Self::from_client(client, true)
+    }
 
+    fn from_client(client: reqwest::Client, custom_client: bool) -> Self {
         Self {
             client,
+            custom_client,
+            timeout: TIMEOUT,
+            pool_idle_timeout: POOL_IDLE_TIMEOUT,
+            tls_roots: Vec::new(),
+            accept_invalid_certs: false,
+            fresh_connections: false,
             method: Method::GET,
             path: None,
             body: None,
             headers: HeaderMap::new(),
+            expected_headers: HeaderMap::new(),
+            body_matcher: None,
+            max_body_size: MAX_BODY_SIZE,
+            success_fn: None,
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
         }
     }
 
+    fn client(
+        timeout: Duration,
+        pool_idle_timeout: Duration,
+        tls_roots: &[Certificate],
+        accept_invalid_certs: bool,
+        fresh_connections: bool,
+    ) -> reqwest::Client {
+        let mut builder = ClientBuilder::new()
+            .timeout(timeout)
+            .pool_idle_timeout(pool_idle_timeout)
+            .danger_accept_invalid_certs(accept_invalid_certs);
+        if fresh_connections {
+            builder = builder.pool_max_idle_per_host(0);
+        }
+        for root in tls_roots {
+            builder = builder.add_root_certificate(root.clone());
+        }
+        builder.build().unwrap()
+    }
+
+    fn rebuild_client(&mut self) {
+        if self.custom_client {
+            return;
+        }
+        self.client = Self::client(
+            self.timeout,
+            self.pool_idle_timeout,
+            &self.tls_roots,
+            self.accept_invalid_certs,
+            self.fresh_connections,
+        );
+    }
+
+    /// How long a check may take, 30 seconds by default
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+        self.rebuild_client();
+    }
+
+    /// How long an idle connection to a backend is kept for the next check, 90 seconds by
+    /// default
+    pub fn set_pool_idle_timeout(&mut self, pool_idle_timeout: Duration) {
+        self.pool_idle_timeout = pool_idle_timeout;
+        self.rebuild_client();
+    }
+
+    /// Open a new connection, resolving the name of the backend again, for every check instead
+    /// of reusing the previous one. Disabled by default.
+    ///
+    /// A reused connection keeps checking the address the name resolved to back then, which is
+    /// wrong once the name moves to another address. In exchange, every check pays for a new
+    /// connection, and its TLS handshake for HTTPS backends.
+    pub fn set_fresh_connections(&mut self, fresh: bool) {
+        self.fresh_connections = fresh;
+        self.rebuild_client();
+    }
+
+    /// Trust `roots` besides the system roots for HTTPS backends, e.g. a private CA.
+    pub fn set_tls_roots(&mut self, roots: Vec<Certificate>) {
+        self.tls_roots = roots;
+        self.rebuild_client();
+    }
+
+    /// Accept any certificate of HTTPS backends, even expired or for another name.
+    ///
+    /// This disables the authentication of the backends, prefer [HttpHealthCheck::set_tls_roots].
+    pub fn set_danger_accept_invalid_certs(&mut self, accept: bool) {
+        self.accept_invalid_certs = accept;
+        self.rebuild_client();
+    }
+
+    /// How many consecutive checks should pass to readmit an unhealthy backend, 1 by default
+    pub fn set_healthy_threshold(&mut self, threshold: usize) {
+        self.healthy_threshold = threshold;
+    }
+
+    /// How many consecutive checks should fail to eject a healthy backend, 1 by default
+    pub fn set_unhealthy_threshold(&mut self, threshold: usize) {
+        self.unhealthy_threshold = threshold;
+    }
+
     pub fn set_method(&mut self, method: Method) {
         self.method = method;
     }
 
-    pub fn set_path(&mut self, path: &'static str) {
-        self.path = Some(path);
+    pub fn set_path(&mut self, path: impl Into<String>) {
+        self.path = Some(path.into());
     }
 
     pub fn set_header(&mut self, key: HeaderName, value: HeaderValue) {
         self.headers.insert(key, value);
     }
 
+    /// Authenticate the checks with HTTP basic auth.
+    pub fn set_basic_auth(&mut self, user: &str, pass: Option<&str>) {
+        let credentials = BASE64_STANDARD.encode(format!("{user}:{}", pass.unwrap_or_default()));
+        self.set_authorization(format!("Basic {credentials}"));
+    }
+
+    /// Authenticate the checks with a bearer token.
+    ///
+    /// Panics if `token` has characters not allowed in a header, e.g. a newline.
+    pub fn set_bearer_token(&mut self, token: &str) {
+        self.set_authorization(format!("Bearer {token}"));
+    }
+
+    fn set_authorization(&mut self, value: String) {
+        let mut value = HeaderValue::try_from(value).expect("invalid credentials");
+        value.set_sensitive(true);
+        self.headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+
     pub fn set_body(&mut self, body: String) {
         self.body = Some(body);
     }
+
+    /// Only pass the check if the response also has the header `key` with `value`.
+    pub fn set_expected_header(&mut self, key: HeaderName, value: HeaderValue) {
+        self.expected_headers.insert(key, value);
+    }
+
+    /// Only pass the check if the response body contains `substring`.
+    pub fn set_expected_body_substring(&mut self, substring: String) {
+        self.body_matcher = Some(BodyMatcher::Substring(substring));
+    }
+
+    /// Only pass the check if `matcher` accepts the response body.
+    pub fn set_body_matcher(&mut self, matcher: Box<dyn Fn(&Bytes) -> bool + Send + Sync>) {
+        self.body_matcher = Some(BodyMatcher::Fn(matcher));
+    }
+
+    /// Pass the check if `success` accepts the response, e.g. based on its status and headers,
+    /// instead of for any `2xx` status.
+    pub fn set_success_fn(
+        &mut self,
+        success: Box<dyn Fn(&reqwest::Response) -> bool + Send + Sync>,
+    ) {
+        self.success_fn = Some(SuccessFn(success));
+    }
+
+    /// Fail the check of a response body over `size` bytes when matching it, 64 KiB by
+    /// default.
+    pub fn set_max_body_size(&mut self, size: usize) {
+        self.max_body_size = size;
+    }
 }
 
 #[async_trait]
-impl HealthCheck for HttpHealthCheck<'_> {
+impl HealthCheck for HttpHealthCheck {
     async fn check(&self, target: &Backend) -> Result<()> {
         // Build a new request with the target address
+        let url = Url::parse(target.health_check_target()).with_context(|| {
+            format!(
+                "invalid health check address: {}",
+                target.health_check_target()
+            )
+        })?;
+        let mut request = reqwest::Request::new(self.method.clone(), url);
 
-        let mut request =
-            reqwest::Request::new(self.method.clone(), Url::parse(&target.addr).unwrap());
-
-        if let Some(path) = self.path {
+        if let Some(path) = &self.path {
             let url = request.url_mut();
             url.set_path(path);
         }
@@ -89,18 +319,468 @@ impl HealthCheck for HttpHealthCheck<'_> {
             *request.body_mut() = Some(reqwest::Body::from(body.clone()));
         }
 
-        let response = self.client.execute(request).await?;
+        let mut response = self.client.execute(request).await?;
+        if let Some(success) = &self.success_fn {
+            if !(success.0)(&response) {
+                return Err(anyhow::anyhow!(format!(
+                    "health check failed with response: {}",
+                    response.status()
+                )));
+            }
+        } else if !response.status().is_success() {
+            return Err(anyhow::anyhow!(format!(
+                "health check failed with status: {}",
+                response.status()
+            )));
+        }
+        for (key, value) in &self.expected_headers {
+            if !response.headers().get_all(key).iter().any(|v| v == value) {
+                return Err(anyhow::anyhow!(format!(
+                    "health check failed without header: {key}: {value:?}"
+                )));
+            }
+        }
+        if let Some(matcher) = &self.body_matcher {
+            // Read the body up to the limit only, in case the backend misbehaves
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                if body.len() + chunk.len() > self.max_body_size {
+                    return Err(anyhow::anyhow!(format!(
+                        "health check failed with a body over {} bytes",
+                        self.max_body_size
+                    )));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            if !matcher.matches(&Bytes::from(body)) {
+                return Err(anyhow::anyhow!("health check failed with unexpected body"));
+            }
+        }
+        Ok(())
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.healthy_threshold
+        } else {
+            self.unhealthy_threshold
+        }
+    }
+}
+
+/// Checks that a TCP connection to the backend can be established, for backends which don't
+/// speak HTTP.
+///
+/// The address is the host and port of [Backend::health_check_target], the port defaulting to
+/// the one of its scheme.
+#[derive(Debug)]
+pub struct TcpHealthCheck {
+    timeout: Duration,
+    /// The consecutive passing checks to readmit an unhealthy backend
+    healthy_threshold: usize,
+    /// The consecutive failing checks to eject a healthy backend
+    unhealthy_threshold: usize,
+}
+
+impl Default for TcpHealthCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TcpHealthCheck {
+    pub fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+        }
+    }
+
+    /// How long to wait for the connection, 5 seconds by default
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// How many consecutive checks should pass to readmit an unhealthy backend, 1 by default
+    pub fn set_healthy_threshold(&mut self, threshold: usize) {
+        self.healthy_threshold = threshold;
+    }
+
+    /// How many consecutive checks should fail to eject a healthy backend, 1 by default
+    pub fn set_unhealthy_threshold(&mut self, threshold: usize) {
+        self.unhealthy_threshold = threshold;
+    }
+}
+
+#[async_trait]
+impl HealthCheck for TcpHealthCheck {
+    async fn check(&self, target: &Backend) -> Result<()> {
+        let addr: hyper::Uri = target.health_check_target().parse()?;
+        let connect = tokio::net::TcpStream::connect((host(&addr), port(&addr)));
+        match tokio::time::timeout(self.timeout, connect).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(anyhow::anyhow!("health check failed to connect: {e}")),
+            Err(_) => Err(anyhow::anyhow!(
+                "health check timed out connecting after {:?}",
+                self.timeout
+            )),
+        }
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.healthy_threshold
+        } else {
+            self.unhealthy_threshold
+        }
+    }
+}
+
+/// Checks backends listening on a Unix socket with an HTTP request, the socket being the path
+/// of a `unix://` [Backend::health_check_target], e.g. `unix:///run/app.sock`.
+///
+/// The check passes on a `2xx` response.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UdsHealthCheck {
+    path: String,
+    timeout: Duration,
+    /// The consecutive passing checks to readmit an unhealthy backend
+    healthy_threshold: usize,
+    /// The consecutive failing checks to eject a healthy backend
+    unhealthy_threshold: usize,
+}
+
+#[cfg(unix)]
+impl Default for UdsHealthCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(unix)]
+impl UdsHealthCheck {
+    pub fn new() -> Self {
+        Self {
+            path: "/".to_string(),
+            timeout: Duration::from_secs(5),
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+        }
+    }
+
+    /// The path of the request, `/` by default
+    pub fn set_path(&mut self, path: impl Into<String>) {
+        self.path = path.into();
+    }
+
+    /// How long the check may take, 5 seconds by default
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// How many consecutive checks should pass to readmit an unhealthy backend, 1 by default
+    pub fn set_healthy_threshold(&mut self, threshold: usize) {
+        self.healthy_threshold = threshold;
+    }
+
+    /// How many consecutive checks should fail to eject a healthy backend, 1 by default
+    pub fn set_unhealthy_threshold(&mut self, threshold: usize) {
+        self.unhealthy_threshold = threshold;
+    }
+
+    async fn request(&self, socket: &str) -> Result<hyper::StatusCode> {
+        let stream = tokio::net::UnixStream::connect(socket).await?;
+        let (mut sender, connection) =
+            hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream)).await?;
+        tokio::spawn(connection);
+        let request = hyper::Request::get(&self.path)
+            .header(hyper::header::HOST, "localhost")
+            .body(http_body_util::Empty::<Bytes>::new())?;
+        Ok(sender.send_request(request).await?.status())
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl HealthCheck for UdsHealthCheck {
+    async fn check(&self, target: &Backend) -> Result<()> {
+        let addr = target.health_check_target();
+        let Some(socket) = addr.strip_prefix("unix://") else {
+            return Err(anyhow::anyhow!("{addr} is not a unix:// address"));
+        };
+        match tokio::time::timeout(self.timeout, self.request(socket)).await {
+            Ok(Ok(status)) if status.is_success() => Ok(()),
+            Ok(Ok(status)) => Err(anyhow::anyhow!("health check failed with status: {status}")),
+            Ok(Err(e)) => Err(anyhow::anyhow!("health check failed: {e}")),
+            Err(_) => Err(anyhow::anyhow!(
+                "health check timed out after {:?}",
+                self.timeout
+            )),
+        }
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.healthy_threshold
+        } else {
+            self.unhealthy_threshold
+        }
+    }
+}
+
+/// The `ServingStatus` of a `grpc.health.v1.HealthCheckResponse`
+const SERVING: u64 = 1;
+
+/// Checks backends with the gRPC health checking protocol, the `grpc.health.v1.Health/Check`
+/// call, a backend passing when it answers `SERVING`.
+///
+/// The call is made over HTTP/2 to [Backend::health_check_target], with prior knowledge for
+/// `http` and negotiated for `https`.
+#[derive(Debug)]
+pub struct GrpcHealthCheck {
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    service: String,
+    timeout: Duration,
+    /// The consecutive passing checks to readmit an unhealthy backend
+    healthy_threshold: usize,
+    /// The consecutive failing checks to eject a healthy backend
+    unhealthy_threshold: usize,
+}
+
+impl Default for GrpcHealthCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GrpcHealthCheck {
+    pub fn new() -> Self {
+        // A host without a certificate store can still check plaintext backends
+        let tls = rustls::ClientConfig::builder()
+            .with_root_certificates(crate::proxy::native_roots())
+            .with_no_client_auth();
+        let https = HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http2()
+            .build();
+        let client = Client::builder(TokioExecutor::new())
+            .http2_only(true)
+            .build(https);
+
+        Self {
+            client,
+            service: String::new(),
+            timeout: Duration::from_secs(5),
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+        }
+    }
+
+    /// The service to check the health of, the whole server by default
+    pub fn set_service(&mut self, service: String) {
+        self.service = service;
+    }
+
+    /// How long to wait for the answer, 5 seconds by default
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// How many consecutive checks should pass to readmit an unhealthy backend, 1 by default
+    pub fn set_healthy_threshold(&mut self, threshold: usize) {
+        self.healthy_threshold = threshold;
+    }
+
+    /// How many consecutive checks should fail to eject a healthy backend, 1 by default
+    pub fn set_unhealthy_threshold(&mut self, threshold: usize) {
+        self.unhealthy_threshold = threshold;
+    }
+
+    /// A length prefixed `HealthCheckRequest` message
+    fn request_body(&self) -> Bytes {
+        let mut message = Vec::new();
+        if !self.service.is_empty() {
+            // Field 1, length delimited
+            message.push(0x0a);
+            put_varint(&mut message, self.service.len() as u64);
+            message.extend_from_slice(self.service.as_bytes());
+        }
+        let mut body = vec![0];
+        body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        body.extend(message);
+        body.into()
+    }
+
+    async fn call(&self, target: &Backend) -> Result<u64> {
+        let target: hyper::Uri = target.health_check_target().parse()?;
+        let mut uri = hyper::Uri::builder().path_and_query("/grpc.health.v1.Health/Check");
+        if let Some(scheme) = target.scheme() {
+            uri = uri.scheme(scheme.clone());
+        }
+        if let Some(authority) = target.authority() {
+            uri = uri.authority(authority.clone());
+        }
+        let request = hyper::Request::post(uri.build()?)
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(Full::new(self.request_body()))?;
+
+        let response = self.client.request(request).await?;
         if !response.status().is_success() {
             return Err(anyhow::anyhow!(format!(
                 "health check failed with status: {}",
                 response.status()
             )));
         }
+        // Errors come without a message, in the headers of a trailers-only response
+        grpc_status(response.headers())?;
+        let body = response.into_body().collect().await?;
+        if let Some(trailers) = body.trailers() {
+            grpc_status(trailers)?;
+        }
+        parse_serving_status(&body.to_bytes())
+    }
+}
+
+#[async_trait]
+impl HealthCheck for GrpcHealthCheck {
+    async fn check(&self, target: &Backend) -> Result<()> {
+        let status = tokio::time::timeout(self.timeout, self.call(target))
+            .await
+            .map_err(|_| anyhow::anyhow!("health check timed out after {:?}", self.timeout))??;
+        if status != SERVING {
+            return Err(anyhow::anyhow!(format!(
+                "health check failed with serving status: {status}"
+            )));
+        }
         Ok(())
     }
 
-    fn health_threshold(&self, _success: bool) -> usize {
-        1
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.healthy_threshold
+        } else {
+            self.unhealthy_threshold
+        }
+    }
+}
+
+/// Fail on a `grpc-status` other than `0`, i.e. `OK`
+fn grpc_status(headers: &HeaderMap) -> Result<()> {
+    match headers.get("grpc-status") {
+        Some(status) if status != "0" => Err(anyhow::anyhow!(format!(
+            "health check failed with grpc-status: {status:?}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("truncated varint"))?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow::anyhow!("varint too long"))
+}
+
+/// The status of a length prefixed `HealthCheckResponse` message, `UNKNOWN` if it is absent
+fn parse_serving_status(body: &[u8]) -> Result<u64> {
+    let Some((&[compressed, len @ ..], rest)) = body.split_first_chunk::<5>() else {
+        return Err(anyhow::anyhow!("health check response without a message"));
+    };
+    if compressed != 0 {
+        return Err(anyhow::anyhow!("compressed health check response"));
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    let mut message = rest
+        .get(..len)
+        .ok_or_else(|| anyhow::anyhow!("truncated health check response"))?;
+
+    let mut status = 0;
+    while !message.is_empty() {
+        let key = get_varint(&mut message)?;
+        match key & 0x7 {
+            0 => {
+                let value = get_varint(&mut message)?;
+                if key >> 3 == 1 {
+                    status = value;
+                }
+            }
+            1 | 5 => {
+                let size = if key & 0x7 == 1 { 8 } else { 4 };
+                message = message
+                    .get(size..)
+                    .ok_or_else(|| anyhow::anyhow!("truncated health check response"))?;
+            }
+            2 => {
+                let size = get_varint(&mut message)? as usize;
+                message = message
+                    .get(size..)
+                    .ok_or_else(|| anyhow::anyhow!("truncated health check response"))?;
+            }
+            wire_type => {
+                return Err(anyhow::anyhow!(format!(
+                    "invalid wire type {wire_type} in health check response"
+                )))
+            }
+        }
+    }
+    Ok(status)
+}
+
+/// A backend whose health was flipped by its health check, see
+/// [super::LoadBalancer::subscribe_health_events]
+#[derive(Clone, Debug)]
+pub struct HealthEvent {
+    pub backend: Backend,
+    pub healthy: bool,
+    /// Why the check failed, for a backend becoming unhealthy
+    pub error: Option<String>,
+}
+
+/// Passive health checking settings, also known as outlier detection.
+///
+/// A backend which is reported to fail `consecutive_failures` times in a row within `window`
+/// is ejected for `base_ejection` multiplied by the number of times it has been ejected so far,
+/// capped at `max_ejection`.
+#[derive(Clone, Debug)]
+pub struct OutlierDetection {
+    /// The number of consecutive failures that ejects a backend
+    pub consecutive_failures: usize,
+    /// The failures should happen within this window to count as consecutive
+    pub window: Duration,
+    /// How long a backend is ejected the first time
+    pub base_ejection: Duration,
+    /// The maximum time a backend can be ejected for
+    pub max_ejection: Duration,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            window: Duration::from_secs(10),
+            base_ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(300),
+        }
     }
 }
 
@@ -116,54 +796,312 @@ struct HealthInner {
     /// If the health status is not flipped, the number of failed checks will be incremented
     /// by 1
     health_counter: usize,
+    /// The number of consecutive failures reported from live traffic
+    passive_failures: usize,
+    /// When the first of the current run of passive failures was reported
+    failure_window_start: Option<Instant>,
+    /// The backend is ejected by outlier detection until this instant
+    ejected_until: Option<Instant>,
+    /// The number of times the backend has been ejected, used to back off the ejection time
+    ejections: u32,
+    /// The health forced by an operator, taking precedence over the checks
+    manual: Option<bool>,
+    /// Taken out of rotation by an operator, whatever the health
+    drained: bool,
+    /// When the checks last flipped the health, or when the health was created
+    last_flip: Instant,
+    /// The number of checks which have failed in a row
+    consecutive_failures: usize,
+    /// Why the last failed check failed
+    last_error: Option<String>,
+    /// How long the last check took
+    check_latency: Option<Duration>,
+    /// The moving average of how long the checks take
+    check_latency_ewma: Option<Duration>,
 }
 
+impl HealthInner {
+    fn ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| now < until)
+    }
+
+    fn healthy(&self) -> bool {
+        self.manual.unwrap_or_else(|| {
+            // Only read the clock for an ejected backend, it is on the hot path of selections
+            self.healthy && (self.ejected_until.is_none() || !self.ejected(Instant::now()))
+        })
+    }
+}
+
+/// The health of a backend along with its history, see [Health::status]
+#[derive(Clone, Debug)]
+pub struct HealthStatus {
+    /// Whether the backend is healthy, like [Health::healthy], drained or not
+    pub healthy: bool,
+    /// Whether the backend is drained, like [Health::drained]
+    pub drained: bool,
+    /// When the checks last flipped the health, or when the backend was added
+    pub last_flip: Instant,
+    /// The number of checks which have failed in a row, `0` after a passing check
+    pub consecutive_failures: usize,
+    /// Why the last failed check failed, kept after the backend recovers
+    pub last_error: Option<String>,
+    /// How long the last check took, `None` until checked
+    pub check_latency: Option<Duration>,
+    /// The exponentially weighted moving average of how long the checks take, the last one
+    /// weighing [CHECK_LATENCY_WEIGHT]
+    pub check_latency_ewma: Option<Duration>,
+}
+
+/// The weight of the last check in [HealthStatus::check_latency_ewma]
+pub const CHECK_LATENCY_WEIGHT: f64 = 0.3;
+
 #[derive(Debug)]
 pub struct Health(ArcSwap<HealthInner>);
 
 impl Default for Health {
     fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+impl Health {
+    /// The health of a backend which has not been checked yet
+    pub fn new(healthy: bool) -> Self {
         Self(ArcSwap::new(Arc::new(HealthInner {
-            healthy: true,
+            healthy,
             health_counter: 0,
+            passive_failures: 0,
+            failure_window_start: None,
+            ejected_until: None,
+            ejections: 0,
+            manual: None,
+            drained: false,
+            last_flip: Instant::now(),
+            consecutive_failures: 0,
+            last_error: None,
+            check_latency: None,
+            check_latency_ewma: None,
         })))
     }
-}
 
-impl Health {
     pub fn healthy(&self) -> bool {
-        self.0.load().healthy
+        self.0.load().healthy()
+    }
+
+    /// Whether the backend can be selected, healthy and not drained
+    pub fn in_rotation(&self) -> bool {
+        let health = self.0.load();
+        !health.drained && health.healthy()
+    }
+
+    /// The health along with when it last flipped and why the checks failed, e.g. for a
+    /// status page.
+    pub fn status(&self) -> HealthStatus {
+        let health = self.0.load();
+        HealthStatus {
+            healthy: health.healthy(),
+            drained: health.drained,
+            last_flip: health.last_flip,
+            consecutive_failures: health.consecutive_failures,
+            last_error: health.last_error.clone(),
+            check_latency: health.check_latency,
+            check_latency_ewma: health.check_latency_ewma,
+        }
+    }
+
+    /// The health forced via [Health::set_manual], if any
+    pub fn manual(&self) -> Option<bool> {
+        self.0.load().manual
+    }
+
+    /// Replace the health with the one `update` makes out of it, along with the outcome of
+    /// the change, or keep it if `update` returns `None`.
+    ///
+    /// `update` is called again if the health is replaced meanwhile, e.g. by concurrent
+    /// reports, so that none of them is lost.
+    fn update<T: Default>(
+        &self,
+        mut update: impl FnMut(&HealthInner) -> Option<(HealthInner, T)>,
+    ) -> T {
+        let mut current = self.0.load();
+        loop {
+            let Some((new_health, outcome)) = update(&current) else {
+                return T::default();
+            };
+            let previous = self.0.compare_and_swap(&*current, Arc::new(new_health));
+            if Arc::ptr_eq(&previous, &current) {
+                return outcome;
+            }
+            current = previous;
+        }
+    }
+
+    /// Force the health regardless of the checks, or go back to the checks with `None`
+    pub fn set_manual(&self, healthy: Option<bool>) {
+        self.update(|health| {
+            if health.manual == healthy {
+                return None;
+            }
+            let mut new_health = health.clone();
+            new_health.manual = healthy;
+            Some((new_health, ()))
+        })
+    }
+
+    /// Whether the backend is taken out of rotation via [Health::set_drained]
+    pub fn drained(&self) -> bool {
+        self.0.load().drained
+    }
+
+    /// Take the backend out of rotation, or put it back, without changing its health
+    pub fn set_drained(&self, drained: bool) {
+        self.update(|health| {
+            if health.drained == drained {
+                return None;
+            }
+            let mut new_health = health.clone();
+            new_health.drained = drained;
+            Some((new_health, ()))
+        })
+    }
+
+    /// Whether the backend is currently ejected by outlier detection
+    pub fn ejected(&self) -> bool {
+        self.0.load().ejected(Instant::now())
+    }
+
+    // Returns true if the backend got ejected by this failure
+    pub fn observe_failure(&self, detection: &OutlierDetection) -> bool {
+        self.update(|health| {
+            // Read again on a retry, not to predate the health stored meanwhile
+            let now = Instant::now();
+            if health.ejected(now) {
+                // already out of rotation, nothing to count
+                return None;
+            }
+
+            let mut new_health = health.clone();
+            match new_health.failure_window_start {
+                Some(start) if now.duration_since(start) <= detection.window => {
+                    new_health.passive_failures += 1;
+                }
+                _ => {
+                    // the previous failures are too old to be consecutive, start a new window
+                    new_health.failure_window_start = Some(now);
+                    new_health.passive_failures = 1;
+                }
+            }
+
+            let mut ejected = false;
+            if new_health.passive_failures >= detection.consecutive_failures {
+                new_health.ejections = new_health.ejections.saturating_add(1);
+                let ejection = detection
+                    .base_ejection
+                    .saturating_mul(new_health.ejections)
+                    .min(detection.max_ejection);
+                new_health.ejected_until = Some(now + ejection);
+                new_health.passive_failures = 0;
+                new_health.failure_window_start = None;
+                ejected = true;
+            }
+            Some((new_health, ejected))
+        })
+    }
+
+    pub fn observe_success(&self, detection: &OutlierDetection) {
+        self.update(|health| {
+            let now = Instant::now();
+            // Forget about previous ejections once the backend behaved for `max_ejection`
+            let forgive = health
+                .ejected_until
+                .is_some_and(|until| now >= until + detection.max_ejection);
+            if health.passive_failures == 0 && !forgive {
+                return None;
+            }
+
+            let mut new_health = health.clone();
+            new_health.passive_failures = 0;
+            new_health.failure_window_start = None;
+            if forgive {
+                new_health.ejected_until = None;
+                new_health.ejections = 0;
+            }
+            Some((new_health, ()))
+        })
     }
 
     // Returns true if the health status is flipped
     pub fn observe_health(&self, healthy: bool, flip_threshold: usize) -> bool {
-        let health = self.0.load();
-        let mut flipped = false;
-        if health.healthy != healthy {
-            // opposite health observed, ready to increase the counter
-            // clone the inner
-            let mut new_health = (**health).clone();
-            new_health.health_counter += 1;
-            if new_health.health_counter >= flip_threshold {
-                new_health.healthy = healthy;
+        self.observe(healthy, None, flip_threshold)
+    }
+
+    /// Like [Health::observe_health] with the outcome of a check, keeping why it failed.
+    pub fn observe_check(&self, result: &Result<()>, flip_threshold: usize) -> bool {
+        let error = result.as_ref().err().map(|e| e.to_string());
+        self.observe(result.is_ok(), error, flip_threshold)
+    }
+
+    /// Record how long a check took.
+    pub fn observe_latency(&self, latency: Duration) {
+        self.update(|health| {
+            let mut new_health = health.clone();
+            new_health.check_latency = Some(latency);
+            new_health.check_latency_ewma = Some(match health.check_latency_ewma {
+                Some(average) => {
+                    average.mul_f64(1.0 - CHECK_LATENCY_WEIGHT)
+                        + latency.mul_f64(CHECK_LATENCY_WEIGHT)
+                }
+                None => latency,
+            });
+            Some((new_health, ()))
+        })
+    }
+
+    fn observe(&self, healthy: bool, error: Option<String>, flip_threshold: usize) -> bool {
+        self.update(|health| {
+            let consecutive_failures = if healthy {
+                0
+            } else {
+                health.consecutive_failures + 1
+            };
+            if health.healthy == healthy
+                && health.health_counter == 0
+                && health.consecutive_failures == consecutive_failures
+            {
+                // nothing to record
+                return None;
+            }
+
+            let mut new_health = health.clone();
+            new_health.consecutive_failures = consecutive_failures;
+            if error.is_some() {
+                new_health.last_error = error.clone();
+            }
+            let mut flipped = false;
+            if health.healthy != healthy {
+                // opposite health observed, ready to increase the counter
+                new_health.health_counter += 1;
+                if new_health.health_counter >= flip_threshold {
+                    new_health.healthy = healthy;
+                    new_health.health_counter = 0;
+                    new_health.last_flip = Instant::now();
+                    flipped = true;
+                }
+            } else {
+                // observing the same health as the current state.
+                // reset the counter because it is no longer consecutive
                 new_health.health_counter = 0;
-                flipped = true;
             }
-            self.0.store(Arc::new(new_health));
-        } else if health.health_counter > 0 {
-            // observing the same health as the current state.
-            // reset the counter, if it is non-zero, because it is no longer consecutive
-            let mut new_health = (**health).clone();
-            new_health.health_counter = 0;
-            self.0.store(Arc::new(new_health));
-        }
-        flipped
+            Some((new_health, flipped))
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::load_balancer::{strategy::RoundRobin, LoadBalancer};
     use wiremock::http::HeaderName;
     use wiremock::matchers::{header, method, path};
     use wiremock::{Mock, MockServer, ResponseTemplate};
@@ -237,4 +1175,598 @@ mod tests {
 
         assert!(result.is_ok(), "failed to check health: {:?}", result);
     }
+
+    #[tokio::test]
+    async fn test_http_health_check_computed_path() {
+        let mock_server = MockServer::start().await;
+        let backend = Backend::new(mock_server.uri());
+        let mut health_check = HttpHealthCheck::new();
+        let version = 2;
+        health_check.set_path(format!("/v{version}/health"));
+
+        Mock::given(method("GET"))
+            .and(path("/v2/health"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        assert!(health_check.check(&backend).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_with_client() {
+        let server = MockServer::start().await;
+        let backend = Backend::new(server.uri());
+        Mock::given(header("user-agent", "yapf-health"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        assert!(HttpHealthCheck::new().check(&backend).await.is_err());
+        let client = reqwest::Client::builder()
+            .user_agent("yapf-health")
+            .build()
+            .unwrap();
+        let mut health_check = HttpHealthCheck::with_client(client);
+        // Doesn't replace the client
+        health_check.set_timeout(Duration::from_secs(1));
+        assert!(health_check.check(&backend).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_success_fn() {
+        let server = MockServer::start().await;
+        let backend = Backend::new(server.uri());
+        Mock::given(path("/ready"))
+            .respond_with(ResponseTemplate::new(503).insert_header("x-ready", "true"))
+            .mount(&server)
+            .await;
+        Mock::given(path("/"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let mut health_check = HttpHealthCheck::new();
+        health_check.set_success_fn(Box::new(|response| {
+            response
+                .headers()
+                .get("x-ready")
+                .is_some_and(|v| v == "true")
+        }));
+        assert!(health_check.check(&backend).await.is_err());
+        health_check.set_path("/ready");
+        assert!(health_check.check(&backend).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_fresh_connections() {
+        use hyper::{server::conn::http1, service::service_fn};
+        use hyper_util::rt::TokioIo;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = Backend::new(format!("http://{}", listener.local_addr().unwrap()));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::Relaxed);
+                let on_request = service_fn(|_| async {
+                    Ok::<_, std::convert::Infallible>(hyper::Response::new(Full::new(Bytes::new())))
+                });
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), on_request),
+                );
+            }
+        });
+
+        let mut health_check = HttpHealthCheck::new();
+        for _ in 0..3 {
+            health_check.check(&backend).await.unwrap();
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+        health_check.set_fresh_connections(true);
+        for _ in 0..3 {
+            health_check.check(&backend).await.unwrap();
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_auth() {
+        use wiremock::matchers::{basic_auth, bearer_token};
+
+        let server = MockServer::start().await;
+        let backend = Backend::new(server.uri());
+        Mock::given(path("/basic"))
+            .and(basic_auth("user", "secret"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(path("/bearer"))
+            .and(bearer_token("token"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(wiremock::matchers::any())
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        let mut health_check = HttpHealthCheck::new();
+        health_check.set_path("/basic");
+        assert!(health_check.check(&backend).await.is_err());
+        health_check.set_basic_auth("user", Some("wrong"));
+        assert!(health_check.check(&backend).await.is_err());
+        health_check.set_basic_auth("user", Some("secret"));
+        assert!(health_check.check(&backend).await.is_ok());
+
+        health_check.set_path("/bearer");
+        assert!(health_check.check(&backend).await.is_err());
+        health_check.set_bearer_token("token");
+        assert!(health_check.check(&backend).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_expected_header() {
+        let server = MockServer::start().await;
+        let backend = Backend::new(server.uri().to_string());
+        let mut health_check = HttpHealthCheck::new();
+        health_check.set_expected_header(
+            HeaderName::from_static("x-ready"),
+            HeaderValue::from_static("true"),
+        );
+
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        let result = health_check.check(&backend).await;
+        assert!(result.is_err());
+
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).insert_header("X-Ready", "true"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        let result = health_check.check(&backend).await;
+        assert!(result.is_ok(), "failed to check health: {:?}", result);
+
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503).insert_header("X-Ready", "true"))
+            .mount(&server)
+            .await;
+        let result = health_check.check(&backend).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_addr() {
+        let traffic = MockServer::start().await;
+        let probe = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&traffic)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/ready"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&probe)
+            .await;
+        let backend =
+            Backend::new(traffic.uri()).with_health_check_addr(format!("{}/ready", probe.uri()));
+
+        let result = HttpHealthCheck::new().check(&backend).await;
+        assert!(result.is_ok(), "failed to check health: {:?}", result);
+
+        // A malformed probe address fails the check
+        let backend = Backend::new(traffic.uri()).with_health_check_addr("not a url".to_string());
+        let error = HttpHealthCheck::new().check(&backend).await.unwrap_err();
+        assert!(error.to_string().contains("invalid health check address"));
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_body() {
+        let backend_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/error"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"error":"warming up"}"#))
+            .mount(&backend_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/result"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"result":"0x1"}"#))
+            .mount(&backend_server)
+            .await;
+        let error = Backend::new(format!("{}/error", backend_server.uri()));
+        let result = Backend::new(format!("{}/result", backend_server.uri()));
+
+        let mut health_check = HttpHealthCheck::new();
+        health_check.set_expected_body_substring(r#""result""#.to_string());
+        assert!(health_check.check(&result).await.is_ok());
+        assert!(health_check.check(&error).await.is_err());
+
+        health_check.set_body_matcher(Box::new(|body| !body.starts_with(b"{\"error\"")));
+        assert!(health_check.check(&result).await.is_ok());
+        assert!(health_check.check(&error).await.is_err());
+
+        // Too large to be matched
+        health_check.set_max_body_size(8);
+        assert!(health_check.check(&result).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_timeout() {
+        let backend_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .mount(&backend_server)
+            .await;
+
+        let mut health_check = HttpHealthCheck::new();
+        health_check.set_timeout(Duration::from_millis(200));
+        health_check.set_pool_idle_timeout(Duration::from_secs(1));
+        let start = Instant::now();
+        let result = health_check
+            .check(&Backend::new(backend_server.uri()))
+            .await;
+        assert!(result.is_err());
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_thresholds() {
+        let mock_server = MockServer::start().await;
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![Backend::new(mock_server.uri())]);
+        let mut health_check = HttpHealthCheck::new();
+        health_check.set_unhealthy_threshold(3);
+        health_check.set_healthy_threshold(2);
+        lb.set_health_check(Arc::new(health_check));
+
+        let failing = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount_as_scoped(&mock_server)
+            .await;
+        for _ in 0..2 {
+            lb.run_health_check().await;
+            assert!(lb.next().is_some());
+        }
+        lb.run_health_check().await;
+        assert!(lb.next().is_none());
+        drop(failing);
+
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        lb.run_health_check().await;
+        assert!(lb.next().is_none());
+        lb.run_health_check().await;
+        assert!(lb.next().is_some());
+    }
+
+    /// A self-signed certificate for `127.0.0.1`, with its key
+    fn self_signed_cert() -> (
+        openssl::x509::X509,
+        openssl::pkey::PKey<openssl::pkey::Private>,
+    ) {
+        use openssl::{asn1::Asn1Time, bn::BigNum, ec, hash::MessageDigest, nid::Nid, x509};
+
+        let group = ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = openssl::pkey::PKey::from_ec_key(ec::EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "127.0.0.1").unwrap();
+        let name = name.build();
+
+        let mut cert = x509::X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = x509::extension::SubjectAlternativeName::new()
+            .ip("127.0.0.1")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build(), key)
+    }
+
+    /// An HTTPS server answering `200` with `cert`
+    async fn start_tls_server(
+        cert: &openssl::x509::X509,
+        key: &openssl::pkey::PKey<openssl::pkey::Private>,
+    ) -> std::net::SocketAddr {
+        use hyper::{server::conn::http1, service::service_fn};
+        use hyper_util::rt::TokioIo;
+        use openssl::ssl::{Ssl, SslAcceptor, SslMethod};
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(cert).unwrap();
+        acceptor.set_private_key(key).unwrap();
+        let acceptor = acceptor.build();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let ssl = Ssl::new(acceptor.context()).unwrap();
+                let mut stream = tokio_openssl::SslStream::new(ssl, stream).unwrap();
+                // The handshake fails when the client doesn't trust the certificate
+                if std::pin::Pin::new(&mut stream).accept().await.is_err() {
+                    continue;
+                }
+                let on_request = service_fn(|_| async {
+                    Ok::<_, std::convert::Infallible>(hyper::Response::new(Full::new(Bytes::new())))
+                });
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), on_request),
+                );
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_tls_roots() {
+        let (cert, key) = self_signed_cert();
+        let addr = start_tls_server(&cert, &key).await;
+        let backend = Backend::new(format!("https://{addr}"));
+
+        let mut health_check = HttpHealthCheck::new();
+        assert!(health_check.check(&backend).await.is_err());
+        let root = Certificate::from_pem(&cert.to_pem().unwrap()).unwrap();
+        health_check.set_tls_roots(vec![root]);
+        assert!(health_check.check(&backend).await.is_ok());
+
+        let mut health_check = HttpHealthCheck::new();
+        health_check.set_danger_accept_invalid_certs(true);
+        assert!(health_check.check(&backend).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_health_check() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let health_check = TcpHealthCheck::new();
+        for backend in [addr.to_string(), format!("http://{addr}/path")] {
+            let result = health_check.check(&Backend::new(backend)).await;
+            assert!(result.is_ok(), "failed to check health: {:?}", result);
+        }
+
+        // Nothing listens on the port anymore
+        drop(listener);
+        let result = health_check.check(&Backend::new(addr.to_string())).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_health_check_flips_health() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![Backend::new(addr.to_string())]);
+        lb.set_health_check(Arc::new(TcpHealthCheck::new()));
+
+        lb.run_health_check().await;
+        assert!(lb.next().is_some());
+        drop(listener);
+        lb.run_health_check().await;
+        assert!(lb.next().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_health_check_thresholds() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![Backend::new(addr.to_string())]);
+        let mut health_check = TcpHealthCheck::new();
+        health_check.set_unhealthy_threshold(2);
+        health_check.set_healthy_threshold(3);
+        assert_eq!(health_check.health_threshold(true), 3);
+        lb.set_health_check(Arc::new(health_check));
+
+        drop(listener);
+        lb.run_health_check().await;
+        assert!(lb.next().is_some());
+        lb.run_health_check().await;
+        assert!(lb.next().is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_uds_health_check() {
+        use hyper::{server::conn::http1, service::service_fn};
+        use hyper_util::rt::TokioIo;
+
+        let socket = std::env::temp_dir().join(format!("yapf-health-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let on_request = service_fn(
+                    |request: hyper::Request<hyper::body::Incoming>| async move {
+                        let status = if request.uri().path() == "/health" {
+                            200
+                        } else {
+                            503
+                        };
+                        let mut response = hyper::Response::new(Full::new(Bytes::new()));
+                        *response.status_mut() = hyper::StatusCode::from_u16(status).unwrap();
+                        Ok::<_, std::convert::Infallible>(response)
+                    },
+                );
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), on_request),
+                );
+            }
+        });
+
+        let backend = Backend::new(format!("unix://{}", socket.display()));
+        let mut health_check = UdsHealthCheck::new();
+        assert!(health_check.check(&backend).await.is_err());
+        health_check.set_path("/health");
+        assert!(health_check.check(&backend).await.is_ok());
+
+        let missing = Backend::new(format!("unix://{}.missing", socket.display()));
+        assert!(health_check.check(&missing).await.is_err());
+        assert!(health_check
+            .check(&Backend::new("http://127.0.0.1:1".to_string()))
+            .await
+            .is_err());
+        std::fs::remove_file(&socket).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_uds_health_check_thresholds() {
+        let mut health_check = UdsHealthCheck::new();
+        assert_eq!(health_check.health_threshold(true), 1);
+        assert_eq!(health_check.health_threshold(false), 1);
+        health_check.set_healthy_threshold(2);
+        health_check.set_unhealthy_threshold(3);
+        assert_eq!(health_check.health_threshold(true), 2);
+        assert_eq!(health_check.health_threshold(false), 3);
+    }
+
+    /// A gRPC server answering the health of the services `serving`, `not-serving` and
+    /// `unknown`, and `NOT_FOUND` for any other
+    async fn start_grpc_server() -> std::net::SocketAddr {
+        use hyper::{server::conn::http2, service::service_fn};
+        use hyper_util::rt::TokioIo;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let on_request = service_fn(
+                    |request: hyper::Request<hyper::body::Incoming>| async move {
+                        assert_eq!(request.uri().path(), "/grpc.health.v1.Health/Check");
+                        assert_eq!(request.headers()["content-type"], "application/grpc");
+                        let body = request.into_body().collect().await.unwrap().to_bytes();
+                        // The service name follows the prefix, the field key and its length
+                        let service = body.get(7..).unwrap_or_default();
+
+                        let status = match service {
+                            b"serving" => Some(SERVING as u8),
+                            b"not-serving" => Some(2),
+                            b"unknown" => Some(3),
+                            _ => None,
+                        };
+                        let mut response =
+                            hyper::Response::builder().header("content-type", "application/grpc");
+                        let (message, trailers) = match status {
+                            Some(status) => {
+                                let mut trailers = HeaderMap::new();
+                                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                                (vec![0, 0, 0, 0, 2, 0x08, status], Some(trailers))
+                            }
+                            // A trailers-only response
+                            None => {
+                                response = response.header("grpc-status", "5");
+                                (Vec::new(), None)
+                            }
+                        };
+                        let body = Full::new(Bytes::from(message))
+                            .with_trailers(async { trailers.map(Ok) });
+                        Ok::<_, std::convert::Infallible>(response.body(body).unwrap())
+                    },
+                );
+                tokio::spawn(
+                    http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), on_request),
+                );
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_grpc_health_check() {
+        let addr = start_grpc_server().await;
+        let backend = Backend::new(format!("http://{addr}"));
+
+        let mut health_check = GrpcHealthCheck::new();
+        health_check.set_service("serving".to_string());
+        let result = health_check.check(&backend).await;
+        assert!(result.is_ok(), "failed to check health: {:?}", result);
+
+        for service in ["not-serving", "unknown", "missing"] {
+            health_check.set_service(service.to_string());
+            let result = health_check.check(&backend).await;
+            assert!(result.is_err(), "{service}");
+        }
+    }
+
+    #[test]
+    fn test_grpc_health_check_thresholds() {
+        let mut health_check = GrpcHealthCheck::new();
+        assert_eq!(health_check.health_threshold(true), 1);
+        assert_eq!(health_check.health_threshold(false), 1);
+        health_check.set_healthy_threshold(2);
+        health_check.set_unhealthy_threshold(3);
+        assert_eq!(health_check.health_threshold(true), 2);
+        assert_eq!(health_check.health_threshold(false), 3);
+    }
+
+    #[test]
+    fn test_parse_serving_status() {
+        assert_eq!(parse_serving_status(&[0, 0, 0, 0, 2, 0x08, 1]).unwrap(), 1);
+        // An unknown field before the status, and a message without any field
+        let body = [0, 0, 0, 0, 6, 0x12, 2, b'o', b'k', 0x08, 2];
+        assert_eq!(parse_serving_status(&body).unwrap(), 2);
+        assert_eq!(parse_serving_status(&[0, 0, 0, 0, 0]).unwrap(), 0);
+
+        assert!(parse_serving_status(&[]).is_err());
+        assert!(parse_serving_status(&[0, 0, 0, 0, 2, 0x08]).is_err());
+        assert!(parse_serving_status(&[1, 0, 0, 0, 2, 0x08, 1]).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_observations() {
+        let health = Arc::new(Health::new(true));
+        let detection = OutlierDetection {
+            consecutive_failures: 1000,
+            window: Duration::from_secs(60),
+            // Never out of rotation, so that every failure counts
+            base_ejection: Duration::ZERO,
+            ..Default::default()
+        };
+        // 8000 failures, none lost to another thread, eject the backend exactly 8 times
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let health = health.clone();
+                let detection = detection.clone();
+                std::thread::spawn(move || {
+                    let mut ejected = 0;
+                    for _ in 0..1000 {
+                        ejected += usize::from(health.observe_failure(&detection));
+                        health.observe_latency(Duration::from_millis(1));
+                    }
+                    ejected
+                })
+            })
+            .collect();
+        let ejected: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(ejected, 8);
+        assert_eq!(health.0.load().ejections, 8);
+        assert_eq!(health.0.load().passive_failures, 0);
+    }
 }
@@ -1,30 +1,89 @@
-use std::time::Duration;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
-    sync::Arc,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    hash::{BuildHasherDefault, Hash, Hasher},
+    sync::{Arc, Mutex},
 };
 
 use arc_swap::ArcSwap;
 use http::uri::InvalidUri;
 use hyper::Uri;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::task::JoinSet;
 
 mod background;
+pub mod discovery;
 pub mod helthcheck;
 pub mod strategy;
 
-use helthcheck::{Health, HealthCheck};
-use strategy::Strategy;
+use discovery::{BackendProvider, DnsBackends};
+use helthcheck::{Health, HealthCheck, HealthEvent, HealthStatus, OutlierDetection};
+use strategy::{RingNode, Strategy, WeightValidation};
 
-#[derive(Clone, Hash, PartialEq, Debug)]
+/// The weight of a backend unless configured otherwise
+const DEFAULT_WEIGHT: u16 = 100;
+
+/// A backend, identified by everything but its [Backend::metadata], [Backend::tags] and
+/// [Backend::max_connections]
+#[derive(Clone, Debug)]
 pub struct Backend {
     pub addr: String,
     pub weight: u16,
+    /// The priority tier of the backend, `0` being the highest.
+    ///
+    /// Lower priority tiers only receive traffic when no backend of the higher tiers is healthy
+    /// and selectable.
+    pub priority: u8,
+    /// Where the health check probes the backend, when it is not its traffic address, e.g.
+    /// `http://10.0.0.1:9090` for a backend serving traffic on `https://10.0.0.1:8443`.
+    pub health_check_addr: Option<String>,
+    /// Key/value tags for the strategies and filters, e.g. its `region` or `version`.
+    ///
+    /// Changing them doesn't make another backend, which keeps its health.
+    pub metadata: HashMap<String, String>,
+    /// Labels to select a subset of the backends with [LoadBalancer::next_matching], e.g.
+    /// `zone=a` or `canary`.
+    pub tags: HashSet<String>,
+    /// The most connections of [LoadBalancer::next_connection] open at once to the backend,
+    /// which isn't selected while at its cap. Unlimited by default.
+    ///
+    /// Changing it doesn't make another backend, which keeps its health and connections.
+    pub max_connections: Option<usize>,
+}
+
+impl Hash for Backend {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr.hash(state);
+        self.weight.hash(state);
+        self.priority.hash(state);
+        self.health_check_addr.hash(state);
+    }
+}
+
+impl PartialEq for Backend {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr == other.addr
+            && self.weight == other.weight
+            && self.priority == other.priority
+            && self.health_check_addr == other.health_check_addr
+    }
 }
 
 impl Backend {
     pub fn new(addr: String) -> Self {
-        Self { addr, weight: 100 }
+        Self {
+            addr,
+            weight: DEFAULT_WEIGHT,
+            priority: 0,
+            health_check_addr: None,
+            metadata: HashMap::new(),
+            tags: HashSet::new(),
+            max_connections: None,
+        }
     }
 
     pub fn with_weight(mut self, weight: u16) -> Self {
@@ -36,6 +95,60 @@ impl Backend {
         self.weight = weight;
     }
 
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
+    pub fn with_health_check_addr(mut self, addr: String) -> Self {
+        self.health_check_addr = Some(addr);
+        self
+    }
+
+    pub fn set_health_check_addr(&mut self, addr: String) {
+        self.health_check_addr = Some(addr);
+    }
+
+    pub fn with_metadata(mut self, metadata: HashMap<String, String>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn set_metadata(&mut self, metadata: HashMap<String, String>) {
+        self.metadata = metadata;
+    }
+
+    pub fn with_tags(mut self, tags: HashSet<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn set_tags(&mut self, tags: HashSet<String>) {
+        self.tags = tags;
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.contains(tag)
+    }
+
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn set_max_connections(&mut self, max: Option<usize>) {
+        self.max_connections = max;
+    }
+
+    /// The address probed by the health check, [Backend::health_check_addr] if set
+    pub fn health_check_target(&self) -> &str {
+        self.health_check_addr.as_deref().unwrap_or(&self.addr)
+    }
+
     pub fn hash_key(&self) -> u64 {
         let mut hasher = DefaultHasher::new();
         self.hash(&mut hasher);
@@ -43,86 +156,715 @@ impl Backend {
     }
 }
 
+/// A backend, as returned by the selection, along with its health
 #[derive(Debug)]
-struct Backends {
-    health_check: Option<Arc<dyn HealthCheck + Send + Sync + 'static>>,
+struct BackendHealth {
+    backend: Arc<Backend>,
+    health: Arc<Health>,
+    /// The number of times the backend was selected
+    selections: Arc<AtomicU64>,
+    /// The connections of [LoadBalancer::next_connection] currently open
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl BackendHealth {
+    /// Whether the backend is under its [Backend::max_connections], counting a new connection
+    /// to it if `connect`
+    fn admit(&self, connect: bool) -> bool {
+        let max = self.backend.max_connections.unwrap_or(usize::MAX);
+        if !connect {
+            return self.in_flight.load(Ordering::Relaxed) < max;
+        }
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .is_ok()
+    }
+
+    fn select(&self, connect: bool) -> Selected {
+        self.selections.fetch_add(1, Ordering::Relaxed);
+        Selected {
+            backend: self.backend.clone(),
+            in_flight: connect.then(|| self.in_flight.clone()),
+        }
+    }
+}
+
+/// A selected backend, with its counter of connections if one was counted
+struct Selected {
+    backend: Arc<Backend>,
+    in_flight: Option<Arc<AtomicUsize>>,
+}
+
+/// A backend selected by [LoadBalancer::next_connection], counted against its
+/// [Backend::max_connections] until dropped
+#[derive(Debug)]
+pub struct BackendConnection {
+    backend: Arc<Backend>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl BackendConnection {
+    pub fn backend(&self) -> &Arc<Backend> {
+        &self.backend
+    }
+}
+
+impl Deref for BackendConnection {
+    type Target = Backend;
+
+    fn deref(&self) -> &Backend {
+        &self.backend
+    }
+}
+
+impl Drop for BackendConnection {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A snapshot of the backends, replaced as a whole when they are updated
+#[derive(Debug)]
+struct BackendSet {
     backends: Vec<Backend>,
-    health: ArcSwap<HashMap<u64, Health>>,
+    health: HealthTable,
 }
 
-impl Backends {
-    fn new(backends: Vec<Backend>) -> Self {
-        let health: HashMap<u64, Health> = backends
+/// The backends by [Backend::hash_key]
+type HealthTable = HashMap<u64, BackendHealth, BuildHasherDefault<KeyHasher>>;
+
+/// Hashes the keys of a [HealthTable], which already are hashes, as is
+#[derive(Default)]
+struct KeyHasher(u64);
+
+impl Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(byte);
+        }
+    }
+
+    fn write_u64(&mut self, key: u64) {
+        self.0 = key;
+    }
+}
+
+impl BackendSet {
+    /// Build the set of `backends`, keeping the health and the selection counts of the ones
+    /// already in `previous`.
+    ///
+    /// The backends are matched by address, so the health survives e.g. a change of weight.
+    fn new(backends: Vec<Backend>, previous: Option<&BackendSet>, initial_health: bool) -> Self {
+        let previous: HashMap<&str, &BackendHealth> = previous
+            .map(|previous| {
+                previous
+                    .health
+                    .values()
+                    .map(|entry| (entry.backend.addr.as_str(), entry))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let health = backends
             .iter()
-            .map(|b| (b.hash_key(), Health::default()))
+            .map(|backend| {
+                let key = backend.hash_key();
+                let (health, selections, in_flight) = match previous.get(backend.addr.as_str()) {
+                    Some(previous) => (
+                        previous.health.clone(),
+                        previous.selections.clone(),
+                        previous.in_flight.clone(),
+                    ),
+                    None => (
+                        Arc::new(Health::new(initial_health)),
+                        Arc::default(),
+                        Arc::default(),
+                    ),
+                };
+                let backend = Arc::new(backend.clone());
+                let entry = BackendHealth {
+                    backend,
+                    health,
+                    selections,
+                    in_flight,
+                };
+                (key, entry)
+            })
             .collect();
 
+        Self { backends, health }
+    }
+
+    /// The entry of the backend, if it is part of the set, healthy and not drained
+    fn healthy(&self, backend: &Backend) -> Option<&BackendHealth> {
+        self.health
+            .get(&backend.hash_key())
+            .filter(|entry| entry.health.in_rotation())
+    }
+}
+
+/// How many [HealthEvent]s a subscriber can lag behind before missing some
+const HEALTH_EVENTS_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+struct Backends {
+    health_check: Option<Arc<dyn HealthCheck + Send + Sync + 'static>>,
+    /// The health checks of the backends, by hash key, which aren't checked like the others
+    health_check_overrides: HashMap<u64, Arc<dyn HealthCheck + Send + Sync + 'static>>,
+    set: ArcSwap<BackendSet>,
+    outlier_detection: Option<OutlierDetection>,
+    /// Whether the health checks clear the manual health overrides
+    checks_clear_overrides: bool,
+    /// The health of the backends until they are checked
+    initial_health: bool,
+    /// How many backends are checked at once
+    max_concurrent_checks: usize,
+    /// The flips of the health by the checks
+    events: broadcast::Sender<HealthEvent>,
+}
+
+impl Backends {
+    fn new(backends: Vec<Backend>, initial_health: bool) -> Self {
+        let set = BackendSet::new(backends, None, initial_health);
         Self {
-            backends,
             health_check: None,
-            health: ArcSwap::new(Arc::new(health)),
+            health_check_overrides: HashMap::new(),
+            set: ArcSwap::new(Arc::new(set)),
+            outlier_detection: None,
+            checks_clear_overrides: false,
+            initial_health,
+            max_concurrent_checks: 1,
+            events: broadcast::channel(HEALTH_EVENTS_CAPACITY).0,
         }
     }
 
+    /// Replace the backends, keeping the health of the ones which remain
+    fn update(&self, backends: Vec<Backend>) -> Arc<BackendSet> {
+        let previous = self.set.load();
+        let set = Arc::new(BackendSet::new(
+            backends,
+            Some(&previous),
+            self.initial_health,
+        ));
+        self.set.store(set.clone());
+        set
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.set.load().backends.len()
+    }
+
     fn set_health_check(&mut self, health_check: Arc<dyn HealthCheck + Send + Sync + 'static>) {
         self.health_check = Some(health_check);
     }
 
-    async fn run_health_check(&self) {
-        let Some(health_check) = self.health_check.as_ref() else {
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.outlier_detection = Some(outlier_detection);
+    }
+
+    fn report(&self, backend: &Backend, success: bool) {
+        let Some(detection) = self.outlier_detection.as_ref() else {
             return;
         };
 
-        // TODO: Do we want to make this parallel?
-        for backend in &self.backends {
-            Self::check_and_report(backend, health_check, &self.health.load()).await;
+        if let Some(entry) = self.set.load().health.get(&backend.hash_key()) {
+            if success {
+                entry.health.observe_success(detection);
+            } else if entry.health.observe_failure(detection) {
+                tracing::warn!(
+                    backend.addr = %backend.addr,
+                    "backend ejected after consecutive failures"
+                );
+            }
+        }
+    }
+
+    /// Check every backend, returning the outcomes in the order of the backends
+    async fn run_health_check(&self) -> Vec<(Backend, anyhow::Result<()>)> {
+        let set = self.set.load_full();
+        let permits = Arc::new(Semaphore::new(self.max_concurrent_checks));
+        let mut checks = JoinSet::new();
+        // The backend of each check, to report a panicked one
+        let mut checked = HashMap::new();
+        for (index, backend) in set.backends.clone().into_iter().enumerate() {
+            let health_check = self
+                .health_check_overrides
+                .get(&backend.hash_key())
+                .or(self.health_check.as_ref());
+            let Some(health_check) = health_check.cloned() else {
+                continue;
+            };
+            // Wait for a check to finish before starting another one over the limit
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let set = set.clone();
+            let clear_override = self.checks_clear_overrides;
+            let events = self.events.clone();
+            let target = backend.clone();
+            let task = checks.spawn(async move {
+                let result = Self::check_and_report(
+                    &backend,
+                    &health_check,
+                    &set.health,
+                    clear_override,
+                    &events,
+                )
+                .await;
+                drop(permit);
+                (index, backend, result)
+            });
+            checked.insert(task.id(), (index, target));
+        }
+        let mut results = Vec::with_capacity(set.backends.len());
+        while let Some(outcome) = checks.join_next_with_id().await {
+            match outcome {
+                Ok((_, result)) => results.push(result),
+                Err(e) => {
+                    let Some((index, backend)) = checked.remove(&e.id()) else {
+                        continue;
+                    };
+                    tracing::error!(
+                        backend.addr = %backend.addr,
+                        error = %e,
+                        "health check panicked"
+                    );
+                    let error = anyhow::anyhow!("health check panicked: {e}");
+                    results.push((index, backend, Err(error)));
+                }
+            }
         }
+        results.sort_by_key(|(index, _, _)| *index);
+        results
+            .into_iter()
+            .map(|(_, backend, result)| (backend, result))
+            .collect()
     }
 
     async fn check_and_report(
         backend: &Backend,
         health_check: &Arc<dyn HealthCheck + Send + Sync + 'static>,
-        health_table: &HashMap<u64, Health>,
-    ) {
-        let failed = health_check.check(backend).await.err();
-        if let Some(health) = health_table.get(&backend.hash_key()) {
-            let flipped = health.observe_health(
-                failed.is_none(),
-                health_check.health_threshold(failed.is_none()),
-            );
+        health_table: &HealthTable,
+        clear_override: bool,
+        events: &broadcast::Sender<HealthEvent>,
+    ) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let result = health_check.check(backend).await;
+        let latency = start.elapsed();
+        if let Some(BackendHealth { health, .. }) = health_table.get(&backend.hash_key()) {
+            health.observe_latency(latency);
+            if clear_override && health.manual().is_some() {
+                tracing::info!(
+                    backend.addr = %backend.addr,
+                    "health override cleared by health check"
+                );
+                health.set_manual(None);
+            }
+            let flipped =
+                health.observe_check(&result, health_check.health_threshold(result.is_ok()));
             if flipped {
-                if let Some(e) = failed {
-                    println!("{backend:?} becomes unhealthy, {e}");
-                } else {
-                    println!("{backend:?} becomes healthy");
+                match &result {
+                    Err(e) => {
+                        tracing::warn!(
+                            backend.addr = %backend.addr,
+                            error = %e,
+                            "backend becomes unhealthy"
+                        )
+                    }
+                    Ok(()) => {
+                        tracing::info!(backend.addr = %backend.addr, "backend becomes healthy")
+                    }
                 }
+                // Nobody may be listening
+                let _ = events.send(HealthEvent {
+                    backend: backend.clone(),
+                    healthy: result.is_ok(),
+                    error: result.as_ref().err().map(|e| e.to_string()),
+                });
             }
         }
+        result
     }
 
+    fn set_manual_health(&self, backend: &Backend, healthy: Option<bool>) {
+        if let Some(entry) = self.set.load().health.get(&backend.hash_key()) {
+            entry.health.set_manual(healthy);
+        }
+    }
+
+    fn set_drained(&self, backend: &Backend, drained: bool) {
+        if let Some(entry) = self.set.load().health.get(&backend.hash_key()) {
+            entry.health.set_drained(drained);
+        }
+    }
+
+    /// The backends whose health is `healthy`, in the order they were given
+    fn with_health(&self, healthy: bool) -> Vec<Backend> {
+        let set = self.set.load();
+        set.backends
+            .iter()
+            .filter(|backend| set.healthy(backend).is_some() == healthy)
+            .cloned()
+            .collect()
+    }
+
+    #[cfg(test)]
     fn is_healthy(&self, backend: &Backend) -> bool {
-        self.health
-            .load()
-            .get(&backend.hash_key())
-            .map_or(self.health_check.is_none(), |h| h.healthy())
+        self.set.load().healthy(backend).is_some()
+    }
+}
+
+/// Parse a backend address, taking its weight out of the `weight` query parameter
+fn parse_weighted(addr: &str) -> anyhow::Result<Backend> {
+    let uri = addr.parse::<Uri>()?;
+    let Some(query) = uri.query() else {
+        return Ok(Backend::new(uri.to_string()));
+    };
+
+    let mut weight = None;
+    let params: Vec<&str> = query
+        .split('&')
+        .filter(|param| match param.strip_prefix("weight=") {
+            Some(value) => {
+                weight = Some(value);
+                false
+            }
+            None => !param.is_empty(),
+        })
+        .collect();
+    let weight = match weight {
+        Some(weight) => weight
+            .parse::<u16>()
+            .map_err(|e| anyhow::anyhow!("invalid weight {weight:?} in {addr}: {e}"))?,
+        None => DEFAULT_WEIGHT,
+    };
+
+    let mut path_and_query = uri.path().to_string();
+    if !params.is_empty() {
+        path_and_query = format!("{path_and_query}?{}", params.join("&"));
+    }
+    let mut parts = uri.into_parts();
+    parts.path_and_query = Some(path_and_query.parse()?);
+    Ok(Backend::new(Uri::from_parts(parts)?.to_string()).with_weight(weight))
+}
+
+/// Builds a strategy registered via [LoadBalancer::add_strategy]
+type StrategyBuilder = fn(&[Backend]) -> Box<dyn Strategy + Send + Sync>;
+
+fn build_strategy<S>(backends: &[Backend]) -> Box<dyn Strategy + Send + Sync>
+where
+    S: Strategy + Send + Sync + 'static,
+{
+    Box::new(S::build(backends))
+}
+
+/// The backends sharing the same priority, with their own strategy
+struct Tier<T> {
+    backends: Vec<Backend>,
+    /// The health of the backends, to find out whether any is healthy without lookups
+    health: Vec<Arc<Health>>,
+    strategy: T,
+    /// Alternative strategies over the same backends, see [LoadBalancer::add_strategy]
+    named: HashMap<String, Box<dyn Strategy + Send + Sync>>,
+}
+
+impl<T: Debug> Debug for Tier<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tier")
+            .field("backends", &self.backends)
+            .field("strategy", &self.strategy)
+            .field("named", &self.named.keys())
+            .finish()
+    }
+}
+
+/// The tiers along with the backends they were built from, so a selection sees both
+/// consistently while the backends are updated
+#[derive(Debug)]
+struct Tiers<T> {
+    set: Arc<BackendSet>,
+    /// Sorted from the highest priority to the lowest
+    by_priority: Vec<Tier<T>>,
+}
+
+/// What a [LoadBalancer::set_selection_policy] callback decides on, besides the backends
+pub struct SelectionContext<'a> {
+    set: &'a BackendSet,
+    key: Option<&'a [u8]>,
+}
+
+impl SelectionContext<'_> {
+    /// The routing key of [LoadBalancer::select_key], `None` for the other selections
+    pub fn key(&self) -> Option<&[u8]> {
+        self.key
+    }
+
+    /// Whether the backend at `index` is healthy and not drained, `false` if out of range
+    pub fn is_healthy(&self, index: usize) -> bool {
+        self.backend_health(index).is_some()
+    }
+
+    /// How many times the backend at `index` was selected so far, its load
+    pub fn selections(&self, index: usize) -> u64 {
+        self.set
+            .backends
+            .get(index)
+            .and_then(|backend| self.set.health.get(&backend.hash_key()))
+            .map_or(0, |entry| entry.selections.load(Ordering::Relaxed))
+    }
+
+    fn backend_health(&self, index: usize) -> Option<&BackendHealth> {
+        self.set
+            .backends
+            .get(index)
+            .and_then(|backend| self.set.healthy(backend))
+    }
+}
+
+type SelectionPolicyFn = dyn Fn(&[Backend], &SelectionContext) -> Option<usize> + Send + Sync;
+
+/// The callback picking the backends instead of the strategy
+struct SelectionPolicy(Box<SelectionPolicyFn>);
+
+impl Debug for SelectionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SelectionPolicy")
+    }
+}
+
+/// Why [LoadBalancer::select_with_reason] couldn't select a backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectError {
+    /// There are no backends to select from
+    Empty,
+    /// Every backend is unhealthy or drained
+    AllUnhealthy,
+    /// Every healthy backend is at its [Backend::max_connections]
+    Saturated,
+    /// Some backend is healthy but none was selected within the iterations, e.g. the
+    /// strategy kept returning unhealthy backends or every healthy backend has a weight of 0
+    ExhaustedIterations,
+}
+
+impl std::fmt::Display for SelectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "no backends to select from"),
+            Self::AllUnhealthy => write!(f, "all backends are unhealthy"),
+            Self::Saturated => write!(f, "all healthy backends are at their max connections"),
+            Self::ExhaustedIterations => write!(f, "no healthy backend selected"),
+        }
     }
 }
 
+impl std::error::Error for SelectError {}
+
 #[derive(Debug)]
 pub struct LoadBalancer<T> {
-    strategy: T,
+    tiers: ArcSwap<Tiers<T>>,
     backends: Backends,
+    strategies: Vec<(String, StrategyBuilder)>,
+    provider: Option<Arc<dyn BackendProvider + Send + Sync + 'static>>,
     pub health_check_interval: Option<Duration>,
+    /// Up to how long to randomly delay each health check after its interval
+    health_check_jitter: Duration,
+    /// How often the backends are fetched from the [BackendProvider]
+    pub update_interval: Option<Duration>,
+    /// Serializes the updates of the backends so none of them is lost
+    update_lock: Mutex<()>,
+    /// How many times a selection tries to find a healthy backend, the number of backends if
+    /// unset
+    max_iterations: Option<u16>,
+    /// The number of healthy backends needed to be ready, see [LoadBalancer::is_ready]
+    min_healthy: usize,
+    selection_policy: Option<SelectionPolicy>,
+    weight_validation: Option<WeightValidation>,
+}
+
+/// Build a [LoadBalancer] with all of its settings at once.
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// use yapf::load_balancer::{
+///     helthcheck::HttpHealthCheck, strategy::RoundRobin, Backend, LoadBalancer,
+/// };
+///
+/// let lb = LoadBalancer::<RoundRobin>::builder()
+///     .backends(vec![
+///         Backend::new("http://1.0.0.1".to_string()),
+///         Backend::new("http://1.0.0.2".to_string()),
+///     ])
+///     .health_check(Arc::new(HttpHealthCheck::new()))
+///     .health_check_interval(Duration::from_secs(5))
+///     // Wait for the first health check before sending traffic
+///     .initial_health(false)
+///     .select_max_iterations(10)
+///     .build();
+///
+/// assert!(lb.next().is_none());
+/// ```
+#[derive(Debug)]
+pub struct LoadBalancerBuilder<T> {
+    backends: Vec<Backend>,
+    health_check: Option<Arc<dyn HealthCheck + Send + Sync + 'static>>,
+    health_check_interval: Option<Duration>,
+    initial_health: bool,
+    max_iterations: Option<u16>,
+    weight_validation: Option<WeightValidation>,
+    strategy: PhantomData<T>,
+}
+
+impl<T: Strategy> LoadBalancerBuilder<T> {
+    fn new() -> Self {
+        Self {
+            backends: Vec::new(),
+            health_check: None,
+            health_check_interval: None,
+            initial_health: true,
+            max_iterations: None,
+            weight_validation: None,
+            strategy: PhantomData,
+        }
+    }
+
+    pub fn backends(mut self, backends: Vec<Backend>) -> Self {
+        self.backends = backends;
+        self
+    }
+
+    pub fn health_check(
+        mut self,
+        health_check: Arc<dyn HealthCheck + Send + Sync + 'static>,
+    ) -> Self {
+        self.health_check = Some(health_check);
+        self
+    }
+
+    /// How often the health check runs in the background, only once if unset
+    pub fn health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = Some(interval);
+        self
+    }
+
+    /// Whether the backends are healthy until they are checked, `true` by default
+    pub fn initial_health(mut self, healthy: bool) -> Self {
+        self.initial_health = healthy;
+        self
+    }
+
+    /// How many times a selection tries to find a healthy backend, by default the
+    /// [Strategy::fairness_period] or else the number of backends
+    pub fn select_max_iterations(mut self, max_iterations: u16) -> Self {
+        self.max_iterations = Some(max_iterations);
+        self
+    }
+
+    /// Check the weights of the backends with `validation`, when built and on every update.
+    ///
+    /// With [WeightPolicy::Error](strategy::WeightPolicy::Error), the updates failing it are
+    /// refused and the previous backends kept.
+    pub fn weight_validation(mut self, validation: WeightValidation) -> Self {
+        self.weight_validation = Some(validation);
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If the backends fail the [LoadBalancerBuilder::weight_validation], see
+    /// [LoadBalancerBuilder::try_build].
+    pub fn build(self) -> LoadBalancer<T> {
+        self.try_build()
+            .expect("the backends fail the weight validation")
+    }
+
+    /// Like [LoadBalancerBuilder::build] but fails if the backends fail the
+    /// [LoadBalancerBuilder::weight_validation].
+    pub fn try_build(self) -> anyhow::Result<LoadBalancer<T>> {
+        if let Some(validation) = &self.weight_validation {
+            check_weights(validation, &self.backends)?;
+        }
+        let mut backends = Backends::new(self.backends, self.initial_health);
+        backends.health_check = self.health_check;
+        let mut lb = LoadBalancer::from_backends(backends);
+        lb.health_check_interval = self.health_check_interval;
+        lb.max_iterations = self.max_iterations;
+        lb.weight_validation = self.weight_validation;
+        Ok(lb)
+    }
+}
+
+/// Check the weights of each priority tier of `backends`, which are balanced apart
+fn check_weights(validation: &WeightValidation, backends: &[Backend]) -> anyhow::Result<()> {
+    let mut by_priority: BTreeMap<u8, Vec<Backend>> = BTreeMap::new();
+    for backend in backends {
+        by_priority
+            .entry(backend.priority)
+            .or_default()
+            .push(backend.clone());
+    }
+    by_priority
+        .values()
+        .try_for_each(|tier| validation.check(tier))
 }
 
 impl<T: Strategy> LoadBalancer<T> {
     pub fn new(backends: Vec<Backend>) -> Self {
-        let strategy = T::build(&backends);
+        Self::from_backends(Backends::new(backends, true))
+    }
+
+    pub fn builder() -> LoadBalancerBuilder<T> {
+        LoadBalancerBuilder::new()
+    }
+
+    fn from_backends(backends: Backends) -> Self {
+        let tiers = Self::build_tiers(backends.set.load_full(), &[]);
         Self {
-            strategy,
-            backends: Backends::new(backends),
+            tiers: ArcSwap::new(Arc::new(tiers)),
+            backends,
+            strategies: Vec::new(),
+            provider: None,
             health_check_interval: None,
+            health_check_jitter: Duration::ZERO,
+            update_interval: None,
+            update_lock: Mutex::new(()),
+            max_iterations: None,
+            min_healthy: 1,
+            selection_policy: None,
+            weight_validation: None,
+        }
+    }
+
+    fn build_tiers(set: Arc<BackendSet>, strategies: &[(String, StrategyBuilder)]) -> Tiers<T> {
+        let mut by_priority: BTreeMap<u8, Vec<Backend>> = BTreeMap::new();
+        for backend in &set.backends {
+            by_priority
+                .entry(backend.priority)
+                .or_default()
+                .push(backend.clone());
         }
+        let by_priority = by_priority
+            .into_values()
+            .map(|backends| Tier {
+                health: backends
+                    .iter()
+                    .filter_map(|backend| set.health.get(&backend.hash_key()))
+                    .map(|entry| entry.health.clone())
+                    .collect(),
+                strategy: T::build(&backends),
+                named: strategies
+                    .iter()
+                    .map(|(name, build)| (name.clone(), build(&backends)))
+                    .collect(),
+                backends,
+            })
+            .collect();
+        Tiers { set, by_priority }
     }
 
     pub fn try_from_vec(backends: &[&str]) -> Result<Self, http::uri::InvalidUri> {
@@ -136,6 +878,49 @@ impl<T: Strategy> LoadBalancer<T> {
         Ok(Self::new(new_backends?))
     }
 
+    /// Like [LoadBalancer::try_from_vec] but reads the weight of each backend from its optional
+    /// `weight` query parameter, e.g. `http://10.0.0.1:8080?weight=200`.
+    ///
+    /// The parameter is stripped from the address, and the weight defaults to 100.
+    pub fn try_from_vec_with_query(backends: &[&str]) -> anyhow::Result<Self> {
+        let new_backends = backends
+            .iter()
+            .map(|addr| parse_weighted(addr))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self::new(new_backends))
+    }
+
+    /// Like [LoadBalancer::try_from_vec] but with the weight of each backend.
+    pub fn try_from_weighted(backends: &[(&str, u16)]) -> Result<Self, InvalidUri> {
+        let new_backends: Result<Vec<Backend>, InvalidUri> = backends
+            .iter()
+            .map(|&(addr, weight)| {
+                let uri = addr.parse::<Uri>()?;
+                Ok(Backend::new(uri.to_string()).with_weight(weight))
+            })
+            .collect();
+        Ok(Self::new(new_backends?))
+    }
+
+    /// Resolve `names`, URIs like `http://example.com:8080`, into one backend per address.
+    ///
+    /// The names are resolved again every [LoadBalancer::update_interval] by the background
+    /// service. A failed resolution keeps the last resolved backends.
+    pub fn try_from_dns(names: &[&str]) -> anyhow::Result<Self> {
+        let provider = DnsBackends::new(names)?;
+        let mut lb = Self::new(provider.resolve_blocking()?);
+        lb.set_backend_provider(Arc::new(provider));
+        Ok(lb)
+    }
+
+    pub fn with_health_check(
+        mut self,
+        health_check: Arc<dyn HealthCheck + Send + Sync + 'static>,
+    ) -> Self {
+        self.set_health_check(health_check);
+        self
+    }
+
     pub fn set_health_check(&mut self, health_check: Arc<dyn HealthCheck + Send + Sync + 'static>) {
         self.backends.set_health_check(health_check);
     }
@@ -144,41 +929,481 @@ impl<T: Strategy> LoadBalancer<T> {
         self.backends.run_health_check().await;
     }
 
-    pub fn select_with(&self, max_iterations: u16) -> Option<&Backend> {
-        for _ in 0..max_iterations {
-            let Some(backend) = self.strategy.get_next() else {
-                return None;
-            };
-            if self.backends.is_healthy(backend) {
-                return Some(backend);
-            }
-        }
-        None
+    /// Receive an event every time a health check flips the health of a backend.
+    ///
+    /// Only the events after subscribing are received, and a receiver lagging over 1024
+    /// events behind misses the oldest ones.
+    pub fn subscribe_health_events(&self) -> broadcast::Receiver<HealthEvent> {
+        self.backends.events.subscribe()
     }
 
-    pub fn next(&self) -> Option<&Backend> {
-        self.select_with(self.backends.backends.len() as u16)
+    /// Check `backend` with `health_check` instead of the one of every backend, e.g. for a
+    /// backend with another health endpoint.
+    pub fn set_backend_health_check(
+        &mut self,
+        backend: &Backend,
+        health_check: Arc<dyn HealthCheck + Send + Sync + 'static>,
+    ) {
+        self.backends
+            .health_check_overrides
+            .insert(backend.hash_key(), health_check);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use helthcheck::HttpHealthCheck;
-    use reqwest::Method;
-    use strategy::RoundRobin;
-    use wiremock::matchers::{method, path};
-    use wiremock::{Mock, MockServer, ResponseTemplate};
+    /// Like [LoadBalancer::run_health_check] but returns the outcome of the check of every
+    /// backend, e.g. for an operator to recheck them on demand.
+    ///
+    /// Empty without a health check.
+    pub async fn run_health_check_reporting(&self) -> Vec<(Backend, anyhow::Result<()>)> {
+        self.backends.run_health_check().await
+    }
 
-    #[test]
-    fn test_backend_hash_key() {
-        let backend1 = Backend::new("http://localhost:8080".to_string());
-        let backend2 = Backend::new("http://localhost:8080".to_string());
-        assert_eq!(backend1.hash_key(), backend2.hash_key());
+    /// Run the health check every `interval` in the background service.
+    pub fn with_health_check_interval(mut self, interval: Duration) -> Self {
+        self.set_health_check_interval(interval);
+        self
+    }
 
-        let backend1 = Backend::new("http://localhost:8081".to_string());
-        let backend2 = Backend::new("http://localhost:8081".to_string());
-        assert_eq!(backend1.hash_key(), backend2.hash_key());
+    /// Run the health check every `interval` in the background service.
+    pub fn set_health_check_interval(&mut self, interval: Duration) {
+        self.health_check_interval = Some(interval);
+    }
+
+    /// Delay each health check by a random duration up to `jitter` after its interval, so that
+    /// balancers started together don't check the backends at the same time.
+    pub fn set_health_check_jitter(&mut self, jitter: Duration) {
+        self.health_check_jitter = jitter;
+    }
+
+    /// Only run the health check once when the background service starts.
+    pub fn clear_health_check_interval(&mut self) {
+        self.health_check_interval = None;
+    }
+
+    /// Discover the backends with `provider`, polled every [LoadBalancer::update_interval] by the
+    /// background service.
+    pub fn set_backend_provider(
+        &mut self,
+        provider: Arc<dyn BackendProvider + Send + Sync + 'static>,
+    ) {
+        self.provider = Some(provider);
+    }
+
+    /// Fetch the backends from the provider and start balancing over them.
+    ///
+    /// The backends are left untouched if there is no provider or it fails.
+    pub async fn update(&self) -> anyhow::Result<()> {
+        let Some(provider) = self.provider.as_ref() else {
+            return Ok(());
+        };
+        let backends = provider.fetch().await?;
+        self.try_modify_backends(|current| *current = backends)
+    }
+
+    /// Replace all the backends at once.
+    ///
+    /// The backends which remain keep their health, the new ones start in the initial health,
+    /// see [LoadBalancerBuilder::initial_health].
+    ///
+    /// The update is refused, logging why, if the backends fail a
+    /// [LoadBalancerBuilder::weight_validation] with `WeightPolicy::Error`.
+    pub fn update_backends(&self, backends: Vec<Backend>) {
+        self.modify_backends(|current| *current = backends);
+    }
+
+    /// Start balancing over `backend` too, in the default health state.
+    ///
+    /// This is a no-op if the backend is already there.
+    pub fn add_backend(&self, backend: Backend) {
+        self.modify_backends(|backends| {
+            if !backends.contains(&backend) {
+                backends.push(backend);
+            }
+        });
+    }
+
+    /// Stop balancing over `backend` and forget about its health.
+    pub fn remove_backend(&self, backend: &Backend) {
+        self.modify_backends(|backends| backends.retain(|b| b != backend));
+    }
+
+    fn modify_backends(&self, modify: impl FnOnce(&mut Vec<Backend>)) {
+        if let Err(e) = self.try_modify_backends(modify) {
+            tracing::error!(error = %e, "refused the update of the backends");
+        }
+    }
+
+    /// Modify the backends, unless they fail the weight validation
+    fn try_modify_backends(&self, modify: impl FnOnce(&mut Vec<Backend>)) -> anyhow::Result<()> {
+        let _guard = self.update_lock.lock().unwrap();
+        let mut backends = self.backends.set.load().backends.clone();
+        modify(&mut backends);
+        if let Some(validation) = &self.weight_validation {
+            check_weights(validation, &backends)?;
+        }
+
+        let set = self.backends.update(backends);
+        self.tiers
+            .store(Arc::new(Self::build_tiers(set, &self.strategies)));
+        Ok(())
+    }
+
+    /// The backends currently in rotation.
+    ///
+    /// Without a health check, every backend is healthy unless reported otherwise.
+    pub fn healthy_backends(&self) -> Vec<Backend> {
+        self.backends.with_health(true)
+    }
+
+    /// Require `min_healthy` healthy backends for [LoadBalancer::is_ready], 1 by default.
+    pub fn set_min_healthy(&mut self, min_healthy: usize) {
+        self.min_healthy = min_healthy;
+    }
+
+    /// Whether enough backends are healthy to serve traffic, e.g. for a readiness probe.
+    pub fn is_ready(&self) -> bool {
+        let set = self.backends.set.load();
+        let healthy = set
+            .backends
+            .iter()
+            .filter(|backend| set.healthy(backend).is_some())
+            .count();
+        healthy >= self.min_healthy
+    }
+
+    /// How many times each backend, by address, was returned by a selection.
+    pub fn selection_counts(&self) -> HashMap<String, u64> {
+        self.backends
+            .set
+            .load()
+            .health
+            .values()
+            .map(|entry| {
+                let count = entry.selections.load(Ordering::Relaxed);
+                (entry.backend.addr.clone(), count)
+            })
+            .collect()
+    }
+
+    /// The hash rings of a key-based strategy by priority, the highest first, e.g. to debug
+    /// where the keys of [LoadBalancer::select_key] go.
+    ///
+    /// Unhealthy backends are in the rings too, their keys go to the next healthy node.
+    pub fn hash_rings(&self) -> Vec<(u8, Vec<RingNode>)> {
+        self.tiers
+            .load()
+            .by_priority
+            .iter()
+            .filter_map(|tier| {
+                let priority = tier.backends.first()?.priority;
+                Some((priority, tier.strategy.hash_ring()?))
+            })
+            .collect()
+    }
+
+    /// The backends currently out of rotation.
+    pub fn unhealthy_backends(&self) -> Vec<Backend> {
+        self.backends.with_health(false)
+    }
+
+    /// The health of `backend` with why its checks failed, `None` if it isn't balanced over.
+    pub fn health_status(&self, backend: &Backend) -> Option<HealthStatus> {
+        self.backends
+            .set
+            .load()
+            .health
+            .get(&backend.hash_key())
+            .map(|entry| entry.health.status())
+    }
+
+    /// Enable passive health checking based on the outcomes reported via
+    /// [LoadBalancer::report_failure] and [LoadBalancer::report_success].
+    pub fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.backends.set_outlier_detection(outlier_detection);
+    }
+
+    /// Report a failed request to `backend`, e.g. from `fail_to_connect` or a 5xx response.
+    ///
+    /// This is a no-op unless outlier detection is enabled.
+    pub fn report_failure(&self, backend: &Backend) {
+        self.backends.report(backend, false);
+    }
+
+    /// Report a successful request to `backend`.
+    ///
+    /// This is a no-op unless outlier detection is enabled.
+    pub fn report_success(&self, backend: &Backend) {
+        self.backends.report(backend, true);
+    }
+
+    /// Take `backend` out of rotation regardless of its health checks.
+    ///
+    /// The override stays in place until [LoadBalancer::clear_health_override] is called,
+    /// or the next health check if [LoadBalancer::set_checks_clear_overrides] is enabled.
+    pub fn mark_unhealthy(&self, backend: &Backend) {
+        self.backends.set_manual_health(backend, Some(false));
+    }
+
+    /// Put `backend` in rotation regardless of its health checks.
+    ///
+    /// See [LoadBalancer::mark_unhealthy] for how long the override lasts.
+    pub fn mark_healthy(&self, backend: &Backend) {
+        self.backends.set_manual_health(backend, Some(true));
+    }
+
+    /// Stop selecting `backend`, e.g. while deploying it, without changing its health.
+    ///
+    /// The backend is out of rotation like an unhealthy one until [LoadBalancer::undrain],
+    /// while its health checks go on, see [HealthStatus::drained].
+    pub fn drain(&self, backend: &Backend) {
+        self.backends.set_drained(backend, true);
+    }
+
+    /// Put a backend taken out with [LoadBalancer::drain] back in rotation, if healthy.
+    pub fn undrain(&self, backend: &Backend) {
+        self.backends.set_drained(backend, false);
+    }
+
+    /// Go back to the health checks to decide whether `backend` is healthy.
+    pub fn clear_health_override(&self, backend: &Backend) {
+        self.backends.set_manual_health(backend, None);
+    }
+
+    /// Let the next health check of a backend clear its manual override. Disabled by default.
+    pub fn set_checks_clear_overrides(&mut self, clear: bool) {
+        self.backends.checks_clear_overrides = clear;
+    }
+
+    /// Check up to `max` backends at once, 1 by default to check them one after the other.
+    pub fn set_max_concurrent_checks(&mut self, max: usize) {
+        self.backends.max_concurrent_checks = max.max(1);
+    }
+
+    /// Pick the backends with `policy` instead of the strategy, for routing which doesn't fit
+    /// any [Strategy].
+    ///
+    /// `policy` is given every backend, whatever their priority, and returns the index of the
+    /// one to select. Picking none, an unhealthy backend or an index out of range fails the
+    /// selection. Named strategies, see [LoadBalancer::add_strategy], are still used as is.
+    pub fn set_selection_policy(
+        &mut self,
+        policy: impl Fn(&[Backend], &SelectionContext) -> Option<usize> + Send + Sync + 'static,
+    ) {
+        self.selection_policy = Some(SelectionPolicy(Box::new(policy)));
+    }
+
+    /// Go back to selecting with the strategy.
+    pub fn clear_selection_policy(&mut self) {
+        self.selection_policy = None;
+    }
+
+    /// Register an alternative strategy over the same backends under `name`.
+    ///
+    /// This allows picking the balancing per request, e.g. per tenant based on what the
+    /// `request_filter` stored in the `CTX`, via [LoadBalancer::next_with_strategy].
+    pub fn add_strategy<S>(&mut self, name: impl Into<String>)
+    where
+        S: Strategy + Send + Sync + 'static,
+    {
+        self.strategies.push((name.into(), build_strategy::<S>));
+        let tiers = Self::build_tiers(self.backends.set.load_full(), &self.strategies);
+        self.tiers.store(Arc::new(tiers));
+    }
+
+    /// Like [LoadBalancer::next] but selects with the strategy registered as `name`.
+    ///
+    /// Returns `None` if no strategy was registered under `name`.
+    pub fn next_with_strategy(&self, name: &str) -> Option<Arc<Backend>> {
+        if !self
+            .strategies
+            .iter()
+            .any(|(registered, _)| registered == name)
+        {
+            return None;
+        }
+        self.select_from_tiers(Some(name), None, None, None, false)
+            .map(|selected| selected.backend)
+            .ok()
+    }
+
+    /// Select a backend from the highest priority tier that has healthy backends.
+    ///
+    /// The returned backend stays valid even if the backends are updated meanwhile.
+    pub fn select_with(&self, max_iterations: u16) -> Option<Arc<Backend>> {
+        self.select_with_reason(max_iterations).ok()
+    }
+
+    /// Like [LoadBalancer::select_with] but tells why no backend could be selected, e.g. to
+    /// answer `503` when all backends are down.
+    pub fn select_with_reason(&self, max_iterations: u16) -> Result<Arc<Backend>, SelectError> {
+        self.select_from_tiers(None, None, Some(max_iterations), None, false)
+            .map(|selected| selected.backend)
+    }
+
+    pub fn next(&self) -> Option<Arc<Backend>> {
+        self.select_from_tiers(None, None, None, None, false)
+            .map(|selected| selected.backend)
+            .ok()
+    }
+
+    /// Select a backend for `key` with hash based strategies, e.g. a client IP or a session id.
+    ///
+    /// Like [LoadBalancer::next], unhealthy backends are skipped.
+    pub fn select_key(&self, key: &[u8]) -> Option<Arc<Backend>> {
+        self.select_from_tiers(None, Some(key), None, None, false)
+            .map(|selected| selected.backend)
+            .ok()
+    }
+
+    /// Like [LoadBalancer::next] but only selects the backends matching `predicate`, e.g. the
+    /// ones in the zone of the client with `|backend| backend.has_tag("zone=a")`.
+    ///
+    /// The highest priority tier with a healthy matching backend is selected from, with the
+    /// strategy over the whole tier, skipping the backends not matching.
+    pub fn next_matching(&self, predicate: impl Fn(&Backend) -> bool) -> Option<Arc<Backend>> {
+        self.select_from_tiers(None, None, None, Some(&predicate), false)
+            .map(|selected| selected.backend)
+            .ok()
+    }
+
+    /// Like [LoadBalancer::next] but counts a connection to the backend until the returned
+    /// [BackendConnection] is dropped, to cap them with [Backend::max_connections].
+    ///
+    /// The backends at their cap are skipped by every selection, failing with
+    /// [SelectError::Saturated] when all the healthy ones are, e.g. to answer `503`.
+    pub fn next_connection(&self) -> Result<BackendConnection, SelectError> {
+        let selected = self.select_from_tiers(None, None, None, None, true)?;
+        Ok(BackendConnection {
+            backend: selected.backend,
+            in_flight: selected.in_flight.expect("the connection is counted"),
+        })
+    }
+
+    /// Like [LoadBalancer::next] but returns a copy of the backend, detached from the balancer.
+    pub fn next_owned(&self) -> Option<Backend> {
+        self.next().map(|backend| backend.as_ref().clone())
+    }
+
+    /// Like [LoadBalancer::select_key] but returns a copy of the backend, detached from the
+    /// balancer.
+    pub fn select_key_owned(&self, key: &[u8]) -> Option<Backend> {
+        self.select_key(key).map(|backend| backend.as_ref().clone())
+    }
+
+    /// Select from the tiers, trying up to `max_iterations` or the fairness period of the
+    /// strategy per tier, among the backends matching `predicate` if any, and counting a
+    /// connection to the selected one if `connect`.
+    fn select_from_tiers(
+        &self,
+        strategy: Option<&str>,
+        key: Option<&[u8]>,
+        max_iterations: Option<u16>,
+        predicate: Option<&dyn Fn(&Backend) -> bool>,
+        connect: bool,
+    ) -> Result<Selected, SelectError> {
+        let matches = |backend: &Backend| predicate.is_none_or(|predicate| predicate(backend));
+        let tiers = self.tiers.load();
+        let set = &tiers.set;
+        if set.backends.is_empty() {
+            return Err(SelectError::Empty);
+        }
+        let max_iterations = max_iterations.or(self.max_iterations).map(usize::from);
+        let mut tiers = tiers
+            .by_priority
+            .iter()
+            .filter(|tier| {
+                let mut backends = tier.backends.iter().zip(&tier.health);
+                backends.any(|(backend, health)| health.in_rotation() && matches(backend))
+            })
+            .peekable();
+        if tiers.peek().is_none() {
+            return Err(SelectError::AllUnhealthy);
+        }
+        if let (Some(policy), None) = (&self.selection_policy, strategy) {
+            let context = SelectionContext { set, key };
+            let entry = (policy.0)(&set.backends, &context)
+                .and_then(|index| context.backend_health(index))
+                .filter(|entry| matches(&entry.backend))
+                .ok_or(SelectError::ExhaustedIterations)?;
+            if !entry.admit(connect) {
+                return Err(SelectError::Saturated);
+            }
+            return Ok(entry.select(connect));
+        }
+
+        // Whether every tier tried had all its backends at their cap
+        let mut saturated = None;
+        'tiers: for tier in tiers {
+            let strategy: &dyn Strategy = match strategy {
+                Some(name) => match tier.named.get(name) {
+                    Some(strategy) => strategy.as_ref(),
+                    None => continue,
+                },
+                None => &tier.strategy,
+            };
+            // Enough attempts for a cyclic strategy to come across every healthy backend, and
+            // at least one to find out whether anything is selectable
+            let max_iterations = max_iterations
+                .or_else(|| strategy.fairness_period().map(|period| period.max(1)))
+                .unwrap_or(set.backends.len());
+            let mut rehashed;
+            for attempt in 0..max_iterations {
+                // A hash based strategy keeps returning the same backend for the same key,
+                // so the key is rehashed with the attempt number to move on to another backend.
+                let key = match key {
+                    Some(key) if attempt > 0 => {
+                        rehashed = key.to_vec();
+                        rehashed.extend_from_slice(&attempt.to_le_bytes());
+                        Some(rehashed.as_slice())
+                    }
+                    key => key,
+                };
+                let Some(backend) = strategy.select(key) else {
+                    // Nothing is selectable in this tier, e.g. every backend has a weight of 0
+                    saturated = Some(false);
+                    continue 'tiers;
+                };
+                let entry = set.healthy(backend).filter(|_| matches(backend));
+                if let Some(entry) = entry.filter(|entry| entry.admit(connect)) {
+                    return Ok(entry.select(connect));
+                }
+            }
+            let full = tier
+                .backends
+                .iter()
+                .filter_map(|backend| set.healthy(backend))
+                .filter(|entry| matches(&entry.backend))
+                .all(|entry| !entry.admit(false));
+            if !full {
+                return Err(SelectError::ExhaustedIterations);
+            }
+            // Spill over to the lower priority tiers
+            saturated = Some(saturated.unwrap_or(true));
+        }
+        match saturated {
+            Some(true) => Err(SelectError::Saturated),
+            _ => Err(SelectError::ExhaustedIterations),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helthcheck::HttpHealthCheck;
+    use reqwest::Method;
+    use strategy::{ConsistentHash, RoundRobin, WeightedRandom, WeightedRoundRobin};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_backend_hash_key() {
+        let backend1 = Backend::new("http://localhost:8080".to_string());
+        let backend2 = Backend::new("http://localhost:8080".to_string());
+        assert_eq!(backend1.hash_key(), backend2.hash_key());
+
+        let backend1 = Backend::new("http://localhost:8081".to_string());
+        let backend2 = Backend::new("http://localhost:8081".to_string());
+        assert_eq!(backend1.hash_key(), backend2.hash_key());
 
         let backend1 = Backend::new("http://localhost:8081".to_string());
         let backend2 = Backend::new("http://localhost:8082".to_string());
@@ -189,6 +1414,10 @@ mod tests {
         let mut backend2 = Backend::new("http://localhost:8081".to_string());
         backend2.set_weight(150);
         assert_ne!(backend1.hash_key(), backend2.hash_key());
+
+        let backend1 = Backend::new("http://localhost:8081".to_string());
+        let backend2 = Backend::new("http://localhost:8081".to_string()).with_priority(1);
+        assert_ne!(backend1.hash_key(), backend2.hash_key());
     }
 
     #[test]
@@ -220,7 +1449,7 @@ mod tests {
                     .to_string(),
             );
 
-            let mut backends = Backends::new(vec![backend1.clone(), backend2.clone()]);
+            let mut backends = Backends::new(vec![backend1.clone(), backend2.clone()], true);
             backends.set_health_check(Arc::new(health_checker));
             backends
         };
@@ -279,8 +1508,8 @@ mod tests {
         lb.set_health_check(Arc::new(health_checker));
 
         // Backends are healthy by default since we haven't run health check yet
-        assert_eq!(lb.next().unwrap(), &backend1);
-        assert_eq!(lb.next().unwrap(), &backend2);
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend2);
 
         Mock::given(method("GET"))
             .and(path("/"))
@@ -298,8 +1527,8 @@ mod tests {
 
         lb.run_health_check().await;
         // Still should be healthy
-        assert_eq!(lb.next().unwrap(), &backend1);
-        assert_eq!(lb.next().unwrap(), &backend2);
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend2);
 
         Mock::given(method("POST"))
             .and(path("/backend2"))
@@ -310,11 +1539,1073 @@ mod tests {
 
         lb.run_health_check().await;
         // backend2 should be unhealthy and should only return backend1
-        assert_eq!(lb.next().unwrap(), &backend1);
-        assert_eq!(lb.next().unwrap(), &backend1);
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend1);
 
         lb.run_health_check().await;
         // All backends are unhealthy
         assert!(lb.next().is_none());
     }
+
+    #[tokio::test]
+    async fn test_lb_outlier_detection() {
+        let backend1 = Backend::new("1.0.0.1".to_string());
+        let backend2 = Backend::new("1.0.0.2".to_string());
+
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![backend1.clone(), backend2.clone()]);
+        lb.set_outlier_detection(OutlierDetection {
+            consecutive_failures: 3,
+            window: Duration::from_secs(10),
+            base_ejection: Duration::from_millis(200),
+            max_ejection: Duration::from_secs(10),
+        });
+
+        // A success in between breaks the run of failures
+        lb.report_failure(&backend2);
+        lb.report_failure(&backend2);
+        lb.report_success(&backend2);
+        lb.report_failure(&backend2);
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend2);
+
+        // Third consecutive failure ejects backend2
+        lb.report_failure(&backend2);
+        lb.report_failure(&backend2);
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend1);
+
+        // Re-admitted after the base ejection time
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!((0..2).any(|_| *lb.next().unwrap() == backend2));
+
+        // Repeat offense doubles the ejection time
+        for _ in 0..3 {
+            lb.report_failure(&backend2);
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend1);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!((0..2).any(|_| *lb.next().unwrap() == backend2));
+    }
+
+    #[test]
+    fn test_lb_priority_failover() {
+        let primary1 = Backend::new("1.0.0.1".to_string());
+        let primary2 = Backend::new("1.0.0.2".to_string());
+        let backup = Backend::new("2.0.0.1".to_string()).with_priority(1);
+
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![backup.clone(), primary1.clone(), primary2.clone()]);
+        lb.set_outlier_detection(OutlierDetection {
+            consecutive_failures: 1,
+            ..Default::default()
+        });
+
+        // The backup stays idle while the primaries are healthy
+        for _ in 0..4 {
+            assert_ne!(*lb.next().unwrap(), backup);
+        }
+
+        // Even with a single primary left
+        lb.report_failure(&primary1);
+        for _ in 0..4 {
+            assert_eq!(*lb.next().unwrap(), primary2);
+        }
+
+        // The backup takes over when no primary is healthy
+        lb.report_failure(&primary2);
+        for _ in 0..4 {
+            assert_eq!(*lb.next().unwrap(), backup);
+        }
+
+        // And everything is down once the backup fails too
+        lb.report_failure(&backup);
+        assert!(lb.next().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lb_manual_health_override() {
+        let backend_server1 = MockServer::start().await;
+        let backend_server2 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&backend_server1)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&backend_server2)
+            .await;
+
+        let backend1 = Backend::new(backend_server1.uri());
+        let backend2 = Backend::new(backend_server2.uri());
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![backend1.clone(), backend2.clone()]);
+        lb.set_health_check(Arc::new(HttpHealthCheck::new()));
+
+        // A manually ejected backend stays ejected even though its check passes
+        lb.mark_unhealthy(&backend1);
+        lb.mark_healthy(&backend2);
+        lb.run_health_check().await;
+        assert_eq!(*lb.next().unwrap(), backend2);
+        assert_eq!(*lb.next().unwrap(), backend2);
+
+        // Until the override is cleared
+        lb.clear_health_override(&backend1);
+        lb.clear_health_override(&backend2);
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend1);
+
+        // Or the checks are allowed to undo it
+        lb.set_checks_clear_overrides(true);
+        lb.mark_unhealthy(&backend1);
+        assert!(lb.next().is_none());
+        lb.run_health_check().await;
+        assert_eq!(*lb.next().unwrap(), backend1);
+    }
+
+    #[test]
+    fn test_lb_select_key() {
+        let backends = vec!["1.0.0.1", "1.0.0.2", "1.0.0.3"];
+        let mut lb: LoadBalancer<ConsistentHash> = LoadBalancer::try_from_vec(&backends).unwrap();
+        lb.set_outlier_detection(OutlierDetection {
+            consecutive_failures: 1,
+            ..Default::default()
+        });
+
+        let selected = lb.select_key(b"client-1").unwrap();
+        assert_eq!(lb.select_key(b"client-1").unwrap(), selected);
+
+        // An unhealthy backend is skipped, consistently
+        lb.report_failure(&selected);
+        let fallback = lb.select_key(b"client-1").unwrap();
+        assert_ne!(fallback, selected);
+        assert_eq!(lb.select_key(b"client-1").unwrap(), fallback);
+
+        // Stateless strategies ignore the key
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        assert_eq!(lb.select_key(b"client-1").unwrap().addr, "1.0.0.1");
+        assert_eq!(lb.select_key(b"client-1").unwrap().addr, "1.0.0.2");
+    }
+
+    #[test]
+    fn test_lb_all_backends_drained() {
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()).with_weight(0),
+            Backend::new("1.0.0.2".to_string()).with_weight(0),
+        ];
+        let lb: LoadBalancer<WeightedRoundRobin> = LoadBalancer::new(backends.clone());
+        assert!(lb.next().is_none());
+        let lb: LoadBalancer<WeightedRandom> = LoadBalancer::new(backends.clone());
+        assert!(lb.next().is_none());
+        let lb: LoadBalancer<WeightedRandom> = LoadBalancer::new(vec![]);
+        assert!(lb.next().is_none());
+
+        // A drained tier falls through to the next one
+        let mut backends = backends;
+        backends.push(Backend::new("2.0.0.1".to_string()).with_priority(1));
+        let lb: LoadBalancer<WeightedRoundRobin> = LoadBalancer::new(backends);
+        assert_eq!(lb.next().unwrap().addr, "2.0.0.1");
+    }
+
+    #[test]
+    fn test_lb_next_with_strategy() {
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()),
+            Backend::new("1.0.0.2".to_string()),
+            Backend::new("1.0.0.3".to_string()).with_weight(200),
+        ];
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::new(backends);
+        lb.add_strategy::<WeightedRoundRobin>("weighted");
+
+        // The tenant stored in the CTX by the request filter picks the strategy
+        let select = |tenant: &str| match tenant {
+            "premium" => lb.next_with_strategy("weighted"),
+            _ => lb.next(),
+        };
+
+        assert_eq!(select("basic").unwrap().addr, "1.0.0.1");
+        assert_eq!(select("premium").unwrap().addr, "1.0.0.3");
+        assert_eq!(select("basic").unwrap().addr, "1.0.0.2");
+        assert_eq!(select("premium").unwrap().addr, "1.0.0.1");
+        assert_eq!(select("premium").unwrap().addr, "1.0.0.2");
+        assert_eq!(select("basic").unwrap().addr, "1.0.0.3");
+        assert_eq!(select("premium").unwrap().addr, "1.0.0.3");
+
+        assert!(lb.next_with_strategy("unknown").is_none());
+    }
+
+    /// A provider returning the next of `polls` each time, failing once they are exhausted
+    #[derive(Debug)]
+    struct StubProvider {
+        polls: std::sync::Mutex<Vec<Vec<Backend>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl BackendProvider for StubProvider {
+        async fn fetch(&self) -> anyhow::Result<Vec<Backend>> {
+            let mut polls = self.polls.lock().unwrap();
+            if polls.is_empty() {
+                anyhow::bail!("provider unavailable");
+            }
+            Ok(polls.remove(0))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lb_backend_provider() {
+        let backend1 = Backend::new("1.0.0.1".to_string());
+        let backend2 = Backend::new("1.0.0.2".to_string());
+        let backend3 = Backend::new("1.0.0.3".to_string());
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::new(vec![]);
+        lb.set_backend_provider(Arc::new(StubProvider {
+            polls: std::sync::Mutex::new(vec![
+                vec![backend1.clone(), backend2.clone()],
+                vec![backend2.clone(), backend3.clone()],
+            ]),
+        }));
+        assert!(lb.next().is_none());
+
+        lb.update().await.unwrap();
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend2);
+        assert_eq!(*lb.next().unwrap(), backend1);
+
+        // An unhealthy backend stays unhealthy across updates
+        lb.mark_unhealthy(&backend2);
+        lb.update().await.unwrap();
+        assert_eq!(*lb.next().unwrap(), backend3);
+        assert_eq!(*lb.next().unwrap(), backend3);
+        assert!(!lb.backends.is_healthy(&backend1));
+
+        // The last backends are kept if the provider fails
+        assert!(lb.update().await.is_err());
+        assert_eq!(*lb.next().unwrap(), backend3);
+    }
+
+    #[test]
+    fn test_backend_metadata() {
+        let metadata = |region: &str| HashMap::from([("region".to_string(), region.to_string())]);
+        let backend1 = Backend::new("1.0.0.1".to_string()).with_metadata(metadata("eu"));
+        let mut backend2 = Backend::new("1.0.0.1".to_string());
+        backend2.set_metadata(metadata("us"));
+        assert_eq!(backend1.hash_key(), backend2.hash_key());
+        assert_eq!(backend1, backend2);
+
+        // The backend with new metadata keeps its health
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::new(vec![backend1.clone()]);
+        lb.mark_unhealthy(&backend1);
+        lb.update_backends(vec![backend2.clone()]);
+        assert!(!lb.backends.is_healthy(&backend2));
+        assert_eq!(lb.unhealthy_backends()[0].metadata, metadata("us"));
+        lb.mark_healthy(&backend2);
+        assert_eq!(lb.next().unwrap().metadata, metadata("us"));
+    }
+
+    #[test]
+    fn test_next_matching() {
+        let backend = |addr: &str, zone: &str| {
+            Backend::new(addr.to_string()).with_tags(HashSet::from([format!("zone={zone}")]))
+        };
+        let backends = vec![
+            backend("1.0.0.1", "a"),
+            backend("1.0.0.2", "b"),
+            backend("1.0.0.3", "a"),
+            backend("1.0.0.4", "b"),
+        ];
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::new(backends.clone());
+        let zone_a = |backend: &Backend| backend.has_tag("zone=a");
+        for _ in 0..8 {
+            assert!(lb.next_matching(zone_a).unwrap().has_tag("zone=a"));
+        }
+        // The strategy still rotates over the matching backends
+        let selected: HashSet<_> = (0..4)
+            .map(|_| lb.next_matching(zone_a).unwrap().addr.clone())
+            .collect();
+        assert_eq!(selected.len(), 2);
+
+        // Unhealthy matching backends are skipped, down to none
+        lb.mark_unhealthy(&backends[0]);
+        assert_eq!(lb.next_matching(zone_a).unwrap().addr, "1.0.0.3");
+        lb.mark_unhealthy(&backends[2]);
+        assert!(lb.next_matching(zone_a).is_none());
+        assert!(lb.next().unwrap().has_tag("zone=b"));
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let backend1 = Backend::new("1.0.0.1".to_string()).with_max_connections(1);
+        let backend2 = Backend::new("1.0.0.2".to_string()).with_max_connections(2);
+        let lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![backend1.clone(), backend2.clone()]);
+
+        let connection1 = lb.next_connection().unwrap();
+        assert_eq!(*connection1, backend1);
+        // The saturated backend is avoided by the next selections
+        let connection2 = lb.next_connection().unwrap();
+        let connection3 = lb.next_connection().unwrap();
+        assert_eq!(*connection2, backend2);
+        assert_eq!(*connection3, backend2);
+        assert!(lb.next().is_none());
+        assert_eq!(lb.next_connection().unwrap_err(), SelectError::Saturated);
+        assert_eq!(
+            lb.select_with_reason(4).unwrap_err(),
+            SelectError::Saturated
+        );
+
+        // The backend is selected again once a connection is closed
+        drop(connection1);
+        let connection1 = lb.next_connection().unwrap();
+        assert_eq!(*connection1, backend1);
+        drop(connection2);
+        let connection2 = lb.next_connection().unwrap();
+        assert_eq!(*connection2, backend2);
+
+        // The open connections are still counted after an update
+        lb.update_backends(vec![backend1.clone(), backend2.clone()]);
+        assert_eq!(lb.next_connection().unwrap_err(), SelectError::Saturated);
+    }
+
+    #[test]
+    fn test_max_connections_spill_over() {
+        let primary = Backend::new("1.0.0.1".to_string()).with_max_connections(1);
+        let fallback = Backend::new("1.0.0.2".to_string())
+            .with_priority(1)
+            .with_max_connections(1);
+        let lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![primary.clone(), fallback.clone()]);
+
+        let connection1 = lb.next_connection().unwrap();
+        assert_eq!(*connection1, primary);
+        // The full primary tier leaves the traffic to the fallback one
+        let connection2 = lb.next_connection().unwrap();
+        assert_eq!(*connection2, fallback);
+        assert_eq!(lb.next_connection().unwrap_err(), SelectError::Saturated);
+
+        drop(connection1);
+        assert_eq!(*lb.next_connection().unwrap(), primary);
+    }
+
+    #[test]
+    fn test_max_connections_update() {
+        let backend = Backend::new("1.0.0.1".to_string()).with_max_connections(1);
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::new(vec![backend.clone()]);
+        let connection = lb.next_connection().unwrap();
+        lb.drain(&backend);
+
+        // Raising the cap keeps the health, the drain and the open connection
+        let mut raised = backend.clone();
+        raised.set_max_connections(Some(2));
+        assert_eq!(raised.hash_key(), backend.hash_key());
+        lb.update_backends(vec![raised.clone()]);
+        assert!(lb.health_status(&raised).unwrap().drained);
+        lb.undrain(&raised);
+        let second = lb.next_connection().unwrap();
+        assert_eq!(second.max_connections, Some(2));
+        assert_eq!(lb.next_connection().unwrap_err(), SelectError::Saturated);
+
+        // Connections opened before the update are released against the same counter
+        drop(connection);
+        let third = lb.next_connection().unwrap();
+        assert_eq!(lb.next_connection().unwrap_err(), SelectError::Saturated);
+        drop((second, third));
+        assert!(lb.next_connection().is_ok());
+    }
+
+    #[test]
+    fn test_drain() {
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()),
+            Backend::new("1.0.0.2".to_string()),
+        ];
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::new(backends.clone());
+        lb.drain(&backends[0]);
+        for _ in 0..4 {
+            assert_eq!(*lb.next().unwrap(), backends[1]);
+        }
+        let status = lb.health_status(&backends[0]).unwrap();
+        assert!(status.healthy && status.drained);
+        assert_eq!(lb.unhealthy_backends(), vec![backends[0].clone()]);
+
+        // Draining is kept apart from the health
+        lb.mark_unhealthy(&backends[0]);
+        let status = lb.health_status(&backends[0]).unwrap();
+        assert!(!status.healthy && status.drained);
+        lb.undrain(&backends[0]);
+        assert!(!lb.health_status(&backends[0]).unwrap().drained);
+        assert_eq!(*lb.next().unwrap(), backends[1]);
+
+        lb.clear_health_override(&backends[0]);
+        let selected: HashSet<_> = (0..2).map(|_| lb.next().unwrap().addr.clone()).collect();
+        assert_eq!(selected.len(), 2);
+
+        // Every backend drained
+        lb.drain(&backends[0]);
+        lb.drain(&backends[1]);
+        assert_eq!(
+            lb.select_with_reason(2).unwrap_err(),
+            SelectError::AllUnhealthy
+        );
+    }
+
+    #[test]
+    fn test_lb_add_remove_backend() {
+        let backend1 = Backend::new("1.0.0.1".to_string());
+        let backend2 = Backend::new("1.0.0.2".to_string());
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::new(vec![backend1.clone()]);
+
+        lb.add_backend(backend2.clone());
+        lb.add_backend(backend2.clone());
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend2);
+        assert_eq!(*lb.next().unwrap(), backend1);
+
+        // A removed backend comes back in the default health state
+        lb.mark_unhealthy(&backend2);
+        lb.remove_backend(&backend2);
+        assert!(!lb.backends.is_healthy(&backend2));
+        assert_eq!(*lb.next().unwrap(), backend1);
+        assert_eq!(*lb.next().unwrap(), backend1);
+        lb.add_backend(backend2.clone());
+        assert!(lb.backends.is_healthy(&backend2));
+    }
+
+    #[test]
+    fn test_lb_add_remove_backend_concurrently() {
+        let backend1 = Backend::new("1.0.0.1".to_string());
+        let lb: Arc<LoadBalancer<RoundRobin>> = Arc::new(LoadBalancer::new(vec![backend1.clone()]));
+
+        let selecting: Vec<_> = (0..4)
+            .map(|_| {
+                let lb = lb.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        let backend = lb.next().unwrap();
+                        assert!(backend.addr.starts_with("1.0.0."), "{backend:?}");
+                    }
+                })
+            })
+            .collect();
+        let updating: Vec<_> = (2..6)
+            .map(|i| {
+                let lb = lb.clone();
+                std::thread::spawn(move || {
+                    let backend = Backend::new(format!("1.0.0.{i}"));
+                    for _ in 0..1_000 {
+                        lb.add_backend(backend.clone());
+                        lb.remove_backend(&backend);
+                    }
+                    lb.add_backend(backend);
+                })
+            })
+            .collect();
+        for thread in selecting.into_iter().chain(updating) {
+            thread.join().unwrap();
+        }
+
+        // Every update made it
+        assert_eq!(lb.backends.len(), 5);
+        let tiers = lb.tiers.load();
+        assert_eq!(tiers.set.backends.len(), 5);
+        assert_eq!(tiers.by_priority.len(), 1);
+        assert_eq!(tiers.by_priority[0].backends.len(), 5);
+    }
+
+    #[test]
+    fn test_lb_update_backends() {
+        let backend1 = Backend::new("1.0.0.1".to_string());
+        let backend2 = Backend::new("1.0.0.2".to_string());
+        let backend3 = Backend::new("1.0.0.3".to_string());
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![backend1.clone(), backend2.clone()]);
+        lb.set_outlier_detection(OutlierDetection {
+            consecutive_failures: 1,
+            ..Default::default()
+        });
+        lb.report_failure(&backend2);
+
+        // backend2 survives, with another weight, and stays unhealthy
+        let survivor = backend2.clone().with_weight(50);
+        lb.update_backends(vec![survivor.clone(), backend3.clone()]);
+        assert!(!lb.backends.is_healthy(&survivor));
+        assert!(!lb.backends.is_healthy(&backend1));
+        assert!(lb.backends.is_healthy(&backend3));
+        assert_eq!(*lb.next().unwrap(), backend3);
+        assert_eq!(*lb.next().unwrap(), backend3);
+    }
+
+    #[tokio::test]
+    async fn test_lb_next_owned() {
+        let backends = vec!["1.0.0.1", "1.0.0.2"];
+        let lb: LoadBalancer<ConsistentHash> = LoadBalancer::try_from_vec(&backends).unwrap();
+
+        let backend = lb.next_owned().unwrap();
+        // Held across an await point and the backends being replaced
+        tokio::task::yield_now().await;
+        lb.update_backends(vec![]);
+        assert_eq!(backend.addr, "1.0.0.1");
+        assert!(lb.next_owned().is_none());
+
+        lb.update_backends(vec![backend.clone()]);
+        assert_eq!(lb.select_key_owned(b"client-1"), Some(backend));
+    }
+
+    /// Fails the check of a single backend
+    #[derive(Debug)]
+    struct FailingHealthCheck(String);
+
+    #[async_trait::async_trait]
+    impl HealthCheck for FailingHealthCheck {
+        async fn check(&self, target: &Backend) -> anyhow::Result<()> {
+            if target.addr == self.0 {
+                anyhow::bail!("{} is down", self.0);
+            }
+            Ok(())
+        }
+
+        fn health_threshold(&self, _success: bool) -> usize {
+            1
+        }
+    }
+
+    /// Panics checking a single backend
+    #[derive(Debug)]
+    struct PanickingHealthCheck(String);
+
+    #[async_trait::async_trait]
+    impl HealthCheck for PanickingHealthCheck {
+        async fn check(&self, target: &Backend) -> anyhow::Result<()> {
+            if target.addr == self.0 {
+                panic!("{} broke the check", self.0);
+            }
+            Ok(())
+        }
+
+        fn health_threshold(&self, _success: bool) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check_panic() {
+        let backends = vec!["1.0.0.1", "1.0.0.2"];
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        let all = lb.healthy_backends();
+        lb.set_health_check(Arc::new(PanickingHealthCheck(all[0].addr.clone())));
+
+        // The other backends are still checked
+        let results = lb.run_health_check_reporting().await;
+        assert_eq!(results.len(), 2);
+        let error = results[0].1.as_ref().unwrap_err().to_string();
+        assert!(error.contains("health check panicked"), "{error}");
+        assert!(results[1].1.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_lb_healthy_backends() {
+        let backends = vec!["1.0.0.1", "1.0.0.2", "1.0.0.3"];
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        let all: Vec<Backend> = lb.healthy_backends();
+        assert_eq!(all.len(), 3);
+        assert!(lb.unhealthy_backends().is_empty());
+
+        lb.set_health_check(Arc::new(FailingHealthCheck(all[1].addr.clone())));
+        lb.run_health_check().await;
+        assert_eq!(lb.healthy_backends(), vec![all[0].clone(), all[2].clone()]);
+        assert_eq!(lb.unhealthy_backends(), vec![all[1].clone()]);
+    }
+
+    #[test]
+    fn test_lb_weight_validation() {
+        use strategy::WeightPolicy;
+
+        let validation = WeightValidation {
+            max_ratio: 10,
+            policy: WeightPolicy::Error,
+        };
+        let light = Backend::new("1.0.0.1".to_string()).with_weight(1);
+        let heavy = Backend::new("1.0.0.2".to_string()).with_weight(100);
+        let result = LoadBalancer::<WeightedRoundRobin>::builder()
+            .backends(vec![light.clone(), heavy.clone()])
+            .weight_validation(validation.clone())
+            .try_build();
+        assert!(result.is_err());
+
+        // The backends of different tiers don't starve each other
+        let fallback = heavy.clone().with_priority(1);
+        let lb = LoadBalancer::<WeightedRoundRobin>::builder()
+            .backends(vec![light.clone(), fallback.clone()])
+            .weight_validation(validation.clone())
+            .build();
+
+        // The updates failing the validation are refused
+        lb.update_backends(vec![light.clone(), heavy.clone()]);
+        assert_eq!(lb.healthy_backends(), vec![light.clone(), fallback.clone()]);
+        lb.add_backend(heavy.clone());
+        assert_eq!(lb.healthy_backends(), vec![light.clone(), fallback.clone()]);
+        let even = Backend::new("1.0.0.3".to_string()).with_weight(5);
+        lb.add_backend(even.clone());
+        assert_eq!(lb.healthy_backends(), vec![light.clone(), fallback, even]);
+
+        // Only warned about
+        let lb = LoadBalancer::<WeightedRoundRobin>::builder()
+            .backends(vec![light.clone(), heavy.clone()])
+            .weight_validation(WeightValidation {
+                policy: WeightPolicy::Warn,
+                ..validation
+            })
+            .build();
+        assert_eq!(lb.healthy_backends(), vec![light, heavy]);
+    }
+
+    #[tokio::test]
+    async fn test_lb_builder() {
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()),
+            Backend::new("1.0.0.2".to_string()),
+        ];
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::builder()
+            .backends(backends.clone())
+            .health_check(Arc::new(FailingHealthCheck(backends[1].addr.clone())))
+            .health_check_interval(Duration::from_secs(5))
+            .initial_health(false)
+            .select_max_iterations(1)
+            .build();
+        assert_eq!(lb.health_check_interval, Some(Duration::from_secs(5)));
+        assert!(lb.next().is_none());
+
+        // Only the backends passing the check become healthy
+        lb.run_health_check().await;
+        assert_eq!(lb.healthy_backends(), vec![backends[0].clone()]);
+        // A single try, which might land on the unhealthy backend
+        let selected: Vec<_> = (0..4).map(|_| lb.next()).collect();
+        assert!(selected.contains(&None));
+        assert!(selected.contains(&Some(Arc::new(backends[0].clone()))));
+    }
+
+    #[tokio::test]
+    async fn test_lb_try_from_dns() {
+        let lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::try_from_dns(&["http://localhost:8080", "https://127.0.0.2"]).unwrap();
+        let addrs: Vec<String> = lb.healthy_backends().into_iter().map(|b| b.addr).collect();
+        assert!(
+            addrs.contains(&"http://127.0.0.1:8080/".to_string()),
+            "{addrs:?}"
+        );
+        assert!(
+            addrs.contains(&"https://127.0.0.2:443/".to_string()),
+            "{addrs:?}"
+        );
+
+        // Resolved again on update
+        lb.update().await.unwrap();
+        assert_eq!(lb.healthy_backends().len(), addrs.len());
+
+        assert!(LoadBalancer::<RoundRobin>::try_from_dns(&["/no-host"]).is_err());
+    }
+
+    #[test]
+    fn test_lb_min_healthy() {
+        let backends = vec!["1.0.0.1", "1.0.0.2", "1.0.0.3"];
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        let all = lb.healthy_backends();
+        lb.mark_unhealthy(&all[1]);
+        lb.mark_unhealthy(&all[2]);
+        assert!(lb.is_ready());
+
+        lb.set_min_healthy(2);
+        assert!(!lb.is_ready());
+        lb.clear_health_override(&all[2]);
+        assert!(lb.is_ready());
+    }
+
+    #[test]
+    fn test_lb_try_from_weighted() {
+        let backends = vec![("1.0.0.1", 100), ("1.0.0.2", 300)];
+        let lb: LoadBalancer<WeightedRoundRobin> =
+            LoadBalancer::try_from_weighted(&backends).unwrap();
+        let mut count = HashMap::new();
+        for _ in 0..400 {
+            *count.entry(lb.next().unwrap().addr.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(count["1.0.0.1"], 100);
+        assert_eq!(count["1.0.0.2"], 300);
+
+        assert!(LoadBalancer::<RoundRobin>::try_from_weighted(&[("not a uri", 1)]).is_err());
+    }
+
+    #[test]
+    fn test_lb_try_from_vec_with_query() {
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec_with_query(&[
+            "http://10.0.0.1:8080?weight=200",
+            "http://10.0.0.2:8080/api?region=eu&weight=50",
+            "http://10.0.0.3:8080",
+        ])
+        .unwrap();
+        let backends: Vec<(String, u16)> = lb
+            .healthy_backends()
+            .into_iter()
+            .map(|b| (b.addr, b.weight))
+            .collect();
+        assert_eq!(
+            backends,
+            vec![
+                ("http://10.0.0.1:8080/".to_string(), 200),
+                ("http://10.0.0.2:8080/api?region=eu".to_string(), 50),
+                ("http://10.0.0.3:8080/".to_string(), 100),
+            ]
+        );
+
+        for malformed in ["?weight=heavy", "?weight=70000", "?weight=", "?weight=-1"] {
+            let addr = format!("http://10.0.0.1:8080/{malformed}");
+            let err = LoadBalancer::<RoundRobin>::try_from_vec_with_query(&[&addr]).unwrap_err();
+            assert!(err.to_string().contains("invalid weight"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_lb_selection_counts() {
+        let backends = vec!["1.0.0.1", "1.0.0.2", "1.0.0.3"];
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        for _ in 0..7 {
+            lb.next();
+        }
+        let counts = lb.selection_counts();
+        assert_eq!(counts["1.0.0.1"], 3);
+        assert_eq!(counts["1.0.0.2"], 2);
+        assert_eq!(counts["1.0.0.3"], 2);
+
+        // A skipped unhealthy backend is not counted
+        let all = lb.healthy_backends();
+        lb.mark_unhealthy(&all[1]);
+        lb.next();
+        lb.next();
+        let counts = lb.selection_counts();
+        assert_eq!(counts["1.0.0.1"], 4);
+        assert_eq!(counts["1.0.0.2"], 2);
+        assert_eq!(counts["1.0.0.3"], 3);
+    }
+
+    #[test]
+    fn test_lb_select_with_reason() {
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::new(vec![]);
+        assert_eq!(lb.select_with_reason(1).unwrap_err(), SelectError::Empty);
+        assert!(lb.next().is_none());
+
+        let backends = vec!["1.0.0.1", "1.0.0.2", "1.0.0.3"];
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        let all = lb.healthy_backends();
+        lb.mark_unhealthy(&all[0]);
+        lb.mark_unhealthy(&all[1]);
+
+        // The first pick is unhealthy and there is no budget for another
+        assert_eq!(
+            lb.select_with_reason(1).unwrap_err(),
+            SelectError::ExhaustedIterations
+        );
+        assert_eq!(lb.select_with_reason(3).unwrap().addr, "1.0.0.3");
+
+        lb.mark_unhealthy(&all[2]);
+        assert_eq!(
+            lb.select_with_reason(3).unwrap_err(),
+            SelectError::AllUnhealthy
+        );
+
+        // Healthy but drained backends can't be selected either
+        let lb: LoadBalancer<WeightedRoundRobin> =
+            LoadBalancer::new(vec![Backend::new("1.0.0.1".to_string()).with_weight(0)]);
+        assert_eq!(
+            lb.select_with_reason(1).unwrap_err(),
+            SelectError::ExhaustedIterations
+        );
+    }
+
+    #[test]
+    fn test_lb_weighted_finds_low_weight_backend() {
+        let lb: LoadBalancer<WeightedRoundRobin> =
+            LoadBalancer::try_from_weighted(&[("1.0.0.1", 10), ("1.0.0.2", 10), ("1.0.0.3", 1)])
+                .unwrap();
+        let all = lb.healthy_backends();
+        let heavy: Vec<_> = all.iter().filter(|b| b.weight == 10).collect();
+        for backend in heavy {
+            lb.mark_unhealthy(backend);
+        }
+
+        // The light backend is selected once per 21 selections, beyond the 3 backends
+        for _ in 0..25 {
+            assert_eq!(lb.next().unwrap().addr, "1.0.0.3");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lb_with_health_check_fluently() {
+        let backend = Backend::new("1.0.0.1".to_string());
+        let lb = Arc::new(
+            LoadBalancer::<RoundRobin>::new(vec![backend.clone()])
+                .with_health_check(Arc::new(FailingHealthCheck(backend.addr.clone())))
+                .with_health_check_interval(Duration::from_secs(5)),
+        );
+        assert_eq!(lb.health_check_interval, Some(Duration::from_secs(5)));
+
+        assert!(lb.next().is_some());
+        lb.run_health_check().await;
+        assert!(lb.next().is_none());
+    }
+
+    #[test]
+    fn test_lb_hash_rings() {
+        let backends = vec!["1.0.0.1", "1.0.0.2", "1.0.0.3"];
+        let lb: LoadBalancer<ConsistentHash> = LoadBalancer::try_from_vec(&backends).unwrap();
+        let rings = lb.hash_rings();
+        assert_eq!(rings.len(), 1);
+        let (priority, ring) = &rings[0];
+        assert_eq!(*priority, 0);
+        assert_eq!(ring.len(), 3 * DEFAULT_WEIGHT as usize);
+        assert!(ring
+            .windows(2)
+            .all(|nodes| nodes[0].point <= nodes[1].point));
+
+        for i in 0..100 {
+            let key = format!("client-{i}");
+            let point = ConsistentHash::key_point(key.as_bytes());
+            let owner = ring
+                .iter()
+                .find(|node| node.point >= point)
+                .unwrap_or(&ring[0]);
+            assert_eq!(lb.select_key(key.as_bytes()).unwrap().addr, owner.addr);
+        }
+
+        // Nothing to inspect without a key
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        assert!(lb.hash_rings().is_empty());
+    }
+
+    /// Records how many checks run at once, each taking a while
+    #[derive(Debug, Default)]
+    struct ConcurrentHealthCheck {
+        running: std::sync::atomic::AtomicUsize,
+        max_running: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl HealthCheck for ConcurrentHealthCheck {
+        async fn check(&self, _target: &Backend) -> anyhow::Result<()> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn health_threshold(&self, _success: bool) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lb_max_concurrent_checks() {
+        let backends: Vec<_> = (0..20)
+            .map(|i| Backend::new(format!("1.0.0.{i}")))
+            .collect();
+        for max in [1, 4] {
+            let health_check = Arc::new(ConcurrentHealthCheck::default());
+            let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::new(backends.clone());
+            lb.set_health_check(health_check.clone());
+            lb.set_max_concurrent_checks(max);
+            lb.run_health_check().await;
+            assert_eq!(health_check.max_running.load(Ordering::SeqCst), max);
+            assert_eq!(health_check.running.load(Ordering::SeqCst), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lb_run_health_check_reporting() {
+        let backends = vec!["1.0.0.1", "1.0.0.2", "1.0.0.3"];
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        assert!(lb.run_health_check_reporting().await.is_empty());
+
+        lb.set_health_check(Arc::new(FailingHealthCheck("1.0.0.2".to_string())));
+        lb.set_max_concurrent_checks(3);
+        let results = lb.run_health_check_reporting().await;
+        let outcomes: Vec<_> = results
+            .iter()
+            .map(|(backend, result)| (backend.addr.as_str(), result.is_ok()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [("1.0.0.1", true), ("1.0.0.2", false), ("1.0.0.3", true)]
+        );
+        assert_eq!(
+            results[1].1.as_ref().unwrap_err().to_string(),
+            "1.0.0.2 is down"
+        );
+
+        // The health is updated too
+        let unhealthy = lb.unhealthy_backends();
+        assert_eq!(unhealthy.len(), 1);
+        assert_eq!(unhealthy[0].addr, "1.0.0.2");
+    }
+
+    #[tokio::test]
+    async fn test_lb_backend_health_check() {
+        let server1 = MockServer::start().await;
+        let server2 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/healthz"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server1)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/status"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server2)
+            .await;
+        let backend1 = Backend::new(server1.uri());
+        let backend2 = Backend::new(server2.uri());
+
+        let mut healthz = HttpHealthCheck::new();
+        healthz.set_path("/healthz");
+        let mut status = HttpHealthCheck::new();
+        status.set_path("/status");
+
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::new(vec![backend1.clone(), backend2.clone()]);
+        lb.set_health_check(Arc::new(healthz));
+        let results = lb.run_health_check_reporting().await;
+        assert!(results[0].1.is_ok());
+        assert!(results[1].1.is_err());
+
+        lb.set_backend_health_check(&backend2, Arc::new(status));
+        lb.run_health_check().await;
+        assert_eq!(lb.healthy_backends(), [backend1, backend2]);
+    }
+
+    #[tokio::test]
+    async fn test_lb_health_events() {
+        let backends = vec!["1.0.0.1", "1.0.0.2"];
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        lb.set_health_check(Arc::new(FailingHealthCheck("1.0.0.2".to_string())));
+        let mut events = lb.subscribe_health_events();
+
+        // Only the flip is an event, not the checks confirming the health
+        lb.run_health_check().await;
+        lb.run_health_check().await;
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.backend.addr, "1.0.0.2");
+        assert!(!event.healthy);
+        assert_eq!(event.error.as_deref(), Some("1.0.0.2 is down"));
+        assert!(events.try_recv().is_err());
+
+        let backend = event.backend;
+        lb.set_backend_health_check(&backend, Arc::new(FailingHealthCheck(String::new())));
+        lb.run_health_check().await;
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.backend, backend);
+        assert!(event.healthy);
+        assert_eq!(event.error, None);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_lb_selection_policy() {
+        let backends = vec!["1.0.0.3", "1.0.0.1", "1.0.0.4", "1.0.0.2"];
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        lb.set_selection_policy(|backends, context| {
+            (0..backends.len())
+                .filter(|&i| context.is_healthy(i))
+                .max_by(|&a, &b| backends[a].addr.cmp(&backends[b].addr))
+        });
+
+        let highest = lb.next().unwrap();
+        assert_eq!(highest.addr, "1.0.0.4");
+        assert_eq!(lb.select_key(b"key").unwrap().addr, "1.0.0.4");
+        lb.mark_unhealthy(&highest);
+        assert_eq!(lb.next().unwrap().addr, "1.0.0.3");
+        assert_eq!(lb.selection_counts()["1.0.0.4"], 2);
+
+        lb.set_selection_policy(|_, _| None);
+        assert_eq!(
+            lb.select_with_reason(10).unwrap_err(),
+            SelectError::ExhaustedIterations
+        );
+
+        lb.clear_selection_policy();
+        assert!(lb.next().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_lb_health_status() {
+        let backends = vec!["1.0.0.1", "1.0.0.2"];
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&backends).unwrap();
+        lb.set_health_check(Arc::new(FailingHealthCheck("1.0.0.2".to_string())));
+        let up = Backend::new("1.0.0.1".to_string());
+        let down = Backend::new("1.0.0.2".to_string());
+        let added = lb.health_status(&down).unwrap().last_flip;
+
+        lb.run_health_check().await;
+        lb.run_health_check().await;
+        let status = lb.health_status(&down).unwrap();
+        assert!(!status.healthy);
+        assert!(status.last_flip > added);
+        assert_eq!(status.consecutive_failures, 2);
+        assert_eq!(status.last_error.as_deref(), Some("1.0.0.2 is down"));
+        let status = lb.health_status(&up).unwrap();
+        assert!(status.healthy);
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!(status.last_error, None);
+
+        // The reason is kept once the backend recovers
+        lb.set_backend_health_check(&down, Arc::new(FailingHealthCheck(String::new())));
+        lb.run_health_check().await;
+        let status = lb.health_status(&down).unwrap();
+        assert!(status.healthy);
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!(status.last_error.as_deref(), Some("1.0.0.2 is down"));
+
+        assert!(lb
+            .health_status(&Backend::new("1.0.0.3".to_string()))
+            .is_none());
+    }
+
+    #[derive(Debug)]
+    struct SlowHealthCheck(Duration);
+
+    #[async_trait::async_trait]
+    impl HealthCheck for SlowHealthCheck {
+        async fn check(&self, _target: &Backend) -> anyhow::Result<()> {
+            tokio::time::sleep(self.0).await;
+            Ok(())
+        }
+
+        fn health_threshold(&self, _success: bool) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lb_health_check_latency() {
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_vec(&["1.0.0.1"]).unwrap();
+        let backend = Backend::new("1.0.0.1".to_string());
+        assert_eq!(lb.health_status(&backend).unwrap().check_latency, None);
+
+        lb.set_health_check(Arc::new(SlowHealthCheck(Duration::from_millis(50))));
+        lb.run_health_check().await;
+        let status = lb.health_status(&backend).unwrap();
+        let latency = status.check_latency.unwrap();
+        assert!(latency >= Duration::from_millis(50), "{latency:?}");
+        assert!(latency < Duration::from_millis(500), "{latency:?}");
+        assert_eq!(status.check_latency_ewma, Some(latency));
+
+        lb.set_health_check(Arc::new(SlowHealthCheck(Duration::ZERO)));
+        lb.run_health_check().await;
+        let status = lb.health_status(&backend).unwrap();
+        let average = status.check_latency_ewma.unwrap();
+        assert!(status.check_latency.unwrap() < Duration::from_millis(50));
+        assert!(
+            average < latency && average >= latency.mul_f64(0.7),
+            "{average:?}"
+        );
+    }
 }
@@ -1,11 +1,53 @@
 use super::Backend;
 use rand::prelude::*;
+use rand::rngs::StdRng;
 use rand_distr::WeightedAliasIndex;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
 
 pub trait Strategy {
-    fn build(backends: &[Backend]) -> Self;
+    fn build(backends: &[Backend]) -> Self
+    where
+        Self: Sized;
     fn get_next(&self) -> Option<&Backend>;
+
+    /// Select a backend for the given `key`.
+    ///
+    /// Stateless strategies ignore the key, which is the default. Hash based strategies use it to
+    /// pick the same backend for the same key, and their [Strategy::get_next] is `select(None)`.
+    fn select(&self, _key: Option<&[u8]>) -> Option<&Backend> {
+        self.get_next()
+    }
+
+    /// The number of consecutive selections within which every selectable backend is selected
+    /// at least once, used to size the attempts to find a healthy backend.
+    ///
+    /// `None` for strategies without such a guarantee, which get as many attempts as there are
+    /// backends.
+    fn fairness_period(&self) -> Option<usize> {
+        None
+    }
+
+    /// The hash ring of key-based strategies, to inspect where the keys currently go.
+    ///
+    /// `None` for the strategies which ignore the key.
+    fn hash_ring(&self) -> Option<Vec<RingNode>> {
+        None
+    }
+}
+
+/// A virtual node of a hash ring, which owns the keys whose [ConsistentHash::key_point] is in
+/// between the point of the previous node and its own, wrapping around.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RingNode {
+    pub point: u64,
+    pub addr: String,
 }
 
 #[derive(Debug)]
@@ -32,15 +74,48 @@ impl Strategy for RoundRobin {
     }
 }
 
+/// The source of randomness of the random strategies.
+///
+/// The thread local generator seeded from entropy is used by default, a seeded one makes the
+/// selections reproducible.
+#[derive(Debug, Default)]
+struct StrategyRng(Option<Mutex<StdRng>>);
+
+impl StrategyRng {
+    fn seeded(seed: u64) -> Self {
+        Self(Some(Mutex::new(StdRng::seed_from_u64(seed))))
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        match &self.0 {
+            Some(rng) => f(&mut *rng.lock().unwrap()),
+            None => f(&mut rand::thread_rng()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Random {
     backends: Vec<Backend>,
+    rng: StrategyRng,
+}
+
+impl Random {
+    /// Build the strategy with a deterministic generator, the same `seed` giving the same
+    /// sequence of selections.
+    pub fn build_with_seed(backends: &[Backend], seed: u64) -> Self {
+        Self {
+            backends: backends.to_vec(),
+            rng: StrategyRng::seeded(seed),
+        }
+    }
 }
 
 impl Strategy for Random {
     fn build(backends: &[Backend]) -> Self {
         Self {
             backends: backends.to_vec(),
+            rng: StrategyRng::default(),
         }
     }
 
@@ -49,11 +124,71 @@ impl Strategy for Random {
             return None;
         }
 
-        let idx = rand::random::<usize>() % self.backends.len();
+        let idx = self.rng.with(|rng| rng.gen_range(0..self.backends.len()));
         Some(&self.backends[idx])
     }
 }
 
+/// What to do with weights failing [WeightValidation]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WeightPolicy {
+    Ignore,
+    /// Log the problem and build the strategy anyway
+    #[default]
+    Warn,
+    /// Refuse to build the strategy
+    Error,
+}
+
+/// Sanity checks of the weights, to catch configuration mistakes when building a weighted
+/// strategy with `try_build`, or a [LoadBalancer](super::LoadBalancer) with
+/// [LoadBalancerBuilder::weight_validation](super::LoadBalancerBuilder::weight_validation).
+#[derive(Clone, Debug)]
+pub struct WeightValidation {
+    /// How many times the largest weight may be the smallest non zero one, beyond which the
+    /// lighter backends are effectively starved
+    pub max_ratio: u16,
+    pub policy: WeightPolicy,
+}
+
+impl Default for WeightValidation {
+    fn default() -> Self {
+        Self {
+            max_ratio: 1000,
+            policy: WeightPolicy::default(),
+        }
+    }
+}
+
+impl WeightValidation {
+    /// The problem with the weights of `backends`, if any
+    fn problem(&self, backends: &[Backend]) -> Option<String> {
+        let heaviest = backends.iter().max_by_key(|b| b.weight)?;
+        let lightest = backends
+            .iter()
+            .filter(|b| b.weight > 0)
+            .min_by_key(|b| b.weight)?;
+        if heaviest.weight / lightest.weight <= self.max_ratio {
+            return None;
+        }
+        Some(format!(
+            "the weight of {} ({}) starves {} ({}), over {} times as heavy",
+            heaviest.addr, heaviest.weight, lightest.addr, lightest.weight, self.max_ratio
+        ))
+    }
+
+    pub(crate) fn check(&self, backends: &[Backend]) -> anyhow::Result<()> {
+        match (self.policy, self.problem(backends)) {
+            (WeightPolicy::Error, Some(problem)) => Err(anyhow::anyhow!(problem)),
+            (WeightPolicy::Warn, Some(problem)) => {
+                tracing::warn!("{problem}");
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct WeightedRoundRobin {
     backends: Vec<Backend>,
@@ -62,6 +197,12 @@ pub struct WeightedRoundRobin {
 }
 
 impl WeightedRoundRobin {
+    /// Build the strategy once the weights pass `validation`.
+    pub fn try_build(backends: &[Backend], validation: &WeightValidation) -> anyhow::Result<Self> {
+        validation.check(backends)?;
+        Ok(Self::build(backends))
+    }
+
     fn compute_weighted(backends: &[Backend]) -> Vec<usize> {
         let mut weights = Vec::new();
         let mut max_weight = 0;
@@ -72,6 +213,11 @@ impl WeightedRoundRobin {
             gcd = num_integer::gcd(gcd, backend.weight);
         }
 
+        if max_weight == 0 {
+            // Every backend is drained, nothing to select
+            return Vec::new();
+        }
+
         if weights.iter().all(|&x| x == max_weight) {
             return (0..backends.len()).collect();
         }
@@ -110,36 +256,250 @@ impl Strategy for WeightedRoundRobin {
     }
 
     fn get_next(&self) -> Option<&Backend> {
-        if self.backends.is_empty() {
+        if self.weighted.is_empty() {
             return None;
         }
         let index = self.current_index.fetch_add(1, Ordering::Relaxed);
         Some(&self.backends[self.weighted[index % self.weighted.len()]])
     }
+
+    fn fairness_period(&self) -> Option<usize> {
+        Some(self.weighted.len())
+    }
+}
+
+/// How [WeightedRandom] picks the index of a backend
+#[derive(Debug)]
+enum Weights {
+    /// Nothing is selectable: no backends, or all of them with a weight of 0
+    Empty,
+    Alias(WeightedAliasIndex<u32>),
+    /// The weights cannot be represented by the alias table, select uniformly instead
+    Uniform,
+}
+
+impl Weights {
+    fn new(backends: &[Backend]) -> Self {
+        if backends.iter().all(|b| b.weight == 0) {
+            return Self::Empty;
+        }
+
+        // The alias table needs every weight times the number of backends to fit in the weight
+        // type, widen them so that large pools and weights fit.
+        let weights = backends.iter().map(|b| u32::from(b.weight)).collect();
+        match WeightedAliasIndex::new(weights) {
+            Ok(weights) => Self::Alias(weights),
+            Err(_) => Self::Uniform,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct WeightedRandom {
     backends: Vec<Backend>,
-    weights: WeightedAliasIndex<u16>,
+    weights: Weights,
+    rng: StrategyRng,
+}
+
+impl WeightedRandom {
+    /// Build the strategy once the weights pass `validation`.
+    pub fn try_build(backends: &[Backend], validation: &WeightValidation) -> anyhow::Result<Self> {
+        validation.check(backends)?;
+        Ok(Self::build(backends))
+    }
+
+    /// Build the strategy with a deterministic generator, the same `seed` giving the same
+    /// sequence of selections.
+    pub fn build_with_seed(backends: &[Backend], seed: u64) -> Self {
+        Self {
+            rng: StrategyRng::seeded(seed),
+            ..Self::build(backends)
+        }
+    }
 }
 
 impl Strategy for WeightedRandom {
     fn build(backends: &[Backend]) -> Self {
-        let weights = backends.iter().map(|b| b.weight).collect();
         Self {
             backends: backends.to_vec(),
-            weights: WeightedAliasIndex::new(weights).unwrap(),
+            weights: Weights::new(backends),
+            rng: StrategyRng::default(),
         }
     }
 
     fn get_next(&self) -> Option<&Backend> {
-        if self.backends.is_empty() {
+        let idx = match &self.weights {
+            Weights::Empty => return None,
+            Weights::Alias(weights) => self.rng.with(|rng| weights.sample(rng)),
+            Weights::Uniform => self.rng.with(|rng| rng.gen_range(0..self.backends.len())),
+        };
+        Some(&self.backends[idx])
+    }
+}
+
+/// Weighted random selection without replacement, a randomized smooth weighted round-robin.
+///
+/// Each cycle selects every backend as many times as its weight, reduced by the gcd of the
+/// weights, in a random order which spreads the selections of a backend evenly over the cycle.
+#[derive(Debug)]
+pub struct WeightedShuffle {
+    backends: Vec<Backend>,
+    /// The number of selections of each backend per cycle
+    counts: Vec<u32>,
+    /// The backend indices of the current cycle and the position in it
+    cycle: Mutex<(Vec<usize>, usize)>,
+    rng: StrategyRng,
+}
+
+impl WeightedShuffle {
+    /// Build the strategy once the weights pass `validation`.
+    pub fn try_build(backends: &[Backend], validation: &WeightValidation) -> anyhow::Result<Self> {
+        validation.check(backends)?;
+        Ok(Self::build(backends))
+    }
+
+    /// Build the strategy with a deterministic generator, the same `seed` giving the same
+    /// sequence of selections.
+    pub fn build_with_seed(backends: &[Backend], seed: u64) -> Self {
+        Self {
+            rng: StrategyRng::seeded(seed),
+            ..Self::build(backends)
+        }
+    }
+
+    fn new_cycle(&self) -> Vec<usize> {
+        let len: u32 = self.counts.iter().sum();
+        let mut slots = Vec::with_capacity(len as usize);
+        self.rng.with(|rng| {
+            // Each backend gets evenly spaced slots over the cycle, starting at a random phase
+            for (index, &count) in self.counts.iter().enumerate().filter(|(_, &c)| c > 0) {
+                let stride = f64::from(len) / f64::from(count);
+                let phase: f64 = rng.gen();
+                for slot in 0..count {
+                    slots.push(((f64::from(slot) + phase) * stride, index));
+                }
+            }
+        });
+        slots.sort_by(|a, b| a.0.total_cmp(&b.0));
+        slots.into_iter().map(|(_, index)| index).collect()
+    }
+}
+
+impl Strategy for WeightedShuffle {
+    fn build(backends: &[Backend]) -> Self {
+        let gcd = backends
+            .iter()
+            .fold(0, |gcd, b| num_integer::gcd(gcd, u32::from(b.weight)));
+        let counts = backends
+            .iter()
+            .map(|b| u32::from(b.weight).checked_div(gcd).unwrap_or(0))
+            .collect();
+
+        Self {
+            backends: backends.to_vec(),
+            counts,
+            cycle: Mutex::new((Vec::new(), 0)),
+            rng: StrategyRng::default(),
+        }
+    }
+
+    fn get_next(&self) -> Option<&Backend> {
+        let mut cycle = self.cycle.lock().unwrap();
+        let (indices, position) = &mut *cycle;
+        if *position >= indices.len() {
+            *indices = self.new_cycle();
+            *position = 0;
+        }
+        // Empty when every backend has a weight of 0
+        let index = *indices.get(*position)?;
+        *position += 1;
+        Some(&self.backends[index])
+    }
+
+    fn fairness_period(&self) -> Option<usize> {
+        // Starting mid-cycle, the rest of the cycle may miss a backend but the next one won't
+        let len: u32 = self.counts.iter().sum();
+        Some(2 * len as usize)
+    }
+}
+
+/// Consistent hashing over a ring of virtual nodes, as many per backend as its weight.
+///
+/// The same key maps to the same backend, and only the keys of a removed backend move elsewhere.
+/// Passing `None` as the key falls back to round-robin over the backends with a weight.
+/// Nothing is selected
+/// when every backend has a weight of 0.
+#[derive(Debug)]
+pub struct ConsistentHash {
+    backends: Vec<Backend>,
+    /// The points of the virtual nodes and the index of their backend, sorted by point
+    ring: Vec<(u64, usize)>,
+    /// The indexes of the backends with a weight, to round-robin over without a key
+    weighted: Vec<usize>,
+    current: AtomicUsize,
+}
+
+impl ConsistentHash {
+    fn hash<H: Hash + ?Sized>(value: &H) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Where `key` falls on the ring, see [RingNode]
+    pub fn key_point(key: &[u8]) -> u64 {
+        Self::hash(key)
+    }
+}
+
+impl Strategy for ConsistentHash {
+    fn build(backends: &[Backend]) -> Self {
+        let mut ring = Vec::new();
+        for (index, backend) in backends.iter().enumerate() {
+            for node in 0..backend.weight {
+                ring.push((Self::hash(&(&backend.addr, node)), index));
+            }
+        }
+        ring.sort_unstable();
+        let weighted = (0..backends.len())
+            .filter(|&index| backends[index].weight > 0)
+            .collect();
+
+        Self {
+            backends: backends.to_vec(),
+            ring,
+            weighted,
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    fn get_next(&self) -> Option<&Backend> {
+        self.select(None)
+    }
+
+    fn select(&self, key: Option<&[u8]>) -> Option<&Backend> {
+        if self.ring.is_empty() {
             return None;
         }
 
-        let idx = self.weights.sample(&mut rand::thread_rng());
-        Some(&self.backends[idx])
+        let Some(key) = key else {
+            let idx = self.current.fetch_add(1, Ordering::Relaxed);
+            return Some(&self.backends[self.weighted[idx % self.weighted.len()]]);
+        };
+
+        let point = Self::key_point(key);
+        // The first virtual node clockwise from the point, wrapping around the ring
+        let node = self.ring.partition_point(|&(p, _)| p < point);
+        let (_, index) = self.ring.get(node).or_else(|| self.ring.first())?;
+        Some(&self.backends[*index])
+    }
+
+    fn hash_ring(&self) -> Option<Vec<RingNode>> {
+        let ring = self.ring.iter().map(|&(point, index)| RingNode {
+            point,
+            addr: self.backends[index].addr.clone(),
+        });
+        Some(ring.collect())
     }
 }
 
@@ -280,4 +640,211 @@ mod tests {
         assert!((15..=35).contains(count.get("1.0.0.2").unwrap())); // 25% chance
         assert!((40..=60).contains(count.get("1.0.0.3").unwrap())); // 50% chance
     }
+
+    #[test]
+    fn test_consistent_hash() {
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()),
+            Backend::new("1.0.0.2".to_string()),
+            Backend::new("1.0.0.3".to_string()),
+        ];
+        let strategy = ConsistentHash::build(&backends);
+
+        // The same key always maps to the same backend
+        let selected = strategy.select(Some(b"client-1")).unwrap();
+        for _ in 0..10 {
+            assert_eq!(strategy.select(Some(b"client-1")).unwrap(), selected);
+        }
+
+        // Keys are spread across every backend
+        let mut seen = HashMap::new();
+        for i in 0..300 {
+            let backend = strategy.select(Some(format!("client-{i}").as_bytes()));
+            *seen.entry(backend.unwrap().addr.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(seen.len(), 3);
+
+        // Removing a backend only moves its own keys
+        let strategy2 = ConsistentHash::build(&backends[..2]);
+        for i in 0..300 {
+            let key = format!("client-{i}");
+            let before = strategy.select(Some(key.as_bytes())).unwrap();
+            if before.addr != "1.0.0.3" {
+                assert_eq!(strategy2.select(Some(key.as_bytes())).unwrap(), before);
+            }
+        }
+
+        // Without a key it falls back to round-robin
+        assert_eq!(strategy.get_next().unwrap().addr, "1.0.0.1");
+        assert_eq!(strategy.select(None).unwrap().addr, "1.0.0.2");
+        assert_eq!(strategy.get_next().unwrap().addr, "1.0.0.3");
+
+        // Without a key the drained backends are skipped too
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()).with_weight(0),
+            Backend::new("1.0.0.2".to_string()),
+        ];
+        let strategy = ConsistentHash::build(&backends);
+        for _ in 0..10 {
+            assert_eq!(strategy.get_next().unwrap().addr, "1.0.0.2");
+        }
+    }
+
+    #[test]
+    fn test_random_with_seed() {
+        let backends: Vec<Backend> = (1..=5)
+            .map(|i| Backend::new(format!("1.0.0.{i}")).with_weight(i * 10))
+            .collect();
+
+        let random1 = Random::build_with_seed(&backends, 42);
+        let random2 = Random::build_with_seed(&backends, 42);
+        let weighted1 = WeightedRandom::build_with_seed(&backends, 42);
+        let weighted2 = WeightedRandom::build_with_seed(&backends, 42);
+        for _ in 0..100 {
+            assert_eq!(random1.get_next(), random2.get_next());
+            assert_eq!(weighted1.get_next(), weighted2.get_next());
+        }
+    }
+
+    #[test]
+    fn test_weighted_all_zero() {
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()).with_weight(0),
+            Backend::new("1.0.0.2".to_string()).with_weight(0),
+        ];
+        assert!(WeightedRoundRobin::build(&backends).get_next().is_none());
+        assert!(WeightedRandom::build(&backends).get_next().is_none());
+        assert!(WeightedShuffle::build(&backends).get_next().is_none());
+        let strategy = ConsistentHash::build(&backends);
+        assert!(strategy.get_next().is_none());
+        assert!(strategy.select(Some(b"key")).is_none());
+
+        // Drained backends are skipped while others are selectable
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()).with_weight(0),
+            Backend::new("1.0.0.2".to_string()),
+            Backend::new("1.0.0.3".to_string()).with_weight(0),
+        ];
+        let strategies: Vec<Box<dyn Strategy>> = vec![
+            Box::new(WeightedRoundRobin::build(&backends)),
+            Box::new(WeightedRandom::build(&backends)),
+            Box::new(WeightedShuffle::build(&backends)),
+            Box::new(ConsistentHash::build(&backends)),
+        ];
+        for strategy in &strategies {
+            for i in 0..30 {
+                assert_eq!(strategy.get_next().unwrap().addr, "1.0.0.2");
+                let key = format!("client-{i}");
+                assert_eq!(
+                    strategy.select(Some(key.as_bytes())).unwrap().addr,
+                    "1.0.0.2"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_uniform() {
+        let backends: Vec<Backend> = (1..=7)
+            .map(|i| Backend::new(format!("1.0.0.{i}")))
+            .collect();
+        let strategy = Random::build_with_seed(&backends, 7);
+        let mut count: HashMap<String, u32> = HashMap::new();
+        for _ in 0..70_000 {
+            let backend = strategy.get_next().unwrap();
+            *count.entry(backend.addr.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(count.len(), 7);
+        // 10_000 expected each, the standard deviation is around 93
+        assert!(
+            count.values().all(|c| (9_500..=10_500).contains(c)),
+            "{count:?}"
+        );
+    }
+
+    #[test]
+    fn test_weighted_random_degenerate_weights() {
+        // Nothing to select, but no panic either
+        assert!(WeightedRandom::build(&[]).get_next().is_none());
+        let backends = vec![Backend::new("1.0.0.1".to_string()).with_weight(0)];
+        assert!(WeightedRandom::build(&backends).get_next().is_none());
+
+        // Weights which sum over u16::MAX
+        let backends: Vec<Backend> = (0..1000)
+            .map(|i| Backend::new(format!("1.0.{}.{}", i / 256, i % 256)))
+            .collect();
+        assert!(WeightedRandom::build(&backends).get_next().is_some());
+
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()).with_weight(u16::MAX),
+            Backend::new("1.0.0.2".to_string()).with_weight(u16::MAX),
+        ];
+        let strategy = WeightedRandom::build_with_seed(&backends, 1);
+        let mut seen = HashMap::new();
+        for _ in 0..100 {
+            *seen
+                .entry(strategy.get_next().unwrap().addr.clone())
+                .or_insert(0) += 1;
+        }
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn test_weighted_shuffle() {
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()),
+            Backend::new("1.0.0.2".to_string()).with_weight(200),
+            Backend::new("1.0.0.3".to_string()).with_weight(300),
+        ];
+        let strategy = WeightedShuffle::build_with_seed(&backends, 7);
+        for _ in 0..1000 {
+            let cycle: Vec<String> = (0..6)
+                .map(|_| strategy.get_next().unwrap().addr.clone())
+                .collect();
+            let count = |addr: &str| cycle.iter().filter(|a| *a == addr).count();
+            assert_eq!(count("1.0.0.1"), 1, "{cycle:?}");
+            assert_eq!(count("1.0.0.2"), 2, "{cycle:?}");
+            assert_eq!(count("1.0.0.3"), 3, "{cycle:?}");
+            // The selections of a backend are spread out
+            let runs = cycle.windows(3).filter(|w| w[0] == w[1] && w[1] == w[2]);
+            assert_eq!(runs.count(), 0, "{cycle:?}");
+        }
+
+        let backends = vec![Backend::new("1.0.0.1".to_string()).with_weight(0)];
+        assert!(WeightedShuffle::build(&backends).get_next().is_none());
+        assert!(WeightedShuffle::build(&[]).get_next().is_none());
+    }
+
+    #[test]
+    fn test_weight_validation() {
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()).with_weight(65000),
+            Backend::new("1.0.0.2".to_string()).with_weight(1),
+            Backend::new("1.0.0.3".to_string()).with_weight(0),
+        ];
+        let mut validation = WeightValidation {
+            policy: WeightPolicy::Error,
+            ..Default::default()
+        };
+        let err = WeightedRoundRobin::try_build(&backends, &validation).unwrap_err();
+        assert!(err.to_string().contains("1.0.0.2"), "{err}");
+        assert!(WeightedRandom::try_build(&backends, &validation).is_err());
+        assert!(WeightedShuffle::try_build(&backends, &validation).is_err());
+
+        // Warnings still build the strategy
+        validation.policy = WeightPolicy::Warn;
+        assert!(validation.problem(&backends).is_some());
+        assert!(WeightedRoundRobin::try_build(&backends, &validation).is_ok());
+
+        // A sane spread, drained backends aside
+        validation.policy = WeightPolicy::Error;
+        let backends = vec![
+            Backend::new("1.0.0.1".to_string()).with_weight(1000),
+            Backend::new("1.0.0.2".to_string()).with_weight(1),
+            Backend::new("1.0.0.3".to_string()).with_weight(0),
+        ];
+        assert!(validation.problem(&backends).is_none());
+        assert!(WeightedRandom::try_build(&backends, &validation).is_ok());
+        assert!(WeightedRandom::try_build(&[], &validation).is_ok());
+    }
 }
@@ -0,0 +1,79 @@
+//! Prometheus metrics of the proxied requests, rendered for a `/metrics` endpoint by [render]
+//!
+//! They are registered in the default registry of the `prometheus` crate, so that
+//! `prometheus::gather` returns them along with the metrics of the application.
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use hyper::{StatusCode, Uri};
+use prometheus::{
+    register_histogram, register_int_counter_vec, Encoder, Histogram, IntCounterVec, TextEncoder,
+};
+
+static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "yapf_requests_total",
+        "Requests proxied, by the class of their response status",
+        &["status"]
+    )
+    .unwrap()
+});
+
+static REQUEST_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "yapf_request_duration_seconds",
+        "Time to respond to the requests, upstream included"
+    )
+    .unwrap()
+});
+
+static CONNECT_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "yapf_upstream_connect_failures_total",
+        "Requests which couldn't be sent upstream, by upstream",
+        &["upstream"]
+    )
+    .unwrap()
+});
+
+/// Count a request answered with `status` after `duration`
+pub(crate) fn record_request(status: StatusCode, duration: Duration) {
+    let class = format!("{}xx", status.as_u16() / 100);
+    REQUESTS.with_label_values(&[&class]).inc();
+    REQUEST_DURATION.observe(duration.as_secs_f64());
+}
+
+/// Count a request which couldn't be sent to `upstream`
+pub(crate) fn record_connect_failure(upstream: &Uri) {
+    CONNECT_FAILURES
+        .with_label_values(&[&upstream_label(upstream)])
+        .inc();
+}
+
+fn upstream_label(upstream: &Uri) -> String {
+    match upstream.authority() {
+        Some(authority) => authority.to_string(),
+        None => upstream.to_string(),
+    }
+}
+
+/// The metrics of the default prometheus registry, in the Prometheus text exposition format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+#[cfg(test)]
+pub(crate) fn requests(class: &str) -> u64 {
+    REQUESTS.with_label_values(&[class]).get()
+}
+
+#[cfg(test)]
+pub(crate) fn connect_failures(upstream: &Uri) -> u64 {
+    CONNECT_FAILURES
+        .with_label_values(&[&upstream_label(upstream)])
+        .get()
+}
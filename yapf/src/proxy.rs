@@ -1,170 +1,3537 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
-use std::sync::Arc;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
 
+#[cfg(any(test, feature = "pingora-core"))]
 use async_trait::async_trait;
-use http_body_util::Either;
-use hyper::body::Incoming as IncomingRequest;
+use http_body_util::{BodyExt, Either, LengthLimitError, Limited};
+use hyper::body::{Bytes, Frame, Incoming as IncomingRequest, SizeHint};
 use hyper::{
-    http::status::StatusCode, server::conn::http1, service::service_fn, Request, Response,
+    header::{
+        HeaderMap, HeaderValue, CONNECTION, CONTENT_LENGTH, HOST, TE, TRAILER, TRANSFER_ENCODING,
+    },
+    http::{status::StatusCode, uri::Scheme},
+    rt::{Read, ReadBufCursor, Write},
+    server::conn::{http1, http2},
+    service::service_fn,
+    upgrade::OnUpgrade,
+    Request, Response, Uri, Version,
 };
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
-use hyper_util::client::legacy::{connect::HttpConnector, Client};
-use hyper_util::rt::{TokioExecutor, TokioIo};
-use tokio::time::Instant;
+use hyper_util::client::legacy::{
+    connect::{Connected, Connection, HttpConnector},
+    Client,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixListener;
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
 
 #[cfg(feature = "pingora-core")]
 use pingora_core::{
-    apps::ServerApp, protocols::Stream, server::ShutdownWatch, services::listening::Service,
+    apps::ServerApp,
+    protocols::{Stream, ALPN},
+    server::ShutdownWatch,
+    services::listening::Service,
 };
 
+use crate::compression::{compress, CompressionConfig};
 use crate::proxy_trait::Proxy as ProxyTrait;
-use crate::proxy_trait::{empty_body, Body};
+use crate::proxy_trait::{
+    empty_body, full_body, Body, BoxError, ClientAddr, FailedRequestInfo, StreamedBody,
+    UpstreamConnection, UpstreamLatency,
+};
 
-pub struct ProxyService<P> {
-    inner: P,
-    upstream: Client<HttpsConnector<HttpConnector>, IncomingRequest>,
+/// The default of [ProxyService::set_max_request_trailers_size]
+const MAX_REQUEST_TRAILERS_SIZE: usize = 8 * 1024;
+
+/// The default of [ProxyService::set_max_attempts]
+const MAX_ATTEMPTS: usize = 3;
+
+/// The upstream attempts left for a downstream request, shared by everything which sends it
+/// upstream so that one request can't hit the upstreams more than the budget.
+#[derive(Debug)]
+struct AttemptBudget {
+    left: usize,
 }
 
-impl<P> ProxyService<P> {
-    fn new(inner: P) -> Arc<Self> {
-        let https = HttpsConnectorBuilder::new()
-            .with_native_roots()
-            .unwrap()
-            .https_or_http()
-            .enable_http1()
-            .build();
-
-        // TODO: Add pingora executor
-        let client = Client::builder(TokioExecutor::new()).build(https);
-        Arc::new(Self {
+impl AttemptBudget {
+    fn new(max_attempts: usize) -> Self {
+        Self { left: max_attempts }
+    }
+
+    /// Whether an attempt is left
+    fn has_left(&self) -> bool {
+        self.left > 0
+    }
+
+    /// Take an attempt, checked with [AttemptBudget::has_left] before any retry
+    fn spend(&mut self) {
+        self.left = self.left.saturating_sub(1);
+    }
+}
+
+/// The default of [ProxyService::set_max_request_body_size]
+const MAX_REQUEST_BODY_SIZE: usize = 1024 * 1024;
+
+/// The default of [ProxyService::set_max_response_body_size]
+const MAX_RESPONSE_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+/// A downstream request body on its way to the upstream, with its size and trailers bounded.
+///
+/// A failure to read it is recorded with the status to answer, `400` or `413`, rather than
+/// blamed on the upstream.
+struct RequestBody {
+    inner: RequestBodyInner,
+    max_trailers_size: usize,
+    /// The bytes it may still send, see [ProxyService::set_max_request_body_bytes]
+    remaining: Option<usize>,
+    failed: Arc<OnceLock<StatusCode>>,
+}
+
+/// The downstream request body, streamed as it is received or buffered for
+/// [ProxyTrait::request_body_filter]
+enum RequestBodyInner {
+    Streaming(IncomingRequest),
+    Buffered {
+        data: Option<Bytes>,
+        trailers: Option<HeaderMap>,
+    },
+}
+
+impl RequestBodyInner {
+    /// A copy of the body to send it again, if it was buffered or has nothing to send
+    fn replay(&self) -> Option<Self> {
+        match self {
+            Self::Buffered { data, trailers } => Some(Self::Buffered {
+                data: data.clone(),
+                trailers: trailers.clone(),
+            }),
+            Self::Streaming(body) if hyper::body::Body::is_end_stream(body) => {
+                Some(Self::Buffered {
+                    data: None,
+                    trailers: None,
+                })
+            }
+            Self::Streaming(_) => None,
+        }
+    }
+}
+
+impl hyper::body::Body for RequestBody {
+    type Data = Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = match &mut this.inner {
+            RequestBodyInner::Streaming(inner) => match ready!(Pin::new(inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => {
+                    let _ = this.failed.set(StatusCode::BAD_REQUEST);
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+                None => return Poll::Ready(None),
+            },
+            RequestBodyInner::Buffered { data, trailers } => {
+                match data.take().filter(|data| !data.is_empty()) {
+                    Some(data) => Frame::data(data),
+                    None => match trailers.take() {
+                        Some(trailers) => Frame::trailers(trailers),
+                        None => return Poll::Ready(None),
+                    },
+                }
+            }
+        };
+
+        if let (Some(data), Some(remaining)) = (frame.data_ref(), &mut this.remaining) {
+            match remaining.checked_sub(data.len()) {
+                Some(left) => *remaining = left,
+                None => {
+                    let _ = this.failed.set(StatusCode::PAYLOAD_TOO_LARGE);
+                    return Poll::Ready(Some(Err("request body over the limit".into())));
+                }
+            }
+        }
+        if let Some(trailers) = frame.trailers_ref() {
+            let size: usize = trailers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum();
+            if size > this.max_trailers_size {
+                let _ = this.failed.set(StatusCode::BAD_REQUEST);
+                return Poll::Ready(Some(Err(format!(
+                    "request trailers of {size} bytes over the limit of {}",
+                    this.max_trailers_size
+                )
+                .into())));
+            }
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match &self.inner {
+            RequestBodyInner::Streaming(inner) => inner.is_end_stream(),
+            RequestBodyInner::Buffered { data, trailers } => {
+                data.as_ref().is_none_or(Bytes::is_empty) && trailers.is_none()
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match &self.inner {
+            RequestBodyInner::Streaming(inner) => inner.size_hint(),
+            RequestBodyInner::Buffered { data, .. } => {
+                SizeHint::with_exact(data.as_ref().map_or(0, |data| data.len() as u64))
+            }
+        }
+    }
+}
+
+/// An upstream response body which fails once no frame arrived for `timeout`
+struct IdleTimeout<B> {
+    inner: B,
+    timeout: Duration,
+    idle: Pin<Box<time::Sleep>>,
+}
+
+impl<B> IdleTimeout<B> {
+    fn new(inner: B, timeout: Duration) -> Self {
+        Self {
             inner,
-            upstream: client,
-        })
+            timeout,
+            idle: Box::pin(time::sleep(timeout)),
+        }
     }
 }
 
-async fn process_request<P>(
-    proxy: Arc<ProxyService<P>>,
-    request: Request<IncomingRequest>,
-) -> Result<Response<Body>, Infallible>
+impl<B> hyper::body::Body for IdleTimeout<B>
 where
-    P: ProxyTrait + Send + Sync + 'static,
+    B: hyper::body::Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
 {
-    let mut ctx = proxy.inner.new_ctx();
-    let (mut parts, body) = request.into_parts();
+    type Data = Bytes;
+    type Error = BoxError;
 
-    // Run the request filter
-    match proxy.inner.request_filter(&parts, &mut ctx).await {
-        Ok(()) => {}
-        Err(response) => return Ok(response),
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                // The upstream is making progress, give it another `timeout`
+                let deadline = Instant::now() + this.timeout;
+                this.idle.as_mut().reset(deadline);
+                Poll::Ready(frame.map(|frame| frame.map_err(Into::into)))
+            }
+            Poll::Pending => {
+                ready!(this.idle.as_mut().poll(cx));
+                let error = format!("no upstream body received for {:?}", this.timeout);
+                Poll::Ready(Some(Err(error.into())))
+            }
+        }
     }
 
-    // TODO: Request body filter? How do we make it opt in? So we dont alwasy have to read the body
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
 
-    // Get the upstream address
-    let Some(upstream_addr) = proxy.inner.upstream_addr(&parts, &mut ctx).await else {
-        return Ok(Response::builder()
-            .status(StatusCode::SERVICE_UNAVAILABLE)
-            .body(empty_body())
-            .unwrap());
-    };
-    let upstream_addr_clone = upstream_addr.clone();
-    parts.uri = upstream_addr;
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
 
-    // Allow the user to modify the request before sending it to the upstream
-    proxy
-        .inner
-        .upstream_request_filter(&mut parts, &mut ctx)
-        .await;
+/// Buffer a downstream request body of up to `max_size` bytes, along with its trailers.
+///
+/// Fails with the status to answer, `413` for a body over the limit and `400` for a truncated
+/// one.
+async fn buffer_request_body(
+    body: IncomingRequest,
+    max_size: usize,
+) -> Result<(Bytes, Option<HeaderMap>), StatusCode> {
+    match Limited::new(body, max_size).collect().await {
+        Ok(collected) => {
+            let trailers = collected.trailers().cloned();
+            Ok((collected.to_bytes(), trailers))
+        }
+        Err(e) if e.is::<LengthLimitError>() => Err(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
 
-    // TODO: Do we allow the user to modify the request body before sending it to the upstream?
+/// The number of requests sent over an upstream connection, in the extensions of its responses
+#[derive(Clone, Debug, Default)]
+struct ConnectionUses(Arc<AtomicUsize>);
 
-    let request = Request::from_parts(parts, body);
+/// A transport connection to an upstream, see [ProxyService::with_connector]
+pub trait UpstreamIo: Read + Write + Connection + Unpin + Send {}
 
-    // Proxy the request to the upstream
-    let start = Instant::now();
-    let upstream_response = proxy.upstream.request(request).await;
-    let duration = start.elapsed();
+impl<T: Read + Write + Connection + Unpin + Send> UpstreamIo for T {}
 
-    let upstream_response = match upstream_response {
-        Ok(upstream_response) => upstream_response,
-        Err(err) => {
-            match proxy
-                .inner
-                .fail_to_connect(&mut ctx, &upstream_addr_clone, err)
-            {
-                Some(response) => return Ok(response),
-                None => {
-                    return Ok(Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(empty_body())
-                        .unwrap());
-                }
+/// Dials an upstream with the connector given to [ProxyService::with_connector]
+type Dial = Arc<
+    dyn Fn(Uri) -> Pin<Box<dyn Future<Output = Result<Box<dyn UpstreamIo>, BoxError>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// An upstream connection which tells its responses how many requests it was used for
+struct CountedStream {
+    inner: Box<dyn UpstreamIo>,
+    uses: ConnectionUses,
+}
+
+impl Connection for CountedStream {
+    fn connected(&self) -> Connected {
+        self.inner.connected().extra(self.uses.clone())
+    }
+}
+
+impl Read for CountedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl Write for CountedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+}
+
+/// Connects to the upstreams with connections counting their uses, see [UpstreamConnection]
+#[derive(Clone)]
+struct UpstreamConnector {
+    http: HttpConnector,
+    /// Dials the upstreams instead of `http`, if any
+    dial: Option<Dial>,
+    /// Refuse the `https` upstreams, see [UpstreamConfig::plaintext]
+    plaintext: bool,
+}
+
+impl tower_service::Service<Uri> for UpstreamConnector {
+    type Response = CountedStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<CountedStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        if self.plaintext && uri.scheme() == Some(&Scheme::HTTPS) {
+            return Box::pin(
+                async move { Err(format!("{uri} is not a plain text upstream").into()) },
+            );
+        }
+        let connecting = match &self.dial {
+            Some(dial) => dial(uri),
+            None => {
+                let connecting = self.http.call(uri);
+                Box::pin(async move {
+                    let io: Box<dyn UpstreamIo> = Box::new(connecting.await?);
+                    Ok(io)
+                })
+            }
+        };
+        Box::pin(async move {
+            Ok(CountedStream {
+                inner: connecting.await?,
+                uses: ConnectionUses::default(),
+            })
+        })
+    }
+}
+
+/// The HTTP versions spoken to the upstreams, see [UpstreamConfig::version]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UpstreamVersion {
+    #[default]
+    Http1,
+    /// HTTP/2 when the upstream picks it with ALPN over TLS, HTTP/1 otherwise
+    Negotiate,
+    /// HTTP/2 only, with prior knowledge over plain text
+    Http2,
+}
+
+/// How the upstream connections are made, see [ProxyService::set_upstream_config]
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamConfig {
+    pub version: UpstreamVersion,
+    /// The roots to verify the TLS upstreams with, the platform ones by default
+    pub roots: Option<rustls::RootCertStore>,
+    /// Only connect to `http` upstreams, no roots are loaded then
+    pub plaintext: bool,
+    /// The idle connections kept per upstream host, unbounded by default
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long an idle connection is kept, 90 seconds by default
+    pub pool_idle_timeout: Option<Duration>,
+}
+
+/// The TLS settings of the connection of a request to its upstream, in place of the ones for
+/// its uri when it is in the extensions of the request given to
+/// [ProxyTrait::upstream_request_filter], e.g. to dial an upstream by IP.
+///
+/// The requests with the same settings share their connections.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct UpstreamTls {
+    /// The name to send with SNI and to verify the certificate against, the host of the uri by
+    /// default
+    pub server_name: Option<String>,
+    /// Accept any certificate, e.g. a self-signed one
+    pub danger_accept_invalid_certs: bool,
+}
+
+/// A verifier of the upstream certificates accepting any of them, for
+/// [UpstreamTls::danger_accept_invalid_certs]. The handshake signatures are still verified.
+#[derive(Debug)]
+struct AcceptInvalidCerts(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for AcceptInvalidCerts {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.0.signature_verification_algorithms;
+        rustls::crypto::verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.0.signature_verification_algorithms;
+        rustls::crypto::verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// The roots of the platform certificate store, the ones it fails to read are skipped
+pub(crate) fn native_roots() -> rustls::RootCertStore {
+    let native = rustls_native_certs::load_native_certs();
+    for error in &native.errors {
+        tracing::warn!(error = %error, "failed to load native root certificates");
+    }
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_parsable_certificates(native.certs);
+    roots
+}
+
+/// The HTTP/1 options of the downstream connections, see [ProxyService::set_http1_config]
+#[derive(Clone, Debug)]
+pub struct Http1Config {
+    /// Serve more than one request per connection, `true` by default
+    pub keep_alive: bool,
+    /// Keep serving a connection after the client shut its write side, `false` by default
+    pub half_close: bool,
+    /// The size of the connection buffers, which bounds the request heads, at least 8 KiB and
+    /// ~400 KiB by default
+    pub max_buf_size: Option<usize>,
+    /// The number of headers of a request, 100 by default
+    pub max_headers: Option<usize>,
+}
+
+impl Default for Http1Config {
+    fn default() -> Self {
+        Self {
+            keep_alive: true,
+            half_close: false,
+            max_buf_size: None,
+            max_headers: None,
+        }
+    }
+}
+
+/// The number of shards of [ClientConnections], to spread the lock contention
+const CLIENT_SHARDS: usize = 16;
+
+/// The open connections of each client IP, to cap them
+#[derive(Debug)]
+struct ClientConnections {
+    max_per_client: Option<usize>,
+    shards: Vec<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ClientConnections {
+    fn new() -> Self {
+        Self {
+            max_per_client: None,
+            shards: (0..CLIENT_SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn shard(&self, client: &IpAddr) -> &Mutex<HashMap<IpAddr, usize>> {
+        let mut hasher = DefaultHasher::new();
+        client.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Count a new connection of `client`, `None` if it is over the limit
+    fn acquire(&self, client: IpAddr) -> Option<ClientConnection<'_>> {
+        let max = self.max_per_client?;
+        let mut connections = self.shard(&client).lock().unwrap();
+        let count = connections.entry(client).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ClientConnection {
+            connections: self,
+            client,
+        })
+    }
+}
+
+/// A connection counted by [ClientConnections], until dropped
+struct ClientConnection<'a> {
+    connections: &'a ClientConnections,
+    client: IpAddr,
+}
+
+impl Drop for ClientConnection<'_> {
+    fn drop(&mut self) {
+        let mut connections = self.connections.shard(&self.client).lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.client) {
+            *count -= 1;
+            // Forget the clients without connections, the map only holds the connected ones
+            if *count == 0 {
+                connections.remove(&self.client);
             }
         }
+    }
+}
+
+pub struct ProxyService<P> {
+    inner: P,
+    upstream: Client<HttpsConnector<UpstreamConnector>, RequestBody>,
+    upstream_config: UpstreamConfig,
+    dial: Option<Dial>,
+    /// The clients of the requests with their own [UpstreamTls]
+    tls_upstreams:
+        Mutex<HashMap<UpstreamTls, Client<HttpsConnector<UpstreamConnector>, RequestBody>>>,
+    lowercase_headers: bool,
+    http1: Http1Config,
+    http2: bool,
+    max_request_trailers_size: usize,
+    max_request_body_size: usize,
+    max_request_body_bytes: Option<usize>,
+    max_response_body_size: usize,
+    compression: Option<CompressionConfig>,
+    upstream_body_timeout: Option<Duration>,
+    max_attempts: usize,
+    client_connections: ClientConnections,
+    /// Set once [ProxyService::quiesce] is called, every connection holds a receiver
+    quiescing: watch::Sender<bool>,
+}
+
+fn upstream_client(
+    config: &UpstreamConfig,
+    upstream_tls: &UpstreamTls,
+    dial: Option<&Dial>,
+    preserve_header_case: bool,
+) -> Client<HttpsConnector<UpstreamConnector>, RequestBody> {
+    let mut http = HttpConnector::new();
+    // The scheme is up to the HTTPS connector
+    http.enforce_http(false);
+    let roots = match &config.roots {
+        Some(roots) => roots.clone(),
+        None if config.plaintext || upstream_tls.danger_accept_invalid_certs => {
+            rustls::RootCertStore::empty()
+        }
+        None => native_roots(),
+    };
+    let mut tls = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    if upstream_tls.danger_accept_invalid_certs {
+        let verifier = AcceptInvalidCerts(tls.crypto_provider().clone());
+        tls.dangerous().set_certificate_verifier(Arc::new(verifier));
+    }
+    let mut https = HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http();
+    if let Some(server_name) = upstream_tls.server_name.clone() {
+        https = https
+            .with_server_name_resolver(move |_: &Uri| ServerName::try_from(server_name.clone()));
+    }
+    let connector = UpstreamConnector {
+        http,
+        dial: dial.cloned(),
+        plaintext: config.plaintext,
+    };
+    let https = match config.version {
+        UpstreamVersion::Http1 => https.enable_http1().wrap_connector(connector),
+        UpstreamVersion::Negotiate => https.enable_all_versions().wrap_connector(connector),
+        UpstreamVersion::Http2 => https.enable_http2().wrap_connector(connector),
     };
 
-    let (mut parts, body) = upstream_response.into_parts();
+    // TODO: Add pingora executor
+    let mut builder = Client::builder(TokioExecutor::new());
+    builder
+        .http1_preserve_header_case(preserve_header_case)
+        .http2_only(config.version == UpstreamVersion::Http2);
+    if let Some(max) = config.pool_max_idle_per_host {
+        builder.pool_max_idle_per_host(max);
+    }
+    if let Some(timeout) = config.pool_idle_timeout {
+        // The idle connections are only reaped with a timer
+        builder
+            .pool_idle_timeout(timeout)
+            .pool_timer(TokioTimer::new());
+    }
+    builder.build(https)
+}
 
-    // Run latency hook
-    proxy
-        .inner
-        .upstream_latency(&parts, duration, &mut ctx)
-        .await;
+impl<P> ProxyService<P> {
+    pub fn new(inner: P) -> Self {
+        Self::with_dial(inner, None)
+    }
 
-    // Run the response filter
-    match proxy.inner.response_filter(&mut parts, &mut ctx).await {
-        Ok(()) => {}
-        Err(response) => return Ok(response),
+    /// Connect to the upstreams with `connector` instead of over TCP, e.g. to resolve their
+    /// names with a custom DNS resolver or to proxy over an in-memory transport in tests.
+    ///
+    /// TLS is still negotiated on top of its connections for the `https` upstreams.
+    pub fn with_connector<C>(inner: P, connector: C) -> Self
+    where
+        C: tower_service::Service<Uri> + Clone + Send + Sync + 'static,
+        C::Response: UpstreamIo + 'static,
+        C::Error: Into<BoxError>,
+        C::Future: Send + 'static,
+    {
+        let dial: Dial = Arc::new(move |uri| {
+            let mut connector = connector.clone();
+            Box::pin(async move {
+                std::future::poll_fn(|cx| connector.poll_ready(cx))
+                    .await
+                    .map_err(Into::into)?;
+                let io = connector.call(uri).await.map_err(Into::into)?;
+                let io: Box<dyn UpstreamIo> = Box::new(io);
+                Ok(io)
+            })
+        });
+        Self::with_dial(inner, Some(dial))
+    }
+
+    fn with_dial(inner: P, dial: Option<Dial>) -> Self {
+        let config = UpstreamConfig::default();
+        Self {
+            inner,
+            upstream: upstream_client(&config, &UpstreamTls::default(), dial.as_ref(), true),
+            dial,
+            tls_upstreams: Mutex::default(),
+            upstream_config: UpstreamConfig::default(),
+            lowercase_headers: false,
+            http1: Http1Config::default(),
+            http2: false,
+            max_request_trailers_size: MAX_REQUEST_TRAILERS_SIZE,
+            max_request_body_size: MAX_REQUEST_BODY_SIZE,
+            max_request_body_bytes: None,
+            max_response_body_size: MAX_RESPONSE_BODY_SIZE,
+            compression: None,
+            upstream_body_timeout: None,
+            max_attempts: MAX_ATTEMPTS,
+            client_connections: ClientConnections::new(),
+            quiescing: watch::channel(false).0,
+        }
+    }
+
+    /// Forward every header name in lowercase, HTTP/2 style, instead of preserving the casing
+    /// it was received with.
+    pub fn set_lowercase_headers(&mut self, lowercase: bool) {
+        // The casing is recorded when a message is parsed, so both the downstream and the
+        // upstream connections should stop recording it.
+        self.lowercase_headers = lowercase;
+        self.upstream = self.upstream_client(&UpstreamTls::default());
+        self.tls_upstreams.get_mut().unwrap().clear();
+    }
+
+    /// Set how the upstream connections are made, e.g. to speak HTTP/2 to the upstreams, to
+    /// verify them with a private CA or to size their pool. HTTP/1, over plain text or TLS, by
+    /// default.
+    pub fn set_upstream_config(&mut self, config: UpstreamConfig) {
+        self.upstream_config = config;
+        self.upstream = self.upstream_client(&UpstreamTls::default());
+        self.tls_upstreams.get_mut().unwrap().clear();
+    }
+
+    /// Set the options of the HTTP/1 downstream connections. A request head over their limits
+    /// is answered with `431`.
+    pub fn set_http1_config(&mut self, config: Http1Config) {
+        self.http1 = config;
+    }
+
+    /// Serve HTTP/2 to the downstream, with prior knowledge, instead of HTTP/1.
+    ///
+    /// Over TLS, the protocol negotiated with ALPN is served either way.
+    pub fn set_http2(&mut self, http2: bool) {
+        self.http2 = http2;
+    }
+
+    /// Reject with `400` the requests whose trailers are over `size` bytes, 8 KiB by default.
+    ///
+    /// Trailers over 16 KiB are always rejected.
+    pub fn set_max_request_trailers_size(&mut self, size: usize) {
+        self.max_request_trailers_size = size;
     }
 
-    Ok(Response::from_parts(parts, Either::Right(body)))
+    /// Reject with `413` the requests whose body is over `size` bytes when it is buffered for
+    /// [ProxyTrait::request_body_filter], 1 MiB by default.
+    pub fn set_max_request_body_size(&mut self, size: usize) {
+        self.max_request_body_size = size;
+    }
+
+    /// Reject with `413` the requests whose body is over `size` bytes, unlimited by default.
+    ///
+    /// Unlike [ProxyService::set_max_request_body_size] it also bounds the streamed bodies,
+    /// which are cut as soon as they cross it.
+    pub fn set_max_request_body_bytes(&mut self, size: Option<usize>) {
+        self.max_request_body_bytes = size;
+    }
+
+    /// Answer `502` to the requests whose upstream response body is over `size` bytes when it
+    /// is buffered for [ProxyTrait::response_body_filter], 8 MiB by default.
+    pub fn set_max_response_body_size(&mut self, size: usize) {
+        self.max_response_body_size = size;
+    }
+
+    /// Gzip the responses which `compression` allows to the clients accepting it, none by
+    /// default.
+    pub fn set_response_compression(&mut self, compression: Option<CompressionConfig>) {
+        self.compression = compression;
+    }
+
+    /// Abort the upstream response bodies which pause for over `timeout`, e.g. a backend stuck
+    /// in the middle of a body, unbounded by default.
+    ///
+    /// The downstream connection is closed then, or `502` is answered if the body is buffered
+    /// for [ProxyTrait::response_body_filter]. A slow body doesn't trip it as long as some of it
+    /// arrives within every `timeout`.
+    pub fn set_upstream_body_timeout(&mut self, timeout: Duration) {
+        self.upstream_body_timeout = Some(timeout);
+    }
+
+    /// Cap the upstream requests sent for a single downstream request, 3 by default and at
+    /// least 1.
+    ///
+    /// Every attempt counts against it, whichever part of the pipeline makes it, so that a
+    /// downstream request can't be amplified into many upstream ones.
+    pub fn set_max_attempts(&mut self, max_attempts: usize) {
+        self.max_attempts = max_attempts.max(1);
+    }
+
+    /// Close right away the connections of a client IP over `max` connections, unlimited by
+    /// default.
+    pub fn set_max_connections_per_client(&mut self, max: usize) {
+        self.client_connections.max_per_client = Some(max);
+    }
+
+    fn upstream_client(
+        &self,
+        upstream_tls: &UpstreamTls,
+    ) -> Client<HttpsConnector<UpstreamConnector>, RequestBody> {
+        let preserve_header_case = !self.lowercase_headers;
+        upstream_client(
+            &self.upstream_config,
+            upstream_tls,
+            self.dial.as_ref(),
+            preserve_header_case,
+        )
+    }
+
+    /// The client to send a request to its upstream with, as set by its [UpstreamTls]
+    fn upstream_for<B>(
+        &self,
+        request: &Request<B>,
+    ) -> Client<HttpsConnector<UpstreamConnector>, RequestBody> {
+        let Some(upstream_tls) = request.extensions().get::<UpstreamTls>() else {
+            return self.upstream.clone();
+        };
+        let mut upstreams = self.tls_upstreams.lock().unwrap();
+        let upstream = upstreams
+            .entry(upstream_tls.clone())
+            .or_insert_with(|| self.upstream_client(upstream_tls));
+        upstream.clone()
+    }
+
+    /// Whether the service should receive traffic, `false` once it is quiescing.
+    pub fn is_ready(&self) -> bool {
+        !*self.quiescing.borrow()
+    }
+
+    /// Quiesce the service ahead of a shutdown, e.g. for a zero downtime deploy.
+    ///
+    /// The service stops being ready and closes the new connections right away, while the
+    /// in-flight requests get up to `deadline` to complete. Returns whether every connection
+    /// was closed by then.
+    pub async fn quiesce(&self, deadline: Duration) -> bool {
+        self.quiescing.send_replace(true);
+        time::timeout(deadline, self.quiescing.closed())
+            .await
+            .is_ok()
+    }
 }
 
-#[cfg(feature = "pingora-core")]
-#[async_trait]
-impl<P> ServerApp for ProxyService<P>
+impl<P> ProxyService<P>
 where
     P: ProxyTrait + Send + Sync + 'static,
     <P as ProxyTrait>::CTX: Send + Sync,
 {
-    async fn process_new(
-        self: &Arc<Self>,
-        strem: Stream,
-        _shutdown: &ShutdownWatch,
-    ) -> Option<Stream> {
-        let on_request = service_fn(move |req| process_request(self.clone(), req));
-        let io = TokioIo::new(strem);
-        if let Err(err) = http1::Builder::new()
-            .keep_alive(true)
-            .preserve_header_case(true)
-            .serve_connection(io, on_request)
-            .await
+    /// Serve the connections accepted on a Unix domain socket, e.g. of
+    /// [crate::listeners::bind_uds], until `shutdown`, then remove the file of the socket.
+    pub async fn serve_uds(
+        self: Arc<Self>,
+        listener: UnixListener,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        let proxy = self.clone();
+                        let http2 = self.http2;
+                        tokio::spawn(async move {
+                            if let Err(e) = proxy.serve_connection(stream, None, http2).await {
+                                tracing::debug!(error = %e, "failed to serve a unix connection");
+                            }
+                        });
+                    }
+                    Err(e) => tracing::warn!(error = %e, "failed to accept a unix connection"),
+                },
+                _ = shutdown.changed() => break,
+            }
+        }
+        if let Some(path) = listener
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(Path::to_path_buf))
         {
-            println!("Error serving connection: {:?}", err);
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::warn!(
+                    path = %path.display(),
+                    error = %e,
+                    "failed to remove the unix socket"
+                );
+            }
         }
+    }
 
-        None
+    /// Serve the HTTP requests of a downstream connection from `client` until it is closed,
+    /// over HTTP/2 if `http2`.
+    async fn serve_connection<I>(
+        self: &Arc<Self>,
+        io: I,
+        client: Option<SocketAddr>,
+        http2: bool,
+    ) -> hyper::Result<()>
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let quiescing = self.quiescing.subscribe();
+        if *quiescing.borrow() {
+            // Refuse new connections while quiescing
+            return Ok(());
+        }
+        let _client_connection = match client {
+            Some(client) if self.client_connections.max_per_client.is_some() => {
+                match self.client_connections.acquire(client.ip()) {
+                    Some(connection) => Some(connection),
+                    // Refuse the connections over the limit of the client
+                    None => return Ok(()),
+                }
+            }
+            _ => None,
+        };
+
+        let requests = AtomicUsize::new(0);
+        let on_request = service_fn(move |req| {
+            let served = requests.fetch_add(1, Ordering::Relaxed) + 1;
+            process_request(self.clone(), req, client, served)
+        });
+        let io = TokioIo::new(io);
+        if http2 {
+            // Every stream of the connection is a request of its own
+            let builder = http2::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection(io, on_request);
+            serve_until_quiescing(connection, http2::Connection::graceful_shutdown, quiescing).await
+        } else {
+            let mut builder = http1::Builder::new();
+            builder
+                .keep_alive(self.http1.keep_alive)
+                .half_close(self.http1.half_close)
+                .preserve_header_case(!self.lowercase_headers);
+            if let Some(max) = self.http1.max_buf_size {
+                builder.max_buf_size(max);
+            }
+            if let Some(max) = self.http1.max_headers {
+                builder.max_headers(max);
+            }
+            let connection = builder.serve_connection(io, on_request).with_upgrades();
+            let graceful_shutdown = http1::UpgradeableConnection::graceful_shutdown;
+            serve_until_quiescing(connection, graceful_shutdown, quiescing).await
+        }
     }
 }
 
-/// Create a [Service] from the user implemented [ProxyHttp].
-///
-/// The returned [Service] can be hosted by a [pingora_core::server::Server] directly.
-#[cfg(feature = "pingora-core")]
-pub fn http_proxy_service<P>(name: &str, inner: P) -> Service<ProxyService<P>>
+/// Drive a downstream connection until it is closed, gracefully once the service is quiescing
+async fn serve_until_quiescing<C>(
+    connection: C,
+    graceful_shutdown: fn(Pin<&mut C>),
+    mut quiescing: watch::Receiver<bool>,
+) -> hyper::Result<()>
 where
-    P: ProxyTrait + Send + Sync + 'static,
-    <P as ProxyTrait>::CTX: Send + Sync,
+    C: Future<Output = hyper::Result<()>>,
 {
-    Service::new(format!("{} proxy service", name), ProxyService::new(inner))
+    let mut connection = pin!(connection);
+    tokio::select! {
+        result = connection.as_mut() => return result,
+        // Finish the in-flight requests, if any, then close the connection
+        _ = quiescing.wait_for(|quiescing| *quiescing) => graceful_shutdown(connection.as_mut()),
+    }
+    connection.await
 }
 
-#[cfg(not(feature = "pingora-core"))]
-pub fn http_proxy_service<P>(_name: &str, _inner: P)
+/// Process a request from `client`, the `requests`th one of its connection
+async fn process_request<P>(
+    proxy: Arc<ProxyService<P>>,
+    mut request: Request<IncomingRequest>,
+    client: Option<SocketAddr>,
+    requests: usize,
+) -> Result<Response<Body>, Infallible>
 where
     P: ProxyTrait + Send + Sync + 'static,
     <P as ProxyTrait>::CTX: Send + Sync,
 {
-    unimplemented!("http_proxy_service is only available with the pingora-core feature")
+    let start = Instant::now();
+    if let Some(client) = client {
+        request.extensions_mut().insert(ClientAddr(client));
+    }
+    let mut ctx = proxy.inner.new_ctx();
+    let mut attempts = AttemptBudget::new(proxy.max_attempts);
+    let (parts, body) = request.into_parts();
+    let request_headers = parts.clone();
+    let request = Request::from_parts(parts, body);
+    let mut response = proxy_request(&proxy, request, &mut ctx, &mut attempts).await;
+    if let Some(compression) = &proxy.compression {
+        response = compress(compression, &request_headers, response);
+    }
+
+    // An upgraded connection isn't reused for other requests, its `connection` header stays
+    let upgraded = response.status() == StatusCode::SWITCHING_PROTOCOLS;
+    if !upgraded && !proxy.inner.reuse_connection(requests, &mut ctx) {
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+
+    let (parts, body) = response.into_parts();
+    let duration = start.elapsed();
+    #[cfg(feature = "prometheus")]
+    crate::metrics::record_request(parts.status, duration);
+    proxy
+        .inner
+        .logging(&request_headers, &parts, duration, &mut ctx)
+        .await;
+    Ok(Response::from_parts(parts, body))
+}
+
+async fn proxy_request<P>(
+    proxy: &Arc<ProxyService<P>>,
+    request: Request<IncomingRequest>,
+    ctx: &mut P::CTX,
+    attempts: &mut AttemptBudget,
+) -> Response<Body>
+where
+    P: ProxyTrait + Send + Sync + 'static,
+    <P as ProxyTrait>::CTX: Send + Sync,
+{
+    let (mut parts, body) = request.into_parts();
+    // Resolves once the response to an upgrade request is sent, e.g. for a WebSocket
+    let downstream_upgrade = parts.extensions.remove::<OnUpgrade>();
+
+    // Run the request filter
+    match proxy.inner.request_filter(&parts, ctx).await {
+        Ok(()) => {}
+        Err(response) => return response,
+    }
+
+    // Serve a cached response without going to the upstream
+    if let Some(response) = proxy.inner.cache_lookup(&parts, ctx).await {
+        return response;
+    }
+
+    // Don't wait for a body to cross the limit when its length says it will
+    let content_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+    if let (Some(length), Some(max)) = (content_length, proxy.max_request_body_bytes) {
+        if length > max {
+            return Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(empty_body())
+                .unwrap();
+        }
+    }
+
+    // Only buffer the body for the requests whose filter asks for it
+    let body = if proxy.inner.wants_request_body(&parts, ctx) {
+        let max_size = proxy
+            .max_request_body_bytes
+            .map_or(proxy.max_request_body_size, |max| {
+                max.min(proxy.max_request_body_size)
+            });
+        let (data, trailers) = match buffer_request_body(body, max_size).await {
+            Ok(buffered) => buffered,
+            Err(status) => {
+                return Response::builder()
+                    .status(status)
+                    .body(empty_body())
+                    .unwrap();
+            }
+        };
+        if let Err(response) = proxy.inner.request_body_filter(&parts, &data, ctx).await {
+            return response;
+        }
+        RequestBodyInner::Buffered {
+            data: Some(data),
+            trailers,
+        }
+    } else {
+        RequestBodyInner::Streaming(body)
+    };
+
+    // TE is hop-by-hop, only pass on whether the downstream accepts trailers since the
+    // transfer codings are up to each connection
+    let accepts_trailers = accepts_trailers(&parts.headers);
+    if accepts_trailers {
+        parts
+            .headers
+            .insert(TE, HeaderValue::from_static("trailers"));
+    } else {
+        parts.headers.remove(TE);
+    }
+
+    // Reset the version so that the pooled client picks the protocol of each upstream
+    // connection, keeping the authority of a HTTP/2 request in `host` for HTTP/1 upstreams
+    if parts.version == Version::HTTP_2 {
+        parts.version = Version::HTTP_11;
+        if let Some(authority) = parts
+            .uri
+            .authority()
+            .filter(|_| !parts.headers.contains_key(HOST))
+        {
+            if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                parts.headers.insert(HOST, host);
+            }
+        }
+    }
+
+    let mut downstream = Some((parts, body));
+    let mut attempt = 0;
+    let mut peers = Vec::new().into_iter();
+    let (upstream_response, duration) = loop {
+        let Some((mut parts, body)) = downstream.take() else {
+            unreachable!("a request is only retried with its copy");
+        };
+        attempt += 1;
+
+        // Get the next upstream address, the candidates are picked again once all were tried
+        let upstream_addr = match peers.next() {
+            Some(upstream_addr) => upstream_addr,
+            None => {
+                peers = proxy.inner.upstream_peers(&parts, ctx).await.into_iter();
+                let Some(upstream_addr) = peers.next() else {
+                    return Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .body(empty_body())
+                        .unwrap();
+                };
+                upstream_addr
+            }
+        };
+        tracing::debug!(upstream.addr = %upstream_addr, attempt, "proxying request");
+        // Keep a copy to retry with, unless the body can't be sent again
+        let retry = body.replay().map(|body| (parts.clone(), body));
+        let upstream_addr_clone = upstream_addr.clone();
+        // The parts are moved into the upstream request, keep what `fail_to_connect` sees
+        let info = FailedRequestInfo::new(&parts);
+        parts.uri = upstream_addr;
+
+        // Allow the user to modify the request before sending it to the upstream
+        proxy.inner.upstream_request_filter(&mut parts, ctx).await;
+
+        // Allow the user to replace a body buffered for the request body filter, the empty
+        // copy of a streamed body to retry with has no data
+        let body = match body {
+            RequestBodyInner::Buffered {
+                data: Some(data),
+                trailers,
+            } => {
+                let replaced = proxy
+                    .inner
+                    .upstream_request_body_filter(&mut parts, data.clone(), ctx)
+                    .await;
+                // A body with trailers stays chunked
+                if replaced != data && trailers.is_none() {
+                    parts.headers.remove(TRANSFER_ENCODING);
+                    parts
+                        .headers
+                        .insert(CONTENT_LENGTH, HeaderValue::from(replaced.len()));
+                }
+                RequestBodyInner::Buffered {
+                    data: Some(replaced),
+                    trailers,
+                }
+            }
+            body => body,
+        };
+
+        let failed = Arc::new(OnceLock::new());
+        let body = RequestBody {
+            inner: body,
+            max_trailers_size: proxy.max_request_trailers_size,
+            remaining: proxy.max_request_body_bytes,
+            failed: failed.clone(),
+        };
+        let request = Request::from_parts(parts, body);
+
+        // Proxy the request to the upstream
+        attempts.spend();
+        let start = Instant::now();
+        let upstream_response = proxy.upstream_for(&request).request(request).await;
+        let duration = start.elapsed();
+
+        // The downstream sent an invalid body, not the upstream's fault
+        if let (Err(_), Some(&status)) = (&upstream_response, failed.get()) {
+            return Response::builder()
+                .status(status)
+                .body(empty_body())
+                .unwrap();
+        }
+        match upstream_response {
+            Ok(upstream_response) => break (upstream_response, duration),
+            Err(err)
+                if err.is_connect()
+                    && peers.len() > 0
+                    && retry.is_some()
+                    && attempts.has_left() =>
+            {
+                tracing::info!(
+                    upstream.addr = %upstream_addr_clone,
+                    attempt,
+                    error = %err,
+                    "failing over to the next upstream"
+                );
+                downstream = retry;
+            }
+            Err(err)
+                if retry.is_some()
+                    && attempts.has_left()
+                    && proxy.inner.retry_policy(attempt, &err, ctx) =>
+            {
+                tracing::info!(
+                    upstream.addr = %upstream_addr_clone,
+                    attempt,
+                    error = %err,
+                    "retrying request"
+                );
+                downstream = retry;
+            }
+            Err(err) => {
+                #[cfg(feature = "prometheus")]
+                crate::metrics::record_connect_failure(&upstream_addr_clone);
+                match proxy
+                    .inner
+                    .fail_to_connect(&info, ctx, &upstream_addr_clone, err)
+                {
+                    Some(response) => return response,
+                    None => {
+                        return Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(empty_body())
+                            .unwrap();
+                    }
+                }
+            }
+        }
+    };
+
+    let (mut parts, body) = upstream_response.into_parts();
+    parts.extensions.insert(UpstreamLatency(duration));
+    if !accepts_trailers {
+        // The trailers are dropped on the way to the downstream, don't announce them
+        parts.headers.remove(TRAILER);
+    }
+    if let Some(ConnectionUses(uses)) = parts.extensions.remove() {
+        let connection = match uses.fetch_add(1, Ordering::Relaxed) {
+            0 => UpstreamConnection::New,
+            _ => UpstreamConnection::Reused,
+        };
+        parts.extensions.insert(connection);
+    }
+
+    // Run latency hook
+    proxy.inner.upstream_latency(&parts, duration, ctx).await;
+
+    // Run the response filter
+    match proxy.inner.response_filter(&mut parts, ctx).await {
+        Ok(()) => {}
+        Err(response) => return response,
+    }
+
+    // Both sides switched protocols, tunnel the bytes between them
+    if parts.status == StatusCode::SWITCHING_PROTOCOLS {
+        if let (Some(downstream), Some(upstream)) =
+            (downstream_upgrade, parts.extensions.remove::<OnUpgrade>())
+        {
+            tokio::spawn(tunnel(downstream, upstream));
+            return Response::from_parts(parts, empty_body());
+        }
+    }
+
+    // Only buffer the body for the responses whose filter asks for it
+    if proxy.inner.wants_response_body(&parts, ctx) {
+        let body = match proxy.upstream_body_timeout {
+            Some(timeout) => Either::Left(IdleTimeout::new(body, timeout)),
+            None => Either::Right(body),
+        };
+        let Ok(collected) = Limited::new(body, proxy.max_response_body_size)
+            .collect()
+            .await
+        else {
+            return Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(empty_body())
+                .unwrap();
+        };
+        let body = match proxy
+            .inner
+            .response_body_filter(&mut parts, collected.to_bytes(), ctx)
+            .await
+        {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+        proxy.inner.cache_store(&parts, &body, ctx).await;
+        // The body may have changed size, and its trailers are gone
+        parts.headers.remove(TRANSFER_ENCODING);
+        parts.headers.remove(TRAILER);
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        return Response::from_parts(parts, full_body(body));
+    }
+
+    let body = proxy.inner.response_body_transform(&mut parts, body, ctx);
+    // The trailers come after the body, filter them as it is streamed
+    let body = match body {
+        Either::Right(body) if proxy.inner.wants_response_trailers(&parts, ctx) => {
+            let proxy = proxy.clone();
+            let body = body.map_frame(move |mut frame| {
+                if let Some(trailers) = frame.trailers_mut() {
+                    proxy.inner.response_trailers_filter(trailers);
+                }
+                frame
+            });
+            Either::Right(StreamedBody::Wrapped(body.boxed()))
+        }
+        body => body,
+    };
+    let body = match (body, proxy.upstream_body_timeout) {
+        (Either::Right(body), Some(timeout)) => {
+            let body = IdleTimeout::new(body, timeout);
+            Either::Right(StreamedBody::Wrapped(body.boxed()))
+        }
+        (body, _) => body,
+    };
+    Response::from_parts(parts, body)
+}
+
+/// Copy the bytes both ways between an upgraded downstream and upstream connection, until
+/// either of them closes or fails
+async fn tunnel(downstream: OnUpgrade, upstream: OnUpgrade) {
+    let (downstream, upstream) = match tokio::try_join!(downstream, upstream) {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            tracing::info!(error = %e, "failed to upgrade the connections");
+            return;
+        }
+    };
+    let mut downstream = TokioIo::new(downstream);
+    let mut upstream = TokioIo::new(upstream);
+    if let Err(e) = tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await {
+        tracing::debug!(error = %e, "upgraded connection closed");
+    }
+}
+
+/// Whether the `TE` headers of a request accept trailers, e.g. `TE: gzip, trailers`
+fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let coding = coding.split(';').next().unwrap_or_default();
+            coding.trim().eq_ignore_ascii_case("trailers")
+        })
+}
+
+/// Why serving a downstream connection failed
+#[cfg(any(test, feature = "pingora-core"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConnectionError {
+    /// The client closed or reset the connection, possibly in the middle of a request
+    Disconnect,
+    /// The client sent something which isn't HTTP/1
+    Protocol,
+    Timeout,
+    Other,
+}
+
+#[cfg(any(test, feature = "pingora-core"))]
+impl ConnectionError {
+    fn classify(err: &hyper::Error) -> Self {
+        if err.is_timeout() {
+            return Self::Timeout;
+        }
+        if err.is_parse() || err.is_parse_too_large() {
+            return Self::Protocol;
+        }
+        if err.is_incomplete_message()
+            || err.is_closed()
+            || err.is_canceled()
+            || err.is_body_write_aborted()
+        {
+            return Self::Disconnect;
+        }
+        let io_error = std::error::Error::source(err)
+            .and_then(|source| source.downcast_ref::<std::io::Error>());
+        match io_error.map(std::io::Error::kind) {
+            Some(
+                std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::UnexpectedEof,
+            ) => Self::Disconnect,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[cfg(feature = "pingora-core")]
+#[async_trait]
+impl<P> ServerApp for ProxyService<P>
+where
+    P: ProxyTrait + Send + Sync + 'static,
+    <P as ProxyTrait>::CTX: Send + Sync,
+{
+    async fn process_new(
+        self: &Arc<Self>,
+        strem: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let client = strem
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr()?.as_inet().copied());
+        let http2 = match strem.selected_alpn_proto() {
+            Some(ALPN::H2) => true,
+            Some(_) => false,
+            None => self.http2,
+        };
+        if let Err(err) = self.serve_connection(strem, client, http2).await {
+            match ConnectionError::classify(&err) {
+                // The client went away, nothing wrong with that
+                ConnectionError::Disconnect => {}
+                ConnectionError::Protocol => {
+                    tracing::info!(client = ?client, error = %err, "invalid request on connection")
+                }
+                ConnectionError::Timeout => {
+                    tracing::info!(client = ?client, error = %err, "connection timed out")
+                }
+                ConnectionError::Other => {
+                    tracing::warn!(client = ?client, error = ?err, "error serving connection")
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Create a [Service] from the user implemented [ProxyHttp].
+///
+/// The returned [Service] can be hosted by a [pingora_core::server::Server] directly.
+#[cfg(feature = "pingora-core")]
+pub fn http_proxy_service<P>(name: &str, inner: P) -> Service<ProxyService<P>>
+where
+    P: ProxyTrait + Send + Sync + 'static,
+    <P as ProxyTrait>::CTX: Send + Sync,
+{
+    Service::new(format!("{} proxy service", name), ProxyService::new(inner))
+}
+
+#[cfg(not(feature = "pingora-core"))]
+pub fn http_proxy_service<P>(_name: &str, _inner: P)
+where
+    P: ProxyTrait + Send + Sync + 'static,
+    <P as ProxyTrait>::CTX: Send + Sync,
+{
+    unimplemented!("http_proxy_service is only available with the pingora-core feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_trait::{
+        collect_request_body, collect_response_body, RequestHeaders, ResponseHeaders, UpstreamError,
+    };
+    use http_body_util::Empty;
+    use hyper::body::Bytes;
+    use hyper::{Method, Uri};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    struct TestProxy(Uri);
+
+    #[async_trait]
+    impl ProxyTrait for TestProxy {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.0.clone())
+        }
+    }
+
+    /// Serve `proxy` on a random local port, one task per connection
+    async fn start_proxy<P>(proxy: ProxyService<P>) -> SocketAddr
+    where
+        P: ProxyTrait + Send + Sync + 'static,
+        <P as ProxyTrait>::CTX: Send + Sync,
+    {
+        serve(Arc::new(proxy)).await
+    }
+
+    async fn serve<P>(proxy: Arc<ProxyService<P>>) -> SocketAddr
+    where
+        P: ProxyTrait + Send + Sync + 'static,
+        <P as ProxyTrait>::CTX: Send + Sync,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let proxy = proxy.clone();
+                tokio::spawn(
+                    async move { proxy.serve_connection(stream, Some(peer), false).await },
+                );
+            }
+        });
+        addr
+    }
+
+    /// Read a HTTP/1 message head, up to the empty line
+    async fn read_head(stream: &mut (impl AsyncReadExt + Unpin)) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            if stream.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// A raw HTTP/1 upstream which replies to a single request and returns the request head
+    async fn start_raw_upstream(
+        response: &'static str,
+    ) -> (SocketAddr, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let head = read_head(&mut stream).await;
+            stream.write_all(response.as_bytes()).await.unwrap();
+            head
+        });
+        (addr, handle)
+    }
+
+    async fn raw_request(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        read_head(&mut stream).await
+    }
+
+    const CASED_RESPONSE: &str =
+        "HTTP/1.1 200 OK\r\nX-Upstream-Header: 1\r\ncontent-length: 0\r\n\r\n";
+    const CASED_REQUEST: &str = "GET / HTTP/1.1\r\nHost: localhost\r\nX-Custom-Header: 1\r\n\r\n";
+
+    #[tokio::test]
+    async fn test_serve_uds() {
+        let (upstream, _) = start_raw_upstream(CASED_RESPONSE).await;
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let proxy = Arc::new(ProxyService::new(TestProxy(uri)));
+        let path = std::env::temp_dir().join(format!("yapf-serve-uds-{}.sock", std::process::id()));
+        let listener = crate::listeners::bind_uds(&path).unwrap();
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let serving = tokio::spawn(proxy.serve_uds(listener, shutdown));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream.write_all(CASED_REQUEST.as_bytes()).await.unwrap();
+        let response = read_head(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+
+        shutdown_sender.send(true).unwrap();
+        serving.await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_preserve_header_case() {
+        let (upstream, upstream_head) = start_raw_upstream(CASED_RESPONSE).await;
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let proxy = start_proxy(ProxyService::new(TestProxy(uri))).await;
+
+        let response = raw_request(proxy, CASED_REQUEST).await;
+        assert!(response.contains("X-Upstream-Header: 1"), "{response}");
+        let request = upstream_head.await.unwrap();
+        assert!(request.contains("X-Custom-Header: 1"), "{request}");
+    }
+
+    #[tokio::test]
+    async fn test_lowercase_headers() {
+        let (upstream, upstream_head) = start_raw_upstream(CASED_RESPONSE).await;
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let mut proxy = ProxyService::new(TestProxy(uri));
+        proxy.set_lowercase_headers(true);
+        let proxy = start_proxy(proxy).await;
+
+        let response = raw_request(proxy, CASED_REQUEST).await;
+        assert!(response.contains("x-upstream-header: 1"), "{response}");
+        let request = upstream_head.await.unwrap();
+        assert!(request.contains("x-custom-header: 1"), "{request}");
+        assert!(!request.contains("X-Custom-Header"), "{request}");
+    }
+
+    #[tokio::test]
+    async fn test_truncated_upstream_body() {
+        let (upstream, _) =
+            start_raw_upstream("HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nabc").await;
+        let client = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+        let request = Request::get(format!("http://{upstream}/"))
+            .body(Empty::new())
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+
+        let err = collect_response_body(response.into_body())
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_request_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let collected = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+            let on_request = service_fn(move |request: Request<IncomingRequest>| {
+                let sender = sender.clone();
+                async move {
+                    let collected = collect_request_body(request.into_body()).await;
+                    let response = match &collected {
+                        Ok(_) => Response::new(empty_body()),
+                        Err(e) => Response::builder()
+                            .status(e.status())
+                            .body(empty_body())
+                            .unwrap(),
+                    };
+                    sender.send(collected).unwrap();
+                    Ok::<_, Infallible>(response)
+                }
+            });
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), on_request)
+                .await;
+            receiver.recv().await.unwrap()
+        });
+
+        // Abort after 3 of the 10 announced bytes
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\ncontent-length: 10\r\n\r\nabc")
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+
+        let err = collected.await.unwrap().unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_quiesce() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let proxy = Arc::new(ProxyService::new(TestProxy(uri)));
+        let addr = serve(proxy.clone()).await;
+
+        let in_flight = tokio::spawn(raw_request(addr, CASED_REQUEST));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(proxy.is_ready());
+        let quiesced = tokio::spawn({
+            let proxy = proxy.clone();
+            async move { proxy.quiesce(Duration::from_secs(5)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!proxy.is_ready());
+
+        // A new connection is closed without a response
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        let _ = refused.write_all(CASED_REQUEST.as_bytes()).await;
+        let read = refused.read(&mut [0; 1]).await;
+        assert!(!matches!(read, Ok(n) if n > 0), "{read:?}");
+
+        let response = in_flight.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(quiesced.await.unwrap());
+    }
+
+    /// Answers `503` without any upstream, closing the connections after `max_requests`
+    struct NoUpstream {
+        max_requests: usize,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for NoUpstream {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            None
+        }
+
+        fn reuse_connection(&self, requests: usize, _ctx: &mut ()) -> bool {
+            requests < self.max_requests
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reuse_connection_veto() {
+        let proxy = start_proxy(ProxyService::new(NoUpstream { max_requests: 2 })).await;
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(CASED_REQUEST.as_bytes()).await.unwrap();
+        let response = read_head(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+        assert!(!response.contains("connection: close"), "{response}");
+
+        stream.write_all(CASED_REQUEST.as_bytes()).await.unwrap();
+        let response = read_head(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+        assert!(response.contains("connection: close"), "{response}");
+
+        // Closed despite keep-alive
+        let _ = stream.write_all(CASED_REQUEST.as_bytes()).await;
+        let read = stream.read(&mut [0; 1]).await;
+        assert!(!matches!(read, Ok(n) if n > 0), "{read:?}");
+    }
+
+    /// Stamps the upstream latency on the responses, recording the one measured by the hook
+    struct StampLatency {
+        upstream: Uri,
+        measured: std::sync::Mutex<Option<Duration>>,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for StampLatency {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.upstream.clone())
+        }
+
+        async fn upstream_latency(
+            &self,
+            _upstream_response: &ResponseHeaders,
+            latency: Duration,
+            _ctx: &mut (),
+        ) {
+            *self.measured.lock().unwrap() = Some(latency);
+        }
+
+        async fn response_filter(
+            &self,
+            upstream_response: &mut ResponseHeaders,
+            _ctx: &mut (),
+        ) -> Result<(), Response<Body>> {
+            let UpstreamLatency(latency) = upstream_response.extensions.get().copied().unwrap();
+            let value = HeaderValue::from_str(&latency.as_micros().to_string()).unwrap();
+            upstream_response.headers.insert("x-upstream-time", value);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_latency_in_response_filter() {
+        let (upstream, _) = start_raw_upstream(CASED_RESPONSE).await;
+        let proxy = Arc::new(ProxyService::new(StampLatency {
+            upstream: format!("http://{upstream}/").parse().unwrap(),
+            measured: Default::default(),
+        }));
+        let addr = serve(proxy.clone()).await;
+
+        let response = raw_request(addr, CASED_REQUEST).await;
+        let measured = proxy.inner.measured.lock().unwrap().unwrap();
+        let stamp = format!("x-upstream-time: {}\r\n", measured.as_micros());
+        assert!(response.contains(&stamp), "{response}");
+    }
+
+    /// Send a chunked request with trailers of `size` bytes, return the response head
+    async fn request_with_trailers(proxy: SocketAddr, size: usize) -> String {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nTE: trailers\r\n\
+             transfer-encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\nx-trailer: {}\r\n\r\n",
+            "a".repeat(size)
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        read_head(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request_trailers() {
+        // An upstream which reads the whole request before answering
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let on_request = service_fn(|request: Request<IncomingRequest>| async move {
+                    collect_request_body(request.into_body()).await.unwrap();
+                    Ok::<_, Infallible>(Response::new(empty_body()))
+                });
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), on_request),
+                );
+            }
+        });
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let mut proxy = ProxyService::new(TestProxy(uri));
+        proxy.set_max_request_trailers_size(1024);
+        let proxy = start_proxy(proxy).await;
+
+        let response = request_with_trailers(proxy, 100).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        let response = request_with_trailers(proxy, 2000).await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        // Over the hard limit
+        let response = request_with_trailers(proxy, 20_000).await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+    }
+
+    #[test]
+    fn test_attempt_budget() {
+        let mut attempts = AttemptBudget::new(2);
+        assert!(attempts.has_left());
+        attempts.spend();
+        assert!(attempts.has_left());
+        attempts.spend();
+        assert!(!attempts.has_left());
+        attempts.spend();
+        assert!(!attempts.has_left());
+    }
+
+    #[tokio::test]
+    async fn test_max_attempts() {
+        // Count the requests reaching the upstream
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let hits = hits.clone();
+            async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let hits = hits.clone();
+                    let on_request = service_fn(move |_request: Request<IncomingRequest>| {
+                        hits.fetch_add(1, Ordering::Relaxed);
+                        async move { Ok::<_, Infallible>(Response::new(empty_body())) }
+                    });
+                    tokio::spawn(
+                        http1::Builder::new().serve_connection(TokioIo::new(stream), on_request),
+                    );
+                }
+            }
+        });
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let mut proxy = ProxyService::new(TestProxy(uri));
+        proxy.set_max_attempts(0);
+        assert_eq!(proxy.max_attempts, 1);
+        let proxy = start_proxy(proxy).await;
+
+        for _ in 0..3 {
+            let response = raw_request(proxy, CASED_REQUEST).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        }
+        assert_eq!(hits.load(Ordering::Relaxed), 3);
+    }
+
+    /// Records whether each upstream request reused a connection
+    struct RecordConnection {
+        upstream: Uri,
+        connections: std::sync::Mutex<Vec<UpstreamConnection>>,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for RecordConnection {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.upstream.clone())
+        }
+
+        async fn upstream_latency(
+            &self,
+            upstream_response: &ResponseHeaders,
+            _latency: Duration,
+            _ctx: &mut (),
+        ) {
+            let connection = upstream_response.extensions.get().copied().unwrap();
+            self.connections.lock().unwrap().push(connection);
+        }
+    }
+
+    /// A keep-alive upstream answering with empty responses
+    async fn start_keep_alive_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let on_request = service_fn(|_request: Request<IncomingRequest>| async move {
+                    Ok::<_, Infallible>(Response::new(empty_body()))
+                });
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), on_request),
+                );
+            }
+        });
+        upstream
+    }
+
+    #[tokio::test]
+    async fn test_upstream_connection_reuse() {
+        let upstream = start_keep_alive_upstream().await;
+        let proxy = Arc::new(ProxyService::new(RecordConnection {
+            upstream: format!("http://{upstream}/").parse().unwrap(),
+            connections: Default::default(),
+        }));
+        let addr = serve(proxy.clone()).await;
+
+        for _ in 0..2 {
+            let response = raw_request(addr, CASED_REQUEST).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        }
+        assert_eq!(
+            *proxy.inner.connections.lock().unwrap(),
+            [UpstreamConnection::New, UpstreamConnection::Reused]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upstream_pool_config() {
+        let upstream = start_keep_alive_upstream().await;
+        let mut proxy = ProxyService::new(RecordConnection {
+            upstream: format!("http://{upstream}/").parse().unwrap(),
+            connections: Default::default(),
+        });
+        proxy.set_upstream_config(UpstreamConfig {
+            pool_max_idle_per_host: Some(0),
+            ..Default::default()
+        });
+        let proxy = Arc::new(proxy);
+        let addr = serve(proxy.clone()).await;
+
+        // Without idle connections, every request opens a new one
+        for _ in 0..2 {
+            let response = raw_request(addr, CASED_REQUEST).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        }
+        assert_eq!(
+            *proxy.inner.connections.lock().unwrap(),
+            [UpstreamConnection::New, UpstreamConnection::New]
+        );
+    }
+
+    /// Serve the next connection accepted by `listener`, returning how it ended
+    async fn serve_one(listener: TcpListener) -> hyper::Result<()> {
+        let proxy = Arc::new(ProxyService::new(NoUpstream { max_requests: 10 }));
+        let (stream, _) = listener.accept().await.unwrap();
+        proxy.serve_connection(stream, None, false).await
+    }
+
+    #[tokio::test]
+    async fn test_classify_connection_error() {
+        // A client going away in the middle of a request
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = tokio::spawn(serve_one(listener));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: loc")
+            .await
+            .unwrap();
+        drop(stream);
+        let err = served.await.unwrap().unwrap_err();
+        assert_eq!(ConnectionError::classify(&err), ConnectionError::Disconnect);
+
+        // Not HTTP
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = tokio::spawn(serve_one(listener));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"\x16\x03\x01 not http\r\n\r\n")
+            .await
+            .unwrap();
+        let err = served.await.unwrap().unwrap_err();
+        assert_eq!(ConnectionError::classify(&err), ConnectionError::Protocol);
+
+        // A client closing between requests isn't an error at all
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = tokio::spawn(serve_one(listener));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(CASED_REQUEST.as_bytes()).await.unwrap();
+        read_head(&mut stream).await;
+        drop(stream);
+        assert!(served.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_per_client() {
+        let mut proxy = ProxyService::new(NoUpstream { max_requests: 10 });
+        proxy.set_max_connections_per_client(2);
+        let proxy = Arc::new(proxy);
+        let addr = serve(proxy.clone()).await;
+
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(CASED_REQUEST.as_bytes()).await.unwrap();
+            let response = read_head(&mut stream).await;
+            assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+            connections.push(stream);
+        }
+
+        // Closed without a response while the others are open
+        for _ in 0..3 {
+            let mut refused = TcpStream::connect(addr).await.unwrap();
+            let _ = refused.write_all(CASED_REQUEST.as_bytes()).await;
+            let read = refused.read(&mut [0; 1]).await;
+            assert!(!matches!(read, Ok(n) if n > 0), "{read:?}");
+        }
+
+        // Accepted again once a connection is closed, and forgotten once all are
+        connections.pop();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+        connections.clear();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let shards = &proxy.client_connections.shards;
+        assert!(shards.iter().all(|shard| shard.lock().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn test_accepts_trailers() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_trailers(&headers));
+        headers.insert(TE, HeaderValue::from_static("gzip;q=0.5"));
+        assert!(!accepts_trailers(&headers));
+        headers.append(TE, HeaderValue::from_static("deflate, Trailers ;q=1"));
+        assert!(accepts_trailers(&headers));
+    }
+
+    const TRAILERS_RESPONSE: &str = "HTTP/1.1 200 OK\r\nTrailer: x-checksum\r\n\
+        transfer-encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\nx-checksum: 1\r\n\r\n";
+
+    /// Read a whole chunked response, up to its trailers
+    async fn read_chunked(stream: &mut TcpStream) -> String {
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") || !response.windows(5).any(|w| w == b"\r\n0\r\n") {
+            let mut byte = [0; 1];
+            if stream.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            response.push(byte[0]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forward_trailers() {
+        let (upstream, upstream_head) = start_raw_upstream(TRAILERS_RESPONSE).await;
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let proxy = start_proxy(ProxyService::new(TestProxy(uri))).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nTE: gzip, trailers\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_chunked(&mut stream).await;
+        let request = upstream_head.await.unwrap();
+        assert!(request.contains("TE: trailers\r\n"), "{request}");
+        assert!(response.contains("Trailer: x-checksum\r\n"), "{response}");
+        assert!(
+            response.ends_with("0\r\nx-checksum: 1\r\n\r\n"),
+            "{response}"
+        );
+
+        // Without TE: trailers, neither the codings nor the trailers are passed on
+        let (upstream, upstream_head) = start_raw_upstream(TRAILERS_RESPONSE).await;
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let proxy = start_proxy(ProxyService::new(TestProxy(uri))).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nTE: gzip\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_chunked(&mut stream).await;
+        let request = upstream_head.await.unwrap();
+        assert!(!request.to_lowercase().contains("te:"), "{request}");
+        assert!(!response.to_lowercase().contains("trailer:"), "{response}");
+        assert!(!response.contains("x-checksum"), "{response}");
+    }
+
+    /// Only lets through the POST bodies which look like JSON objects
+    struct ValidateBody(Uri);
+
+    #[async_trait]
+    impl ProxyTrait for ValidateBody {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        fn wants_request_body(&self, request: &RequestHeaders, _ctx: &mut ()) -> bool {
+            request.method == hyper::Method::POST
+        }
+
+        async fn request_body_filter(
+            &self,
+            _request: &RequestHeaders,
+            body: &Bytes,
+            _ctx: &mut (),
+        ) -> Result<(), Response<Body>> {
+            if body.starts_with(b"{") {
+                return Ok(());
+            }
+            Err(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(empty_body())
+                .unwrap())
+        }
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.0.clone())
+        }
+    }
+
+    /// An upstream answering with the body of the requests
+    async fn start_echo_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let on_request = service_fn(|request: Request<IncomingRequest>| async move {
+                    let body = collect_request_body(request.into_body()).await.unwrap();
+                    Ok::<_, Infallible>(Response::new(crate::full_body(body)))
+                });
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), on_request),
+                );
+            }
+        });
+        addr
+    }
+
+    /// Send a request, return the whole response of `content-length`
+    async fn request_with_body(proxy: SocketAddr, method: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "{method} / HTTP/1.1\r\nHost: localhost\r\ncontent-length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let head = read_head(&mut stream).await;
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        head + std::str::from_utf8(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_body_filter() {
+        let upstream = start_echo_upstream().await;
+        let uri: Uri = format!("http://{upstream}/").parse().unwrap();
+        let mut proxy = ProxyService::new(ValidateBody(uri));
+        proxy.set_max_request_body_size(16);
+        let proxy = start_proxy(proxy).await;
+
+        let response = request_with_body(proxy, "POST", r#"{"a":1}"#).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("\r\n\r\n{\"a\":1}"), "{response}");
+        let response = request_with_body(proxy, "POST", "a=1").await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        let response = request_with_body(proxy, "POST", &format!("{{{}}}", "a".repeat(16))).await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+
+        // The other requests are streamed without looking at their body
+        let response = request_with_body(proxy, "PUT", "a=1").await;
+        assert!(response.ends_with("\r\n\r\na=1"), "{response}");
+    }
+
+    /// An upstream answering with a body of the `x-type` and `x-repeat` of the requests
+    async fn start_typed_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let on_request = service_fn(|request: Request<IncomingRequest>| async move {
+                    let header = |name| request.headers()[name].to_str().unwrap();
+                    let body = "compressible ".repeat(header("x-repeat").parse().unwrap());
+                    let response = Response::builder()
+                        .header("content-type", header("x-type"))
+                        .body(crate::full_body(body.into()))
+                        .unwrap();
+                    Ok::<_, Infallible>(response)
+                });
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), on_request),
+                );
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_response_compression() {
+        let upstream = start_typed_upstream().await;
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let mut proxy = ProxyService::new(TestProxy(uri));
+        proxy.set_response_compression(Some(crate::compression::CompressionConfig::default()));
+        let proxy = start_proxy(proxy).await;
+        let client = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+        let get = |content_type: &'static str, repeat: usize, accept_encoding: &'static str| {
+            let request = Request::get(format!("http://{proxy}/"))
+                .header("x-type", content_type)
+                .header("x-repeat", repeat)
+                .header("accept-encoding", accept_encoding)
+                .body(Empty::new())
+                .unwrap();
+            client.request(request)
+        };
+
+        let response = get("text/plain", 1000, "br, gzip").await.unwrap();
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["vary"], "accept-encoding");
+        assert!(!response.headers().contains_key("content-length"));
+        let compressed = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(compressed.len() < 13 * 1000 / 10, "{}", compressed.len());
+        let decompressed = crate::compression::gunzip(&compressed);
+        assert_eq!(decompressed, "compressible ".repeat(1000).as_bytes());
+
+        // Too small, not accepted or not compressible
+        for (content_type, repeat, accept_encoding) in [
+            ("text/plain", 10, "gzip"),
+            ("text/plain", 1000, "br"),
+            ("image/png", 1000, "gzip"),
+        ] {
+            let response = get(content_type, repeat, accept_encoding).await.unwrap();
+            assert!(!response.headers().contains_key("content-encoding"));
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body.len(), "compressible ".len() * repeat);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_request_body_bytes() {
+        let upstream = start_echo_upstream().await;
+        let uri: Uri = format!("http://{upstream}/").parse().unwrap();
+        let mut proxy = ProxyService::new(ValidateBody(uri));
+        proxy.set_max_request_body_size(16);
+        proxy.set_max_request_body_bytes(Some(8));
+        let proxy = start_proxy(proxy).await;
+
+        // Streamed
+        let response = request_with_body(proxy, "PUT", "abcdefgh").await;
+        assert!(response.ends_with("\r\n\r\nabcdefgh"), "{response}");
+        let response = request_with_body(proxy, "PUT", "abcdefghi").await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+
+        // Buffered, under the buffering limit but not this one
+        let response = request_with_body(proxy, "POST", r#"{"a":12}"#).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        let response = request_with_body(proxy, "POST", r#"{"a":123}"#).await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+
+        // Rejected once the limit is crossed, without waiting for the rest of the body
+        let chunked = "PUT / HTTP/1.1\r\nHost: localhost\r\n\
+            transfer-encoding: chunked\r\n\r\n5\r\nabcde\r\n5\r\nfghij\r\n";
+        let response = raw_request(proxy, chunked).await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+        let chunked = "PUT / HTTP/1.1\r\nHost: localhost\r\n\
+            transfer-encoding: chunked\r\n\r\n5\r\nabcde\r\n3\r\nfgh\r\n0\r\n\r\n";
+        let response = raw_request(proxy, chunked).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    }
+
+    /// Redacts the secrets of the responses
+    struct Redact(Uri);
+
+    #[async_trait]
+    impl ProxyTrait for Redact {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.0.clone())
+        }
+
+        fn wants_response_body(&self, response: &ResponseHeaders, _ctx: &mut ()) -> bool {
+            response.status == StatusCode::OK
+        }
+
+        async fn response_body_filter(
+            &self,
+            _response: &mut ResponseHeaders,
+            body: Bytes,
+            _ctx: &mut (),
+        ) -> Result<Bytes, Response<Body>> {
+            let body = String::from_utf8_lossy(&body).replace("hunter2", "[redacted]");
+            Ok(Bytes::from(body))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_body_filter() {
+        let upstream = start_echo_upstream().await;
+        let uri: Uri = format!("http://{upstream}/").parse().unwrap();
+        let mut proxy = ProxyService::new(Redact(uri));
+        proxy.set_max_response_body_size(64);
+        let proxy = start_proxy(proxy).await;
+
+        let response = request_with_body(proxy, "POST", r#"{"password":"hunter2"}"#).await;
+        assert!(response.contains("content-length: 25\r\n"), "{response}");
+        assert!(
+            response.ends_with("\r\n\r\n{\"password\":\"[redacted]\"}"),
+            "{response}"
+        );
+        let response = request_with_body(proxy, "POST", &"a".repeat(100)).await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+    }
+
+    /// Tallies the bytes of the response bodies
+    struct Tally {
+        upstream: Uri,
+        total: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for Tally {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.upstream.clone())
+        }
+
+        fn response_body_transform(
+            &self,
+            _upstream_response: &mut ResponseHeaders,
+            body: IncomingRequest,
+            _ctx: &mut (),
+        ) -> Body {
+            let total = self.total.clone();
+            let body = body.map_frame(move |frame| {
+                if let Some(data) = frame.data_ref() {
+                    total.fetch_add(data.len(), Ordering::Relaxed);
+                }
+                frame
+            });
+            crate::proxy_trait::boxed_body(body.boxed())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_body_transform() {
+        let (upstream, _) = start_raw_upstream(TRAILERS_RESPONSE).await;
+        let total = Arc::new(AtomicUsize::new(0));
+        let proxy = Tally {
+            upstream: format!("http://{upstream}/").parse().unwrap(),
+            total: total.clone(),
+        };
+        let proxy = start_proxy(ProxyService::new(proxy)).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nTE: trailers\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_chunked(&mut stream).await;
+        // The trailers are still passed on
+        assert!(
+            response.ends_with("3\r\nabc\r\n0\r\nx-checksum: 1\r\n\r\n"),
+            "{response}"
+        );
+        assert_eq!(total.load(Ordering::Relaxed), 3);
+    }
+
+    /// Sends the first request to `dead`, then the retries to `alive`
+    struct Failover {
+        dead: Uri,
+        alive: Uri,
+        picks: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for Failover {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            match self.picks.fetch_add(1, Ordering::Relaxed) {
+                0 => Some(self.dead.clone()),
+                _ => Some(self.alive.clone()),
+            }
+        }
+
+        fn retry_policy(&self, attempt: usize, _error: &UpstreamError, _ctx: &mut ()) -> bool {
+            attempt < 2
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy() {
+        // Nothing listens anymore on the port of the listener once it is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead: Uri = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(listener);
+        let upstream = start_echo_upstream().await;
+        let alive: Uri = format!("http://{upstream}/").parse().unwrap();
+        let failover = |dead: &Uri| Failover {
+            dead: dead.clone(),
+            alive: alive.clone(),
+            picks: AtomicUsize::new(0),
+        };
+
+        // Retried with its empty body
+        let proxy = Arc::new(ProxyService::new(failover(&dead)));
+        let addr = serve(proxy.clone()).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert_eq!(proxy.inner.picks.load(Ordering::Relaxed), 2);
+
+        // A buffered body is sent again, from the same buffer
+        struct Buffer(Failover, AtomicUsize, Mutex<Vec<usize>>);
+        #[async_trait]
+        impl ProxyTrait for Buffer {
+            type CTX = ();
+
+            fn new_ctx(&self) -> Self::CTX {}
+
+            fn wants_request_body(&self, _request: &RequestHeaders, _ctx: &mut ()) -> bool {
+                true
+            }
+
+            async fn request_body_filter(
+                &self,
+                _request: &RequestHeaders,
+                _body: &Bytes,
+                _ctx: &mut (),
+            ) -> Result<(), Response<Body>> {
+                self.1.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+
+            async fn upstream_request_body_filter(
+                &self,
+                _request: &mut RequestHeaders,
+                body: Bytes,
+                _ctx: &mut (),
+            ) -> Bytes {
+                self.2.lock().unwrap().push(body.as_ptr() as usize);
+                body
+            }
+
+            async fn upstream_addr(&self, request: &RequestHeaders, ctx: &mut ()) -> Option<Uri> {
+                self.0.upstream_addr(request, ctx).await
+            }
+
+            fn retry_policy(&self, attempt: usize, error: &UpstreamError, ctx: &mut ()) -> bool {
+                self.0.retry_policy(attempt, error, ctx)
+            }
+        }
+        let buffer = Buffer(failover(&dead), AtomicUsize::new(0), Mutex::default());
+        let proxy = Arc::new(ProxyService::new(buffer));
+        let addr = serve(proxy.clone()).await;
+        let response = request_with_body(addr, "POST", "a=1").await;
+        assert!(response.ends_with("\r\n\r\na=1"), "{response}");
+        // Buffered once, each attempt sharing the buffer
+        assert_eq!(proxy.inner.1.load(Ordering::Relaxed), 1);
+        let sent = proxy.inner.2.lock().unwrap().clone();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0], sent[1]);
+
+        // A streamed body can't be sent again
+        let proxy = Arc::new(ProxyService::new(failover(&dead)));
+        let addr = serve(proxy.clone()).await;
+        let response = request_with_body(addr, "POST", "a=1").await;
+        assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+        assert_eq!(proxy.inner.picks.load(Ordering::Relaxed), 1);
+    }
+
+    struct Peers {
+        peers: Vec<Uri>,
+        picks: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for Peers {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            unreachable!("the candidates are given by upstream_peers")
+        }
+
+        async fn upstream_peers(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Vec<Uri> {
+            self.picks.fetch_add(1, Ordering::Relaxed);
+            self.peers.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_peers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead: Uri = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(listener);
+        let upstream = start_echo_upstream().await;
+        let alive: Uri = format!("http://{upstream}/").parse().unwrap();
+        let peers = |peers: Vec<Uri>| Peers {
+            peers,
+            picks: AtomicUsize::new(0),
+        };
+
+        // Fails over to the second candidate
+        let proxy = Arc::new(ProxyService::new(peers(vec![dead.clone(), alive])));
+        let addr = serve(proxy.clone()).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert_eq!(proxy.inner.picks.load(Ordering::Relaxed), 1);
+
+        // No candidate left to fail over to
+        let addr = start_proxy(ProxyService::new(peers(vec![dead]))).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+
+        // No candidate at all
+        let addr = start_proxy(ProxyService::new(peers(Vec::new()))).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+    }
+
+    /// An access log line
+    #[derive(Debug, PartialEq)]
+    struct Logged {
+        method: Method,
+        path: String,
+        upstream: Option<Uri>,
+        status: StatusCode,
+    }
+
+    /// Records an access log line per request, refusing the ones to `/deny`
+    struct AccessLog {
+        upstream: Uri,
+        logged: std::sync::Mutex<Vec<(Logged, Duration)>>,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for AccessLog {
+        type CTX = Option<Uri>;
+
+        fn new_ctx(&self) -> Self::CTX {
+            None
+        }
+
+        async fn request_filter(
+            &self,
+            request: &RequestHeaders,
+            _ctx: &mut Self::CTX,
+        ) -> Result<(), Response<Body>> {
+            if request.uri.path() == "/deny" {
+                return Err(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(empty_body())
+                    .unwrap());
+            }
+            Ok(())
+        }
+
+        async fn upstream_addr(
+            &self,
+            _request: &RequestHeaders,
+            _ctx: &mut Self::CTX,
+        ) -> Option<Uri> {
+            Some(self.upstream.clone())
+        }
+
+        async fn upstream_request_filter(&self, request: &mut RequestHeaders, ctx: &mut Self::CTX) {
+            *ctx = Some(request.uri.clone());
+        }
+
+        async fn logging(
+            &self,
+            request: &RequestHeaders,
+            response: &ResponseHeaders,
+            duration: Duration,
+            ctx: &mut Self::CTX,
+        ) {
+            let logged = Logged {
+                method: request.method.clone(),
+                path: request.uri.path().to_string(),
+                upstream: ctx.take(),
+                status: response.status,
+            };
+            self.logged.lock().unwrap().push((logged, duration));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_logging() {
+        let (upstream, _) = start_raw_upstream(CASED_RESPONSE).await;
+        let upstream: Uri = format!("http://{upstream}/").parse().unwrap();
+        let proxy = Arc::new(ProxyService::new(AccessLog {
+            upstream: upstream.clone(),
+            logged: Default::default(),
+        }));
+        let addr = serve(proxy.clone()).await;
+
+        let start = Instant::now();
+        let response = raw_request(addr, CASED_REQUEST).await;
+        let elapsed = start.elapsed();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        let response = raw_request(addr, "GET /deny HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+
+        let logged = proxy.inner.logged.lock().unwrap();
+        assert_eq!(logged.len(), 2);
+        let (proxied, duration) = &logged[0];
+        assert_eq!(
+            proxied,
+            &Logged {
+                method: Method::GET,
+                path: "/".to_string(),
+                upstream: Some(upstream),
+                status: StatusCode::OK,
+            }
+        );
+        assert!(*duration <= elapsed);
+        // A response of a filter is logged too, without an upstream
+        assert_eq!(
+            logged[1].0,
+            Logged {
+                method: Method::GET,
+                path: "/deny".to_string(),
+                upstream: None,
+                status: StatusCode::FORBIDDEN,
+            }
+        );
+    }
+
+    /// Answers the requests it fails to proxy with their path
+    struct FailedPath {
+        upstream: Uri,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for FailedPath {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.upstream.clone())
+        }
+
+        fn fail_to_connect(
+            &self,
+            request: &FailedRequestInfo,
+            _ctx: &mut (),
+            upstream_addr: &Uri,
+            _error: UpstreamError,
+        ) -> Option<Response<Body>> {
+            assert_eq!(upstream_addr, &self.upstream);
+            assert_eq!(request.method, Method::GET);
+            assert_eq!(request.host.as_ref().unwrap(), "localhost");
+            let response = Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .header("x-failed-path", request.uri.path())
+                .body(empty_body())
+                .unwrap();
+            Some(response)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fail_to_connect_request_info() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead: Uri = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(listener);
+        let addr = start_proxy(ProxyService::new(FailedPath { upstream: dead })).await;
+
+        let request = "GET /some/path?a=1 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let response = raw_request(addr, request).await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+        assert!(
+            response.contains("x-failed-path: /some/path\r\n"),
+            "{response}"
+        );
+    }
+
+    /// Caches the responses to the `GET` requests by path
+    struct Cache {
+        upstream: Uri,
+        picks: AtomicUsize,
+        cache: std::sync::Mutex<std::collections::HashMap<String, Bytes>>,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for Cache {
+        /// The cache key of a miss
+        type CTX = Option<String>;
+
+        fn new_ctx(&self) -> Self::CTX {
+            None
+        }
+
+        async fn cache_lookup(
+            &self,
+            request: &RequestHeaders,
+            ctx: &mut Self::CTX,
+        ) -> Option<Response<Body>> {
+            if request.method != Method::GET {
+                return None;
+            }
+            let key = request.uri.path().to_string();
+            let Some(body) = self.cache.lock().unwrap().get(&key).cloned() else {
+                *ctx = Some(key);
+                return None;
+            };
+            let response = Response::builder()
+                .header("x-cache", "hit")
+                .body(full_body(body))
+                .unwrap();
+            Some(response)
+        }
+
+        async fn upstream_addr(
+            &self,
+            _request: &RequestHeaders,
+            _ctx: &mut Self::CTX,
+        ) -> Option<Uri> {
+            self.picks.fetch_add(1, Ordering::Relaxed);
+            Some(self.upstream.clone())
+        }
+
+        fn wants_response_body(
+            &self,
+            upstream_response: &ResponseHeaders,
+            ctx: &mut Self::CTX,
+        ) -> bool {
+            ctx.is_some() && upstream_response.status == StatusCode::OK
+        }
+
+        async fn cache_store(
+            &self,
+            _upstream_response: &ResponseHeaders,
+            body: &Bytes,
+            ctx: &mut Self::CTX,
+        ) {
+            let key = ctx.take().unwrap();
+            self.cache.lock().unwrap().insert(key, body.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let upstream = start_echo_upstream().await;
+        let proxy = Arc::new(ProxyService::new(Cache {
+            upstream: format!("http://{upstream}/").parse().unwrap(),
+            picks: AtomicUsize::new(0),
+            cache: Default::default(),
+        }));
+        let addr = serve(proxy.clone()).await;
+
+        let response = request_with_body(addr, "GET", "cached").await;
+        assert!(!response.contains("x-cache"), "{response}");
+        assert!(response.ends_with("\r\n\r\ncached"), "{response}");
+        assert_eq!(proxy.inner.picks.load(Ordering::Relaxed), 1);
+
+        // Served from the cache, without going to the upstream
+        let response = request_with_body(addr, "GET", "fresh").await;
+        assert!(response.contains("x-cache: hit\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\ncached"), "{response}");
+        assert_eq!(proxy.inner.picks.load(Ordering::Relaxed), 1);
+
+        // Not cached
+        let response = request_with_body(addr, "POST", "fresh").await;
+        assert!(response.ends_with("\r\n\r\nfresh"), "{response}");
+        assert_eq!(proxy.inner.picks.load(Ordering::Relaxed), 2);
+    }
+
+    /// Wraps the body of the `POST` requests
+    struct Wrap(Uri);
+
+    #[async_trait]
+    impl ProxyTrait for Wrap {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        fn wants_request_body(&self, request: &RequestHeaders, _ctx: &mut ()) -> bool {
+            request.method == Method::POST
+        }
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.0.clone())
+        }
+
+        async fn upstream_request_body_filter(
+            &self,
+            _request: &mut RequestHeaders,
+            body: Bytes,
+            _ctx: &mut (),
+        ) -> Bytes {
+            [b"{\"data\":\"", &body[..], b"\"}"].concat().into()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_request_body_filter() {
+        let upstream = start_echo_upstream().await;
+        let upstream = format!("http://{upstream}/").parse().unwrap();
+        let addr = start_proxy(ProxyService::new(Wrap(upstream))).await;
+
+        // The upstream receives the new body, with its length
+        let response = request_with_body(addr, "POST", "a=1").await;
+        assert!(
+            response.ends_with("\r\n\r\n{\"data\":\"a=1\"}"),
+            "{response}"
+        );
+
+        // A streamed body is sent as is
+        let response = request_with_body(addr, "PUT", "a=1").await;
+        assert!(response.ends_with("\r\n\r\na=1"), "{response}");
+    }
+
+    /// Refuses the requests of the clients on its deny list
+    struct DenyClients {
+        upstream: Uri,
+        denied: std::sync::Mutex<Vec<SocketAddr>>,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for DenyClients {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            request: &RequestHeaders,
+            _ctx: &mut (),
+        ) -> Result<(), Response<Body>> {
+            let ClientAddr(client) = request.extensions.get().copied().unwrap();
+            if !self.denied.lock().unwrap().contains(&client) {
+                return Ok(());
+            }
+            Err(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(empty_body())
+                .unwrap())
+        }
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.upstream.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_addr_in_request_filter() {
+        let (upstream, _) = start_raw_upstream(CASED_RESPONSE).await;
+        let proxy = Arc::new(ProxyService::new(DenyClients {
+            upstream: format!("http://{upstream}/").parse().unwrap(),
+            denied: Default::default(),
+        }));
+        let addr = serve(proxy.clone()).await;
+
+        let mut denied = TcpStream::connect(addr).await.unwrap();
+        let client = denied.local_addr().unwrap();
+        proxy.inner.denied.lock().unwrap().push(client);
+        denied.write_all(CASED_REQUEST.as_bytes()).await.unwrap();
+        let response = read_head(&mut denied).await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+
+        // Another connection has another port
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    }
+
+    /// Records the response trailers it sees
+    struct RecordTrailers {
+        upstream: Uri,
+        trailers: std::sync::Mutex<Vec<HeaderMap>>,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for RecordTrailers {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.upstream.clone())
+        }
+
+        fn wants_response_trailers(
+            &self,
+            _upstream_response: &ResponseHeaders,
+            _ctx: &mut (),
+        ) -> bool {
+            true
+        }
+
+        fn response_trailers_filter(&self, trailers: &mut HeaderMap) {
+            self.trailers.lock().unwrap().push(trailers.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_trailers_filter() {
+        let (upstream, _) = start_raw_upstream(TRAILERS_RESPONSE).await;
+        let proxy = Arc::new(ProxyService::new(RecordTrailers {
+            upstream: format!("http://{upstream}/").parse().unwrap(),
+            trailers: Default::default(),
+        }));
+        let addr = serve(proxy.clone()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nTE: trailers\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_chunked(&mut stream).await;
+        assert!(
+            response.ends_with("3\r\nabc\r\n0\r\nx-checksum: 1\r\n\r\n"),
+            "{response}"
+        );
+        let trailers = proxy.inner.trailers.lock().unwrap();
+        assert_eq!(trailers.len(), 1);
+        assert_eq!(trailers[0]["x-checksum"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_http2_downstream() {
+        let (upstream, upstream_head) = start_raw_upstream(CASED_RESPONSE).await;
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let proxy = Arc::new(ProxyService::new(TestProxy(uri)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let proxy = proxy.clone();
+                tokio::spawn(async move { proxy.serve_connection(stream, Some(peer), true).await });
+            }
+        });
+
+        let client = Client::builder(TokioExecutor::new())
+            .http2_only(true)
+            .build_http::<Empty<Bytes>>();
+        let request = Request::get(format!("http://localhost:{}/", addr.port()))
+            .body(Empty::new())
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.version(), Version::HTTP_2);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-upstream-header"], "1");
+
+        // Forwarded over HTTP/1, with the authority as the host
+        let request = upstream_head.await.unwrap();
+        assert!(request.starts_with("GET / HTTP/1.1\r\n"), "{request}");
+        let host = format!("host: localhost:{}\r\n", addr.port());
+        assert!(request.contains(&host), "{request}");
+    }
+
+    /// A HTTP/2 only upstream answering with the version of the requests
+    async fn start_h2_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let on_request = service_fn(|request: Request<IncomingRequest>| async move {
+                    let version = format!("{:?}", request.version());
+                    let response = Response::builder()
+                        .header("x-version", version)
+                        .body(empty_body())
+                        .unwrap();
+                    Ok::<_, Infallible>(response)
+                });
+                let builder = http2::Builder::new(TokioExecutor::new());
+                tokio::spawn(builder.serve_connection(TokioIo::new(stream), on_request));
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_upstream_http2() {
+        let upstream = start_h2_upstream().await;
+        let uri: Uri = format!("http://{upstream}/").parse().unwrap();
+        let mut proxy = ProxyService::new(TestProxy(uri.clone()));
+        proxy.set_upstream_config(UpstreamConfig {
+            version: UpstreamVersion::Http2,
+            plaintext: true,
+            ..Default::default()
+        });
+        let addr = start_proxy(proxy).await;
+
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.contains("x-version: HTTP/2.0\r\n"), "{response}");
+
+        // A HTTP/1 upstream connection can't speak to it
+        let addr = start_proxy(ProxyService::new(TestProxy(uri))).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+    }
+
+    /// Answers the requests it fails to proxy with the error
+    struct ConnectError(Uri);
+
+    #[async_trait]
+    impl ProxyTrait for ConnectError {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.0.clone())
+        }
+
+        fn fail_to_connect(
+            &self,
+            _request: &FailedRequestInfo,
+            _ctx: &mut (),
+            _upstream_addr: &Uri,
+            error: UpstreamError,
+        ) -> Option<Response<Body>> {
+            let cause = std::error::Error::source(&error).unwrap().to_string();
+            let response = Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .header("x-error", cause)
+                .body(empty_body())
+                .unwrap();
+            Some(response)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_plaintext() {
+        let upstream = "https://localhost/".parse().unwrap();
+        let mut proxy = ProxyService::new(ConnectError(upstream));
+        proxy.set_upstream_config(UpstreamConfig {
+            plaintext: true,
+            ..Default::default()
+        });
+        let addr = start_proxy(proxy).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+        assert!(
+            response.contains("x-error: https://localhost/ is not a plain text upstream\r\n"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http1_config() {
+        let (upstream, _) = start_raw_upstream(CASED_RESPONSE).await;
+        let uri: Uri = format!("http://{upstream}/").parse().unwrap();
+        let mut proxy = ProxyService::new(TestProxy(uri));
+        proxy.set_http1_config(Http1Config {
+            max_buf_size: Some(8 * 1024),
+            max_headers: Some(4),
+            ..Default::default()
+        });
+        let addr = start_proxy(proxy).await;
+
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Large: {}\r\n\r\n",
+            "a".repeat(10 * 1024)
+        );
+        let response = raw_request(addr, &request).await;
+        assert!(response.starts_with("HTTP/1.1 431"), "{response}");
+
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nA: 1\r\nB: 1\r\nC: 1\r\nD: 1\r\n\r\n";
+        let response = raw_request(addr, request).await;
+        assert!(response.starts_with("HTTP/1.1 431"), "{response}");
+
+        // Within the limits
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    }
+
+    /// A WebSocket upstream echoing the bytes it receives once the connection is upgraded
+    async fn start_websocket_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let on_request = service_fn(|mut request: Request<IncomingRequest>| async move {
+                    assert_eq!(request.headers()["upgrade"], "websocket");
+                    let upgrade = hyper::upgrade::on(&mut request);
+                    tokio::spawn(async move {
+                        let mut upgraded = TokioIo::new(upgrade.await.unwrap());
+                        let mut buf = [0; 1024];
+                        loop {
+                            match upgraded.read(&mut buf).await {
+                                Ok(0) | Err(_) => break,
+                                Ok(n) => upgraded.write_all(&buf[..n]).await.unwrap(),
+                            }
+                        }
+                    });
+                    let response = Response::builder()
+                        .status(StatusCode::SWITCHING_PROTOCOLS)
+                        .header(CONNECTION, "upgrade")
+                        .header("upgrade", "websocket")
+                        .header("sec-websocket-accept", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+                        .body(empty_body())
+                        .unwrap();
+                    Ok::<_, Infallible>(response)
+                });
+                tokio::spawn(
+                    http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), on_request)
+                        .with_upgrades(),
+                );
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade() {
+        let upstream = start_websocket_upstream().await;
+        let uri = format!("http://{upstream}/").parse().unwrap();
+        let addr = start_proxy(ProxyService::new(TestProxy(uri))).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = "GET /chat HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\n\
+            Upgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_head(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 101"), "{response}");
+        assert!(response.contains("upgrade: websocket\r\n"), "{response}");
+        assert!(
+            response.contains("sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+            "{response}"
+        );
+
+        // The bytes go both ways through the proxy
+        for message in ["hello", "world"] {
+            stream.write_all(message.as_bytes()).await.unwrap();
+            let mut echo = vec![0; message.len()];
+            stream.read_exact(&mut echo).await.unwrap();
+            assert_eq!(echo, message.as_bytes());
+        }
+
+        // Closing the downstream closes the upstream, which closes the tunnel
+        stream.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    /// An upstream sending its 5 bytes body one at a time, pausing for `pause` after each of
+    /// them, then holding the connection
+    async fn start_slow_upstream(pause: Duration) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            let head = "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n";
+            stream.write_all(head.as_bytes()).await.unwrap();
+            for byte in b"abcde" {
+                stream.write_all(&[*byte]).await.unwrap();
+                time::sleep(pause).await;
+            }
+            time::sleep(Duration::from_secs(60)).await;
+        });
+        addr
+    }
+
+    /// Send a request to `proxy`, return the response up to the connection close
+    async fn read_until_close(proxy: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upstream_body_timeout() {
+        // Slow but progressing
+        let upstream = start_slow_upstream(Duration::from_millis(50)).await;
+        let mut proxy =
+            ProxyService::new(TestProxy(format!("http://{upstream}/").parse().unwrap()));
+        proxy.set_upstream_body_timeout(Duration::from_millis(500));
+        let addr = start_proxy(proxy).await;
+        let response = read_until_close(addr).await;
+        assert!(response.ends_with("\r\n\r\nabcde"), "{response}");
+
+        // Stalled in the middle of the body
+        let upstream = start_slow_upstream(Duration::from_secs(60)).await;
+        let mut proxy =
+            ProxyService::new(TestProxy(format!("http://{upstream}/").parse().unwrap()));
+        proxy.set_upstream_body_timeout(Duration::from_millis(100));
+        let addr = start_proxy(proxy).await;
+        let response = time::timeout(Duration::from_secs(5), read_until_close(addr))
+            .await
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("\r\n\r\na"), "{response}");
+    }
+
+    /// A self-signed certificate for `upstream.test`, with its key
+    fn upstream_test_cert() -> (
+        openssl::x509::X509,
+        openssl::pkey::PKey<openssl::pkey::Private>,
+    ) {
+        use openssl::{asn1::Asn1Time, bn::BigNum, ec, hash::MessageDigest, nid::Nid, x509};
+
+        let group = ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = openssl::pkey::PKey::from_ec_key(ec::EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "upstream.test").unwrap();
+        let name = name.build();
+
+        let mut cert = x509::X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = x509::extension::SubjectAlternativeName::new()
+            .dns("upstream.test")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build(), key)
+    }
+
+    /// An HTTPS upstream with `cert`, answering with the SNI of the connections
+    async fn start_tls_upstream(
+        cert: &openssl::x509::X509,
+        key: &openssl::pkey::PKey<openssl::pkey::Private>,
+    ) -> SocketAddr {
+        use openssl::ssl::{NameType, Ssl, SslAcceptor, SslMethod};
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(cert).unwrap();
+        acceptor.set_private_key(key).unwrap();
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let ssl = Ssl::new(acceptor.context()).unwrap();
+                let mut stream = tokio_openssl::SslStream::new(ssl, stream).unwrap();
+                // The handshake fails when the proxy doesn't trust the certificate
+                if Pin::new(&mut stream).accept().await.is_err() {
+                    continue;
+                }
+                let sni = stream
+                    .ssl()
+                    .servername(NameType::HOST_NAME)
+                    .unwrap_or_default()
+                    .to_string();
+                let on_request = service_fn(move |_request: Request<IncomingRequest>| {
+                    let response = Response::builder()
+                        .header("x-sni", sni.clone())
+                        .body(empty_body())
+                        .unwrap();
+                    async move { Ok::<_, Infallible>(response) }
+                });
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), on_request),
+                );
+            }
+        });
+        addr
+    }
+
+    /// Dials its upstream by IP, with its TLS settings if any
+    struct DialIp {
+        upstream: Uri,
+        tls: Option<UpstreamTls>,
+    }
+
+    #[async_trait]
+    impl ProxyTrait for DialIp {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            Some(self.upstream.clone())
+        }
+
+        async fn upstream_request_filter(&self, request: &mut RequestHeaders, _ctx: &mut ()) {
+            if let Some(tls) = self.tls.clone() {
+                request.extensions.insert(tls);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_tls() {
+        let (cert, key) = upstream_test_cert();
+        let upstream = start_tls_upstream(&cert, &key).await;
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(cert.to_der().unwrap()))
+            .unwrap();
+        let proxy = |tls: Option<UpstreamTls>, roots: Option<rustls::RootCertStore>| {
+            let upstream = format!("https://{upstream}/").parse().unwrap();
+            let mut proxy = ProxyService::new(DialIp { upstream, tls });
+            proxy.set_upstream_config(UpstreamConfig {
+                roots,
+                ..Default::default()
+            });
+            proxy
+        };
+
+        // The certificate isn't for the IP
+        let addr = start_proxy(proxy(None, Some(roots.clone()))).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+
+        // Verified against the name of the request
+        let tls = UpstreamTls {
+            server_name: Some("upstream.test".to_string()),
+            ..Default::default()
+        };
+        let addr = start_proxy(proxy(Some(tls), Some(roots))).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.contains("x-sni: upstream.test\r\n"), "{response}");
+
+        // Not verified at all
+        let tls = UpstreamTls {
+            danger_accept_invalid_certs: true,
+            ..Default::default()
+        };
+        let addr = start_proxy(proxy(Some(tls), None)).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    }
+
+    /// An in-memory upstream connection
+    struct DuplexIo(TokioIo<tokio::io::DuplexStream>);
+
+    impl Read for DuplexIo {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: ReadBufCursor<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl Write for DuplexIo {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    impl Connection for DuplexIo {
+        fn connected(&self) -> Connected {
+            Connected::new()
+        }
+    }
+
+    /// Connects to an in-memory upstream serving `CASED_RESPONSE`, recording the dialed URIs
+    #[derive(Clone, Default)]
+    struct DuplexConnector(Arc<Mutex<Vec<Uri>>>);
+
+    impl tower_service::Service<Uri> for DuplexConnector {
+        type Response = DuplexIo;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<DuplexIo, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, uri: Uri) -> Self::Future {
+            self.0.lock().unwrap().push(uri);
+            let (client, mut server) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0; 1];
+                    if server.read(&mut byte).await.unwrap() == 0 {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                server.write_all(CASED_RESPONSE.as_bytes()).await.unwrap();
+            });
+            std::future::ready(Ok(DuplexIo(TokioIo::new(client))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_connector() {
+        let connector = DuplexConnector::default();
+        let upstream = "http://upstream.test/".parse().unwrap();
+        let proxy = ProxyService::with_connector(TestProxy(upstream), connector.clone());
+        let addr = start_proxy(proxy).await;
+
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        let dialed = connector.0.lock().unwrap().clone();
+        assert_eq!(dialed, vec![Uri::from_static("http://upstream.test/")]);
+    }
+
+    /// Refuses every connection, counting the dials
+    #[derive(Clone, Default)]
+    struct RefusingConnector(Arc<AtomicUsize>);
+
+    impl tower_service::Service<Uri> for RefusingConnector {
+        type Response = DuplexIo;
+        type Error = std::io::Error;
+        type Future = std::future::Ready<Result<DuplexIo, std::io::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _uri: Uri) -> Self::Future {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            std::future::ready(Err(std::io::ErrorKind::ConnectionRefused.into()))
+        }
+    }
+
+    /// Fails over between dead upstreams and always retries
+    struct DeadPeers;
+
+    #[async_trait]
+    impl ProxyTrait for DeadPeers {
+        type CTX = ();
+
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_addr(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Option<Uri> {
+            None
+        }
+
+        async fn upstream_peers(&self, _request: &RequestHeaders, _ctx: &mut ()) -> Vec<Uri> {
+            (1..=5)
+                .map(|peer| format!("http://dead-{peer}.test/").parse().unwrap())
+                .collect()
+        }
+
+        fn retry_policy(&self, _attempt: usize, _error: &UpstreamError, _ctx: &mut ()) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_attempts_across_retries_and_failovers() {
+        let connector = RefusingConnector::default();
+        let mut proxy = ProxyService::with_connector(DeadPeers, connector.clone());
+        proxy.set_max_attempts(3);
+        let addr = start_proxy(proxy).await;
+
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+        assert_eq!(connector.0.load(Ordering::Relaxed), 3);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_metrics() {
+        use crate::metrics;
+
+        let (upstream, _) = start_raw_upstream(CASED_RESPONSE).await;
+        let ok = metrics::requests("2xx");
+        let addr = start_proxy(ProxyService::new(TestProxy(
+            format!("http://{upstream}/").parse().unwrap(),
+        )))
+        .await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(metrics::requests("2xx") > ok);
+
+        // Nothing listens on the upstream port anymore
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused: Uri = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(listener);
+        let failed = metrics::requests("5xx");
+        let addr = start_proxy(ProxyService::new(TestProxy(refused.clone()))).await;
+        let response = raw_request(addr, CASED_REQUEST).await;
+        assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+        assert!(metrics::requests("5xx") > failed);
+        assert_eq!(metrics::connect_failures(&refused), 1);
+
+        let rendered = metrics::render();
+        assert!(
+            rendered.contains("yapf_requests_total{status=\"2xx\"}"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("yapf_request_duration_seconds_count"),
+            "{rendered}"
+        );
+        assert!(rendered.contains(&format!(
+            "yapf_upstream_connect_failures_total{{upstream=\"{}\"}} 1",
+            refused.authority().unwrap()
+        )));
+    }
 }
@@ -1,16 +1,111 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
 use async_trait::async_trait;
-use http_body_util::{Either, Empty, Full};
+use http_body_util::{combinators, BodyExt, Either, Empty, Full};
 use hyper::{
     body::Bytes,
-    body::Incoming,
-    http::{request, response},
+    body::{Frame, Incoming, SizeHint},
+    http::{request, response, HeaderMap, HeaderValue, Method, StatusCode},
     Response, Uri,
 };
 pub use hyper_util::client::legacy::Error as UpstreamError;
 
 pub type RequestHeaders = request::Parts;
 pub type ResponseHeaders = response::Parts;
-pub type Body = Either<Either<Empty<Bytes>, Full<Bytes>>, Incoming>;
+pub type Body = Either<Either<Empty<Bytes>, Full<Bytes>>, StreamedBody>;
+
+/// An adapter over the upstream body, see [Proxy::response_body_transform]
+pub type BoxBody = combinators::BoxBody<Bytes, hyper::Error>;
+
+/// The errors of a [StreamedBody]
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A body streamed from the upstream, as it is received or through an adapter
+#[derive(Debug)]
+pub enum StreamedBody {
+    Upstream(Incoming),
+    Boxed(BoxBody),
+    /// An adapter of the proxy itself, e.g. for [Proxy::response_trailers_filter]
+    Wrapped(combinators::BoxBody<Bytes, BoxError>),
+}
+
+impl hyper::body::Body for StreamedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        match self.get_mut() {
+            Self::Upstream(body) => Pin::new(body).poll_frame(cx).map_err(Into::into),
+            Self::Boxed(body) => Pin::new(body).poll_frame(cx).map_err(Into::into),
+            Self::Wrapped(body) => Pin::new(body).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            Self::Upstream(body) => body.is_end_stream(),
+            Self::Boxed(body) => body.is_end_stream(),
+            Self::Wrapped(body) => body.is_end_stream(),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            Self::Upstream(body) => body.size_hint(),
+            Self::Boxed(body) => body.size_hint(),
+            Self::Wrapped(body) => body.size_hint(),
+        }
+    }
+}
+
+/// The address of the downstream client, e.g. to rate limit by IP.
+///
+/// It is in the extensions of the [RequestHeaders] given to the request hooks, when the
+/// connection has a peer address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAddr(pub std::net::SocketAddr);
+
+/// The time it took to receive the upstream response headers.
+///
+/// It is in the extensions of the [ResponseHeaders] given to [Proxy::response_filter], e.g. to
+/// stamp it on the response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UpstreamLatency(pub std::time::Duration);
+
+/// Whether the upstream request was sent over a new connection or a pooled one, e.g. to tell
+/// the connection setup apart in the [UpstreamLatency].
+///
+/// It is in the extensions of the [ResponseHeaders] given to [Proxy::upstream_latency] and
+/// [Proxy::response_filter].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamConnection {
+    New,
+    Reused,
+}
+
+/// What [Proxy::fail_to_connect] can see of the request, captured before it was sent to the
+/// upstream.
+#[derive(Clone, Debug)]
+pub struct FailedRequestInfo {
+    pub method: Method,
+    /// The uri of the downstream request, not the upstream one
+    pub uri: Uri,
+    pub host: Option<HeaderValue>,
+}
+
+impl FailedRequestInfo {
+    pub fn new(request: &RequestHeaders) -> Self {
+        Self {
+            method: request.method.clone(),
+            uri: request.uri.clone(),
+            host: request.headers.get(hyper::header::HOST).cloned(),
+        }
+    }
+}
 
 pub fn empty_body() -> Body {
     Either::Left(Either::Left(Empty::new()))
@@ -20,6 +115,74 @@ pub fn full_body(body: Bytes) -> Body {
     Either::Left(Either::Right(Full::new(body)))
 }
 
+/// Stream `body` as it is received, e.g. the upstream body given to
+/// [Proxy::response_body_transform]
+pub fn streamed_body(body: Incoming) -> Body {
+    Either::Right(StreamedBody::Upstream(body))
+}
+
+pub fn boxed_body(body: BoxBody) -> Body {
+    Either::Right(StreamedBody::Boxed(body))
+}
+
+/// A body which could not be buffered to the end, e.g. its sender closed the connection before
+/// all of it was received.
+#[derive(Debug)]
+pub enum BodyError {
+    /// Reading the downstream request body failed
+    Downstream(hyper::Error),
+    /// Reading the upstream response body failed
+    Upstream(hyper::Error),
+}
+
+impl BodyError {
+    /// `400` if the downstream sent a truncated body, `502` if the upstream did
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Downstream(_) => StatusCode::BAD_REQUEST,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        Response::builder()
+            .status(self.status())
+            .body(empty_body())
+            .unwrap()
+    }
+}
+
+impl std::fmt::Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Downstream(e) => write!(f, "failed to read the request body: {e}"),
+            Self::Upstream(e) => write!(f, "failed to read the upstream response body: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+/// Buffer the whole body of a downstream request.
+///
+/// A truncated body is an error rather than a partial buffer.
+pub async fn collect_request_body(body: Incoming) -> Result<Bytes, BodyError> {
+    match body.collect().await {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(e) => Err(BodyError::Downstream(e)),
+    }
+}
+
+/// Buffer the whole body of an upstream response.
+///
+/// A truncated body is an error rather than a partial buffer.
+pub async fn collect_response_body(body: Incoming) -> Result<Bytes, BodyError> {
+    match body.collect().await {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(e) => Err(BodyError::Upstream(e)),
+    }
+}
+
 #[async_trait]
 pub trait Proxy {
     /// The per request object to share state across the different filters
@@ -39,23 +202,88 @@ pub trait Proxy {
     ) -> Result<(), Response<Body>> {
         Ok(())
     }
+    /// Answer the request from a cache, without going to the upstream, when it returns a
+    /// response. Called after [Proxy::request_filter], the requests it misses go on as usual.
+    ///
+    /// The cache key of a miss can be kept in the `ctx` for [Proxy::cache_store].
+    async fn cache_lookup(
+        &self,
+        _request: &RequestHeaders,
+        _ctx: &mut Self::CTX,
+    ) -> Option<Response<Body>> {
+        None
+    }
+
+    /// Whether [Proxy::request_body_filter] should see the body of this request, `false` by
+    /// default.
+    ///
+    /// The body is buffered for that, so only opt in for the requests whose body is small and
+    /// worth inspecting, the other ones are streamed to the upstream.
+    fn wants_request_body(&self, _request: &RequestHeaders, _ctx: &mut Self::CTX) -> bool {
+        false
+    }
+
+    /// Validate the buffered body of a request, e.g. check it against a schema or verify its
+    /// signature, before [Proxy::upstream_addr] is resolved.
+    ///
+    /// Only called for the requests [Proxy::wants_request_body] opts in, the body is then sent
+    /// as is to the upstream.
+    async fn request_body_filter(
+        &self,
+        _request: &RequestHeaders,
+        _body: &Bytes,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Response<Body>> {
+        Ok(())
+    }
+
     /// Define where the proxy should sent the request to.
     ///
     /// The returned [Uri] contains the information regarding where this request should be forwarded to.
     async fn upstream_addr(&self, request: &RequestHeaders, ctx: &mut Self::CTX) -> Option<Uri>;
 
+    /// Define the upstreams to try in order, e.g. the backends of a `LoadBalancer` ranked by
+    /// preference. Only the one of [Proxy::upstream_addr] by default.
+    ///
+    /// The request fails over to the next candidate when it can't connect to one, as long as
+    /// its body can be sent again (see [Proxy::retry_policy]) and attempts are left. No
+    /// candidate at all is a `503`.
+    async fn upstream_peers(&self, request: &RequestHeaders, ctx: &mut Self::CTX) -> Vec<Uri>
+    where
+        Self: Sync,
+        Self::CTX: Send,
+    {
+        self.upstream_addr(request, ctx).await.into_iter().collect()
+    }
+
     /// Modify the request header before it is send to the upstream
     ///
-    /// This is the last chance to modify the request before it is sent to the upstream.
+    /// This is the last chance to modify the request before it is sent to the upstream, e.g. to
+    /// insert a [crate::proxy::UpstreamTls] in its extensions for the TLS connection.
     async fn upstream_request_filter(&self, _request: &mut RequestHeaders, _ctx: &mut Self::CTX) {}
 
+    /// Replace the body sent to the upstream, e.g. to sign or transform it, after
+    /// [Proxy::upstream_request_filter]. It is sent as is by default.
+    ///
+    /// Only called for the requests buffered for [Proxy::request_body_filter], on every attempt
+    /// with the body as it was received. The `content-length` is set to the size of the
+    /// returned body, unless the request has trailers.
+    async fn upstream_request_body_filter(
+        &self,
+        _request: &mut RequestHeaders,
+        body: Bytes,
+        _ctx: &mut Self::CTX,
+    ) -> Bytes {
+        body
+    }
+
     /// This filter is called when there is an error in the process of establishing a connection
     /// to the upstream.
     ///
     /// Users can return a response to be sent to the downstream or a 500 error will be sent by default.
     fn fail_to_connect(
         &self,
-        // _request: &RequestHeaders,  TODO: Figure how to clone this
+        _request: &FailedRequestInfo,
         _ctx: &mut Self::CTX,
         _upstream_addr: &Uri,
         _error: UpstreamError,
@@ -63,9 +291,34 @@ pub trait Proxy {
         None
     }
 
+    /// Whether to retry the request after the `attempt`th one failed with `error`, e.g. for
+    /// idempotent methods recorded in the `ctx`. Never by default.
+    ///
+    /// A retry goes to the next candidate of [Proxy::upstream_peers], or picks them again once
+    /// all of them were tried, so that it can go to another backend. Only the requests with an
+    /// empty body, or with one buffered for [Proxy::request_body_filter], are retried since a
+    /// streamed body can't be sent twice. The retries are bounded by the attempts of
+    /// `ProxyService::set_max_attempts`, and once no retry is made [Proxy::fail_to_connect] is
+    /// called.
+    fn retry_policy(&self, _attempt: usize, _error: &UpstreamError, _ctx: &mut Self::CTX) -> bool {
+        false
+    }
+
+    /// This hook is called before a keep-alive downstream connection is reused for another
+    /// request, once the response to the current one is ready.
+    ///
+    /// `requests` is the number of requests served on the connection so far, this one included.
+    /// Return `false` to close the connection after this response, e.g. to re-validate a per
+    /// connection authentication.
+    fn reuse_connection(&self, _requests: usize, _ctx: &mut Self::CTX) -> bool {
+        true
+    }
+
     /// This hook is called when the upstream response is received.
     /// The `latency` is the time it took to receive the response from the upstream.
     ///
+    /// The [UpstreamConnection] in the extensions of the response tells whether that includes
+    /// connecting to the upstream.
     async fn upstream_latency(
         &self,
         _upstream_response: &ResponseHeaders,
@@ -76,6 +329,7 @@ pub trait Proxy {
 
     /// Modify the response header before it is send to the downstream
     ///
+    /// The [UpstreamLatency] of the response is available in its extensions.
     async fn response_filter(
         &self,
         _upstream_response: &mut ResponseHeaders,
@@ -83,4 +337,94 @@ pub trait Proxy {
     ) -> Result<(), Response<Body>> {
         Ok(())
     }
+
+    /// Wrap the upstream response body while it is streamed to the downstream, e.g. to count
+    /// its bytes or inject a prefix, with [boxed_body]. It is streamed as is by default.
+    ///
+    /// An adapter should pass on the errors and the trailers of the upstream body, and adjust
+    /// the `content-length` of the response if it changes the size of the body. Not called for
+    /// the responses buffered for [Proxy::response_body_filter].
+    fn response_body_transform(
+        &self,
+        _upstream_response: &mut ResponseHeaders,
+        body: Incoming,
+        _ctx: &mut Self::CTX,
+    ) -> Body {
+        streamed_body(body)
+    }
+
+    /// Whether [Proxy::response_body_filter] should rewrite the body of this response, `false`
+    /// by default.
+    ///
+    /// The body is buffered for that, so only opt in for the responses whose body is small and
+    /// worth rewriting, e.g. based on what the `request_filter` stored in the `ctx` for the
+    /// route, the other ones are streamed to the downstream.
+    fn wants_response_body(
+        &self,
+        _upstream_response: &ResponseHeaders,
+        _ctx: &mut Self::CTX,
+    ) -> bool {
+        false
+    }
+
+    /// Rewrite the buffered body of a response before it is sent to the downstream, e.g. to
+    /// redact fields.
+    ///
+    /// Only called for the responses [Proxy::wants_response_body] opts in, after
+    /// [Proxy::response_filter]. The `content-length` is set to the size of the returned body
+    /// and the upstream trailers are dropped.
+    async fn response_body_filter(
+        &self,
+        _upstream_response: &mut ResponseHeaders,
+        body: Bytes,
+        _ctx: &mut Self::CTX,
+    ) -> Result<Bytes, Response<Body>> {
+        Ok(body)
+    }
+
+    /// Whether [Proxy::response_trailers_filter] should see the trailers of this streamed
+    /// response, `false` by default.
+    fn wants_response_trailers(
+        &self,
+        _upstream_response: &ResponseHeaders,
+        _ctx: &mut Self::CTX,
+    ) -> bool {
+        false
+    }
+
+    /// Inspect or modify the trailers of a response, e.g. its `grpc-status`, as they are
+    /// streamed to the downstream after the body.
+    ///
+    /// Only called for the responses [Proxy::wants_response_trailers] opts in, if the upstream
+    /// sent trailers. The other hooks are done by then, so it doesn't get the `ctx`, and a new
+    /// trailer must be announced by the `trailer` header in [Proxy::response_filter].
+    fn response_trailers_filter(&self, _trailers: &mut HeaderMap) {}
+
+    /// Store a response in the cache of [Proxy::cache_lookup], once it passed
+    /// [Proxy::response_filter].
+    ///
+    /// Only called for the responses buffered for [Proxy::response_body_filter], with the body
+    /// it returned, so opt in the cacheable ones with [Proxy::wants_response_body].
+    async fn cache_store(
+        &self,
+        _upstream_response: &ResponseHeaders,
+        _body: &Bytes,
+        _ctx: &mut Self::CTX,
+    ) {
+    }
+
+    /// This hook is called once the response to the downstream is decided, e.g. to emit an
+    /// access log line or record metrics.
+    ///
+    /// `request` is the request as it was received and `duration` is the time it took to
+    /// process it, filters included. A response returned by a filter is given as well, the
+    /// upstream can be recorded in the `ctx` by [Proxy::upstream_request_filter].
+    async fn logging(
+        &self,
+        _request: &RequestHeaders,
+        _response: &ResponseHeaders,
+        _duration: std::time::Duration,
+        _ctx: &mut Self::CTX,
+    ) {
+    }
 }
@@ -4,14 +4,36 @@ use async_trait::async_trait;
 use pingora_runtime::current_handle;
 use pingora_server::{
     server::{ListenFds, ShutdownWatch},
-    services::Service,
+    services::{background::BackgroundService, Service},
 };
+use tokio::net::TcpListener;
 
-struct TcpService<T> {
+use crate::listeners::{listen, ListenerConfig};
+
+/// The task of a [TcpService]
+#[async_trait]
+pub trait TcpTask {
+    /// Run until `shutdown`, with a listener for each of the [TcpService::add_tcp] addresses
+    async fn serve(&self, listeners: Vec<TcpListener>, shutdown: ShutdownWatch);
+}
+
+/// A [BackgroundService] listens on nothing
+#[async_trait]
+impl<T: BackgroundService + Send + Sync> TcpTask for T {
+    async fn serve(&self, _listeners: Vec<TcpListener>, shutdown: ShutdownWatch) {
+        self.start(shutdown).await;
+    }
+}
+
+/// Runs a [TcpTask], e.g. the health checks of a
+/// [LoadBalancer](crate::load_balancer::LoadBalancer), on the runtime of the service
+pub struct TcpService<T> {
     // Name of the service
     name: String,
     // Task the service will execute
     task: Arc<T>,
+    // Addresses to listen on for the task
+    addrs: Vec<(String, ListenerConfig)>,
     /// The number of threads. Default is 1
     pub threads: Option<usize>,
 }
@@ -22,10 +44,30 @@ impl<T> TcpService<T> {
         Self {
             name,
             task,
+            addrs: Vec::new(),
             threads: Some(1),
         }
     }
 
+    /// Run the service on `threads` threads, e.g. for a CPU bound task.
+    ///
+    /// The server builds the runtime of the service with as many threads.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Listen on `addr` for the task, on the socket passed by the previous process of a
+    /// graceful upgrade if any.
+    pub fn add_tcp(&mut self, addr: &str) {
+        self.add_tcp_with_config(addr, ListenerConfig::default());
+    }
+
+    /// Listen on `addr` for the task as set by `config`, see [TcpService::add_tcp]
+    pub fn add_tcp_with_config(&mut self, addr: &str, config: ListenerConfig) {
+        self.addrs.push((addr.to_string(), config));
+    }
+
     /// Return the task behind [Arc] to be shared other logic.
     pub fn task(&self) -> Arc<T> {
         self.task.clone()
@@ -35,11 +77,26 @@ impl<T> TcpService<T> {
 #[async_trait]
 impl<T> Service for TcpService<T>
 where
-    T: Send + Sync + 'static,
+    T: TcpTask + Send + Sync + 'static,
 {
-    async fn start_service(&mut self, _fds: Option<ListenFds>, shutdown: ShutdownWatch) {
-        let runtime = current_handle();
-        // self.task.start(shutdown).await;
+    /// Run the task until it returns, on the runtime of [TcpService::threads] threads the
+    /// server gives the service
+    async fn start_service(&mut self, fds: Option<ListenFds>, shutdown: ShutdownWatch) {
+        let mut listeners = Vec::with_capacity(self.addrs.len());
+        for (addr, config) in &self.addrs {
+            match listen(addr, config, fds.as_ref()).await {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => {
+                    tracing::error!(service = %self.name, error = %e, "failed to listen");
+                    return;
+                }
+            }
+        }
+        let task = self.task.clone();
+        let running = current_handle().spawn(async move { task.serve(listeners, shutdown).await });
+        if let Err(e) = running.await {
+            tracing::error!(service = %self.name, error = %e, "background task failed");
+        }
     }
 
     fn name(&self) -> &str {
@@ -50,3 +107,96 @@ where
         self.threads
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_server::server::Fds;
+    use std::net::SocketAddr;
+    use std::os::fd::{AsRawFd, IntoRawFd, RawFd};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    /// Counts its runs, until shut down
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    #[async_trait]
+    impl BackgroundService for Counter {
+        async fn start(&self, mut shutdown: ShutdownWatch) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            let _ = shutdown.changed().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_start_service() {
+        let mut service = TcpService::new("counter".to_string(), Arc::new(Counter::default()));
+        let counter = service.task();
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let running = tokio::spawn(async move { service.start_service(None, shutdown).await });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+        assert!(!running.is_finished());
+
+        shutdown_sender.send(true).unwrap();
+        running.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_with_threads() {
+        let service = TcpService::new("counter".to_string(), Arc::new(Counter::default()));
+        assert_eq!(service.threads(), Some(1));
+        let mut service = service.with_threads(4);
+        assert_eq!(service.threads(), Some(4));
+
+        let counter = service.task();
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let running = tokio::spawn(async move { service.start_service(None, shutdown).await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+        shutdown_sender.send(true).unwrap();
+        running.await.unwrap();
+    }
+
+    /// Records the sockets it is given
+    #[derive(Default)]
+    struct Sockets(Mutex<Vec<(RawFd, SocketAddr)>>);
+
+    #[async_trait]
+    impl TcpTask for Sockets {
+        async fn serve(&self, listeners: Vec<TcpListener>, _shutdown: ShutdownWatch) {
+            let mut sockets = self.0.lock().unwrap();
+            for listener in listeners {
+                sockets.push((listener.as_raw_fd(), listener.local_addr().unwrap()));
+                // Keep the socket open for the assertions
+                let _ = listener.into_std().unwrap().into_raw_fd();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inherited_listeners() {
+        let inherited = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let inherited_addr = inherited.local_addr().unwrap();
+        let inherited_fd = inherited.into_raw_fd();
+        let mut fds = Fds::new();
+        fds.add(inherited_addr.to_string(), inherited_fd);
+        let fds: ListenFds = Arc::new(tokio::sync::Mutex::new(fds));
+
+        let mut service = TcpService::new("sockets".to_string(), Arc::new(Sockets::default()));
+        service.add_tcp(&inherited_addr.to_string());
+        service.add_tcp("127.0.0.1:0");
+        let (_shutdown_sender, shutdown) = watch::channel(false);
+        service.start_service(Some(fds.clone()), shutdown).await;
+
+        // The inherited socket is reused, the other one is bound and passed on
+        let sockets = service.task().0.lock().unwrap().clone();
+        assert_eq!(sockets[0], (inherited_fd, inherited_addr));
+        assert_ne!(sockets[1].0, inherited_fd);
+        assert_eq!(fds.lock().await.get("127.0.0.1:0"), Some(&sockets[1].0));
+    }
+}